@@ -1,34 +1,60 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
+use serde::Deserialize;
 use structopt::StructOpt;
 use themelio_stf::{melvm::Address, GenesisConfig};
-use tmelcrypt::Ed25519SK;
+use tmelcrypt::{Ed25519SK, HashVal};
 
 use crate::storage::{NodeStorage, SharedStorage};
 
+const DEFAULT_LISTEN: &str = "0.0.0.0:11814";
+const DEFAULT_BOOTSTRAP: &str = "mainnet-bootstrap.themelio.org:11814";
+const DEFAULT_DATABASE: &str = "/var/themelio-node/main.sqlite3";
+const DEFAULT_TARGET_FEE_MULTIPLIER: u128 = 1000;
+
+/// Command-line arguments. Every option except `--config` and `--emergency-reset-block` can also be given in the config file, and options given on the command line take precedence. Flags that the config file turns on can be turned off again with their `--no-` forms.
 #[derive(Debug, StructOpt)]
 pub struct Args {
-    /// Listen address
-    #[structopt(long, default_value = "0.0.0.0:11814")]
-    listen: SocketAddr,
+    /// Path of a TOML config file. Its keys are the names of the options below, with underscores instead of dashes. Relative paths in it are relative to the file.
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    /// Listen address. Default is 0.0.0.0:11814.
+    #[structopt(long)]
+    listen: Option<SocketAddr>,
 
     /// Advertise address. Put your public IP address here.
     #[structopt(long)]
     advertise: Option<SocketAddr>,
 
-    /// Bootstrap addresses. May be given as a DNS name.
-    #[structopt(long, default_value = "mainnet-bootstrap.themelio.org:11814")]
+    /// If given, serves an HTTP/JSON query API on this address.
+    #[structopt(long)]
+    rpc_listen: Option<SocketAddr>,
+
+    /// If given, serves Prometheus metrics on this address.
+    #[structopt(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Bootstrap addresses. May be given as a DNS name. Default is mainnet-bootstrap.themelio.org:11814.
+    #[structopt(long)]
     bootstrap: Vec<String>,
 
-    /// Database path
-    #[structopt(long, default_value = "/var/themelio-node/main.sqlite3")]
-    database: String,
+    /// Database path. Default is /var/themelio-node/main.sqlite3.
+    #[structopt(long)]
+    database: Option<PathBuf>,
 
-    /// Specifies the secret key for staking.
+    /// Specifies the secret key for staking. Deprecated, since other users can see it in the process list: use --staker-sk-file instead.
     #[structopt(long)]
     staker_sk: Option<Ed25519SK>,
 
+    /// Path of a file holding the hex-encoded secret key for staking. On Unix, the file must not be accessible by anyone but its owner.
+    #[structopt(long)]
+    staker_sk_file: Option<PathBuf>,
+
     /// Bootstrap addresses for the staker network.
     #[structopt(long)]
     staker_bootstrap: Vec<SocketAddr>,
@@ -49,16 +75,169 @@ pub struct Args {
     #[structopt(long)]
     testnet: bool,
 
+    /// Uses mainnet validation rules even if the config file sets testnet.
+    #[structopt(long, conflicts_with = "testnet")]
+    no_testnet: bool,
+
     /// Fee multiplier to target. Default is 1000.
-    #[structopt(long, default_value = "1000")]
-    target_fee_multiplier: u128,
+    #[structopt(long)]
+    target_fee_multiplier: Option<u128>,
+
+    /// Bound on the total size of pending transactions in the mempool, in bytes. The lowest-paying transactions are evicted beyond this. Default is 100 MiB.
+    #[structopt(long)]
+    mempool_max_bytes: Option<usize>,
+
+    /// If given, only keeps the states of the latest N confirmed blocks. Older blocks keep their headers and consensus proofs, but can no longer be queried for their contents.
+    #[structopt(long)]
+    prune_keep: Option<u64>,
+
+    /// If set, maintains an index of coins and transactions by address, served over the HTTP/JSON API. The index is backfilled from the existing history on startup.
+    #[structopt(long)]
+    index: bool,
+
+    /// Doesn't maintain the index even if the config file sets index.
+    #[structopt(long, conflicts_with = "index")]
+    no_index: bool,
+
+    /// A trusted checkpoint, given as HEIGHT:BLOCKHASH. A node that's behind it downloads the state at the checkpoint from its peers, rather than replaying every block before it.
+    #[structopt(long)]
+    checkpoint: Option<String>,
 
     /// Reset last block to the given height.
     #[structopt(long)]
     emergency_reset_block: Option<u64>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+/// The contents of a config file. See [Args] for what each option means.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    listen: Option<SocketAddr>,
+    advertise: Option<SocketAddr>,
+    rpc_listen: Option<SocketAddr>,
+    metrics_listen: Option<SocketAddr>,
+    #[serde(default)]
+    bootstrap: Vec<String>,
+    database: Option<PathBuf>,
+    // the key itself is deliberately not allowed here, since config files are often world-readable
+    staker_sk_file: Option<PathBuf>,
+    #[serde(default)]
+    staker_bootstrap: Vec<SocketAddr>,
+    staker_listen: Option<SocketAddr>,
+    // in the same format as on the command line, rather than the hex that Address deserializes from
+    staker_payout_addr: Option<String>,
+    override_genesis: Option<PathBuf>,
+    #[serde(default)]
+    testnet: bool,
+    // TOML has no 128-bit integers
+    target_fee_multiplier: Option<u64>,
+    mempool_max_bytes: Option<usize>,
+    prune_keep: Option<u64>,
+    #[serde(default)]
+    index: bool,
+    checkpoint: Option<String>,
+}
+
+/// Maintenance subcommands. When none is given, the node runs normally.
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Database maintenance.
+    Db(DbCommand),
+    /// Exports a range of blocks, with their consensus proofs, into a chain archive.
+    Export {
+        /// First height to export.
+        #[structopt(long, default_value = "1")]
+        from: u64,
+        /// Last height to export. Defaults to the highest block.
+        #[structopt(long)]
+        to: Option<u64>,
+        /// Path of the archive to write.
+        file: PathBuf,
+    },
+    /// Imports a chain archive, validating every block.
+    Import {
+        /// Path of the archive to read.
+        file: PathBuf,
+    },
+}
+
+/// Database maintenance subcommands.
+#[derive(Debug, StructOpt)]
+pub enum DbCommand {
+    /// Checks the block database for inconsistencies.
+    Check {
+        /// Also repair every inconsistency that can be repaired.
+        #[structopt(long)]
+        repair: bool,
+    },
 }
 
 impl Args {
+    /// Fills in the options that weren't given on the command line from the config file, if there is one.
+    pub fn with_config(mut self) -> anyhow::Result<Self> {
+        let path = match &self.config {
+            Some(path) => path.clone(),
+            None => return Ok(self),
+        };
+        let config: Config = toml::from_slice(
+            &std::fs::read(&path)
+                .with_context(|| format!("cannot read config file {}", path.display()))?,
+        )
+        .context("config file not a valid TOML file")?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        // joining an absolute path just returns it
+        let resolve = |relative: Option<PathBuf>| relative.map(|relative| dir.join(relative));
+
+        self.listen = self.listen.or(config.listen);
+        self.advertise = self.advertise.or(config.advertise);
+        self.rpc_listen = self.rpc_listen.or(config.rpc_listen);
+        self.metrics_listen = self.metrics_listen.or(config.metrics_listen);
+        if self.bootstrap.is_empty() {
+            self.bootstrap = config.bootstrap;
+        }
+        self.database = self.database.take().or(resolve(config.database));
+        // a key given on the command line replaces the key file from the config file, rather than clashing with it
+        if self.staker_sk.is_none() {
+            self.staker_sk_file = self
+                .staker_sk_file
+                .take()
+                .or(resolve(config.staker_sk_file));
+        }
+        if self.staker_bootstrap.is_empty() {
+            self.staker_bootstrap = config.staker_bootstrap;
+        }
+        self.staker_listen = self.staker_listen.or(config.staker_listen);
+        if self.staker_payout_addr.is_none() {
+            self.staker_payout_addr = config
+                .staker_payout_addr
+                .map(|addr| addr.parse())
+                .transpose()
+                .map_err(|_| anyhow::anyhow!("malformed staker_payout_addr in config file"))?;
+        }
+        self.override_genesis = self
+            .override_genesis
+            .take()
+            .or(resolve(config.override_genesis));
+        self.testnet = !self.no_testnet && (self.testnet || config.testnet);
+        self.target_fee_multiplier = self
+            .target_fee_multiplier
+            .or(config.target_fee_multiplier.map(u128::from));
+        self.mempool_max_bytes = self.mempool_max_bytes.or(config.mempool_max_bytes);
+        self.prune_keep = self.prune_keep.or(config.prune_keep);
+        self.index = !self.no_index && (self.index || config.index);
+        self.checkpoint = self.checkpoint.take().or(config.checkpoint);
+        log::debug!("read config file {}", path.display());
+        Ok(self)
+    }
+
+    /// Gets the maintenance subcommand, if any.
+    pub fn command(&self) -> Option<&Command> {
+        self.command.as_ref()
+    }
+
     /// Gets the advertised IP.
     pub fn advertise_addr(&self) -> Option<SocketAddr> {
         self.advertise
@@ -78,36 +257,74 @@ impl Args {
         }
     }
 
+    /// Database path
+    fn database(&self) -> &Path {
+        self.database
+            .as_deref()
+            .unwrap_or_else(|| Path::new(DEFAULT_DATABASE))
+    }
+
     /// Derives a SharedStorage from the arguments
     pub async fn storage(&self) -> anyhow::Result<SharedStorage> {
         let database =
-            boringdb::Database::open(&self.database).context("cannot open boringdb database")?;
-        log::debug!("database opened at {}", self.database);
+            boringdb::Database::open(self.database()).context("cannot open boringdb database")?;
+        log::debug!("database opened at {}", self.database().display());
 
-        let storage = NodeStorage::new(database, self.genesis_config().await?).share();
+        let mut storage = NodeStorage::new(database, self.genesis_config().await?);
+        if let Some(max_bytes) = self.mempool_max_bytes {
+            storage.mempool_mut().set_max_bytes(max_bytes);
+        }
+        storage.set_prune_keep(self.prune_keep);
+        if self.index {
+            storage.enable_index()?;
+        }
+        let storage = storage.share();
 
         // Reset block. This is used to roll back history in emergencies
         if let Some(height) = self.emergency_reset_block {
-            let mut storage = storage.write();
             log::warn!("*** EMERGENCY RESET TO BLOCK {} ***", height);
-            let history = storage.history_mut();
-            while history
-                .get_tips()
-                .iter()
-                .any(|f| f.header().height > height)
-            {
-                history.delete_tips();
-            }
+            storage.write().reset_to(height);
         }
 
         log::debug!("node storage opened");
         Ok(storage)
     }
 
+    /// Checks the block database for inconsistencies, without fully opening it.
+    pub async fn check_database(&self, repair: bool) -> anyhow::Result<blkdb::RepairReport> {
+        let database =
+            boringdb::Database::open(self.database()).context("cannot open boringdb database")?;
+        log::debug!("database opened at {}", self.database().display());
+        let genesis = self.genesis_config().await?;
+        Ok(smol::unblock(move || NodeStorage::check_history(database, genesis, repair)).await)
+    }
+
+    /// Trusted checkpoint to state sync to, as a height and a block hash
+    pub fn checkpoint(&self) -> anyhow::Result<Option<(u64, HashVal)>> {
+        self.checkpoint
+            .as_ref()
+            .map(|checkpoint| {
+                let (height, blkhash) = checkpoint
+                    .split_once(':')
+                    .context("checkpoint must be given as HEIGHT:BLOCKHASH")?;
+                let height = height.parse().context("malformed checkpoint height")?;
+                let blkhash = blkhash
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("malformed checkpoint block hash"))?;
+                Ok((height, blkhash))
+            })
+            .transpose()
+    }
+
     /// Derives a list of bootstrap addresses
     pub async fn bootstrap(&self) -> anyhow::Result<Vec<SocketAddr>> {
         let mut bootstrap = vec![];
-        for name in self.bootstrap.iter() {
+        let names = if self.bootstrap.is_empty() {
+            vec![DEFAULT_BOOTSTRAP.to_string()]
+        } else {
+            self.bootstrap.clone()
+        };
+        for name in names.iter() {
             let addrs = smol::net::resolve(&name)
                 .await
                 .context("cannot resolve DNS bootstrap")?;
@@ -116,16 +333,38 @@ impl Args {
         Ok(bootstrap)
     }
 
+    /// HTTP/JSON API listening address
+    pub fn rpc_listen_addr(&self) -> Option<SocketAddr> {
+        self.rpc_listen
+    }
+
+    /// Prometheus metrics listening address
+    pub fn metrics_listen_addr(&self) -> Option<SocketAddr> {
+        self.metrics_listen
+    }
+
     /// Listening address
     pub fn listen_addr(&self) -> SocketAddr {
         self.listen
+            .unwrap_or_else(|| DEFAULT_LISTEN.parse().unwrap())
     }
 
     /// Staker secret key
     pub async fn staker_sk(
         &self,
     ) -> anyhow::Result<Option<(Ed25519SK, SocketAddr, Vec<SocketAddr>, u128, Address)>> {
-        if let Some(staker_sk) = self.staker_sk {
+        let staker_sk = match (self.staker_sk, &self.staker_sk_file) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("only one of staker_sk and staker_sk_file may be set")
+            }
+            (Some(staker_sk), None) => {
+                log::warn!("--staker-sk leaks into process listings, use --staker-sk-file");
+                Some(staker_sk)
+            }
+            (None, Some(path)) => Some(read_staker_sk(path).await?),
+            (None, None) => None,
+        };
+        if let Some(staker_sk) = staker_sk {
             let staker_listen = self
                 .staker_listen
                 .context("staker_listen must be set if staker_sk is set")?;
@@ -137,7 +376,8 @@ impl Args {
                 staker_sk,
                 staker_listen,
                 staker_bootstrap,
-                self.target_fee_multiplier,
+                self.target_fee_multiplier
+                    .unwrap_or(DEFAULT_TARGET_FEE_MULTIPLIER),
                 staker_payout_addr,
             )))
         } else {
@@ -145,3 +385,122 @@ impl Args {
         }
     }
 }
+
+/// Reads a hex-encoded secret key from a file, refusing to if anyone but the file's owner can access it.
+async fn read_staker_sk(path: &Path) -> anyhow::Result<Ed25519SK> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = smol::fs::metadata(path)
+            .await
+            .with_context(|| format!("cannot read staker key file {}", path.display()))?
+            .permissions()
+            .mode();
+        if mode & 0o077 != 0 {
+            anyhow::bail!(
+                "staker key file {} is accessible by other users (mode {:o}), chmod it to 600",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+    let contents = smol::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("cannot read staker key file {}", path.display()))?;
+    contents.trim().parse().map_err(|_| {
+        anyhow::anyhow!(
+            "staker key file {} doesn't hold a valid key",
+            path.display()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory to put test files in.
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("args-test-{}", fastrand::u64(..)));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn staker_sk_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let (_, sk) = tmelcrypt::ed25519_keygen();
+        let path = temp_dir().join("staker.key");
+        std::fs::write(&path, format!("{}\n", hex::encode(sk.0))).unwrap();
+        for mode in [0o640, 0o604, 0o660, 0o644, 0o666] {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+            assert!(
+                smol::block_on(read_staker_sk(&path)).is_err(),
+                "accepted a key file with mode {:o}",
+                mode
+            );
+        }
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(smol::block_on(read_staker_sk(&path)).unwrap(), sk);
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let dir = temp_dir();
+        let config = dir.join("config.toml");
+        std::fs::write(
+            &config,
+            r#"
+listen = "127.0.0.1:1000"
+rpc_listen = "127.0.0.1:2000"
+bootstrap = ["config-bootstrap:11814"]
+database = "node.sqlite3"
+staker_sk_file = "staker.key"
+testnet = true
+index = true
+prune_keep = 100
+"#,
+        )
+        .unwrap();
+        let (_, sk) = tmelcrypt::ed25519_keygen();
+        let args = Args::from_iter(&[
+            "themelio-node",
+            "--config",
+            config.to_str().unwrap(),
+            "--listen",
+            "127.0.0.1:3000",
+            "--bootstrap",
+            "cli-bootstrap:11814",
+            "--database",
+            "/elsewhere.sqlite3",
+            "--staker-sk",
+            &hex::encode(sk.0),
+            "--no-testnet",
+            "--no-index",
+        ])
+        .with_config()
+        .unwrap();
+        assert_eq!(args.listen_addr(), "127.0.0.1:3000".parse().unwrap());
+        assert_eq!(args.bootstrap, vec!["cli-bootstrap:11814".to_string()]);
+        assert_eq!(args.database(), Path::new("/elsewhere.sqlite3"));
+        assert_eq!(args.staker_sk, Some(sk));
+        assert_eq!(args.staker_sk_file, None);
+        assert!(!args.testnet);
+        assert!(!args.index);
+        // what the command line leaves out still comes from the config file, with paths relative to it
+        assert_eq!(
+            args.rpc_listen_addr(),
+            Some("127.0.0.1:2000".parse().unwrap())
+        );
+        assert_eq!(args.prune_keep, Some(100));
+        let args = Args::from_iter(&["themelio-node", "--config", config.to_str().unwrap()])
+            .with_config()
+            .unwrap();
+        assert_eq!(args.listen_addr(), "127.0.0.1:1000".parse().unwrap());
+        assert_eq!(args.database(), dir.join("node.sqlite3"));
+        assert_eq!(args.staker_sk_file, Some(dir.join("staker.key")));
+        assert!(args.testnet);
+        assert!(args.index);
+    }
+}
@@ -1,12 +1,26 @@
 mod args;
+mod metrics;
 mod protocols;
 mod storage;
 
-use args::Args;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use args::{Args, Command, DbCommand};
+use peermgr::PeerManager;
 use structopt::StructOpt;
 use tracing::instrument;
 
-use crate::protocols::{NodeProtocol, StakerProtocol};
+use crate::{
+    protocols::{state_sync, NodeProtocol, StakerProtocol},
+    storage::SharedStorage,
+};
 
 #[cfg(unix)]
 #[global_allocator]
@@ -17,7 +31,7 @@ fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env("RUST_LOG")
         .parse_filters("themelio_node=debug,warn")
         .init();
-    let opts = Args::from_args();
+    let opts = Args::from_args().with_config()?;
 
     smolscale::block_on(main_async(opts))
 }
@@ -28,17 +42,50 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 #[instrument(skip(opt))]
 pub async fn main_async(opt: Args) -> anyhow::Result<()> {
     log::info!("themelio-core v{} initializing...", VERSION);
+    match opt.command() {
+        Some(Command::Db(DbCommand::Check { repair })) => return db_check(&opt, *repair).await,
+        Some(Command::Export { from, to, file }) => {
+            return export_chain(&opt, *from, *to, file).await
+        }
+        Some(Command::Import { file }) => return import_chain(&opt, file).await,
+        None => {}
+    }
     let genesis = opt.genesis_config().await?;
     let netid = genesis.network;
     let storage = opt.storage().await?;
+    let _mempool_saver = smolscale::spawn(mempool_save_loop(storage.clone()));
+    let _smt_gc = smolscale::spawn(smt_gc_loop(storage.clone()));
+    let node_peers = load_peers(&storage, NODE_PEERS);
+    let staker_peers = load_peers(&storage, STAKER_PEERS);
+    let _peer_savers = [
+        smolscale::spawn(peers_save_loop(
+            storage.clone(),
+            NODE_PEERS,
+            node_peers.clone(),
+        )),
+        smolscale::spawn(peers_save_loop(
+            storage.clone(),
+            STAKER_PEERS,
+            staker_peers.clone(),
+        )),
+    ];
+    let _metrics_server = opt
+        .metrics_listen_addr()
+        .map(|listen| smolscale::spawn(metrics::serve_metrics(listen, storage.clone())));
     let bootstrap = opt.bootstrap().await?;
     log::info!("bootstrapping with {:?}", bootstrap);
+    if let Some((height, blkhash)) = opt.checkpoint()? {
+        node_peers.add_peers(bootstrap.iter().copied());
+        state_sync(netid, storage.clone(), &node_peers, height, blkhash).await;
+    }
     let _node_prot = NodeProtocol::new(
         netid,
         opt.listen_addr(),
         opt.advertise_addr(),
         bootstrap,
+        opt.rpc_listen_addr(),
         storage.clone(),
+        node_peers,
     );
     let _staker_prot = if let Some((
         staker_sk,
@@ -55,6 +102,7 @@ pub async fn main_async(opt: Args) -> anyhow::Result<()> {
             staker_sk,
             staker_payout_addr,
             target_fee_multiplier,
+            staker_peers,
         )?)
     } else {
         None
@@ -62,3 +110,118 @@ pub async fn main_async(opt: Args) -> anyhow::Result<()> {
 
     smol::future::pending().await
 }
+
+/// How often the mempool is snapshotted to the database.
+const MEMPOOL_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Periodically snapshots the mempool, so that pending transactions survive restarts.
+async fn mempool_save_loop(storage: SharedStorage) {
+    loop {
+        smol::Timer::after(MEMPOOL_SAVE_INTERVAL).await;
+        let storage = storage.clone();
+        smol::unblock(move || storage.read().save_mempool()).await;
+    }
+}
+
+/// How often queued SMT roots are garbage-collected.
+const SMT_GC_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How long a queued SMT root is left alone before it's garbage-collected. Roots are queued while trees over them are still open, so this must be much longer than any tree stays open.
+const SMT_GC_GRACE: Duration = Duration::from_secs(86400);
+
+/// Periodically deletes the SMT roots that were queued for deletion long enough ago, reclaiming their disk space.
+async fn smt_gc_loop(storage: SharedStorage) {
+    loop {
+        smol::Timer::after(SMT_GC_INTERVAL).await;
+        let smt = storage.read().smt();
+        let report =
+            smol::unblock(move || smt.collect_garbage(SystemTime::now() - SMT_GC_GRACE)).await;
+        metrics::SMT_NODES_RECLAIMED.inc_by(report.nodes as u64);
+        log::info!(
+            "SMT garbage collection deleted {} roots and {} nodes, {} roots pending",
+            report.roots,
+            report.nodes,
+            report.pending
+        );
+    }
+}
+
+/// Under which names the peers of the node and staker networks are saved.
+const NODE_PEERS: &str = "node";
+const STAKER_PEERS: &str = "staker";
+
+/// How often the good peers of each network are saved to the database.
+const PEERS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Creates the peer manager for a network, starting out with the peers saved by the previous run.
+fn load_peers(storage: &SharedStorage, network: &str) -> Arc<PeerManager> {
+    let peers = Arc::new(PeerManager::new());
+    let saved = storage.read().saved_peers(network);
+    log::info!("remembered {} {} peers", saved.len(), network);
+    peers.add_peers(saved);
+    peers
+}
+
+/// Periodically saves the good peers of a network, so that they can be reached again after a restart.
+async fn peers_save_loop(storage: SharedStorage, network: &'static str, peers: Arc<PeerManager>) {
+    loop {
+        smol::Timer::after(PEERS_SAVE_INTERVAL).await;
+        let good = peers.good_peers();
+        let storage = storage.clone();
+        smol::unblock(move || storage.read().save_peers(network, &good)).await;
+    }
+}
+
+/// Checks, and possibly repairs, the block database.
+async fn db_check(opt: &Args, repair: bool) -> anyhow::Result<()> {
+    let report = opt.check_database(repair).await?;
+    for issue in report.repaired.iter() {
+        log::info!("repaired: {}", issue);
+    }
+    for issue in report.remaining.iter() {
+        log::error!("found: {}", issue);
+    }
+    if !report.remaining.is_empty() {
+        anyhow::bail!(
+            "{} inconsistencies remain in the database",
+            report.remaining.len()
+        )
+    }
+    log::info!("database is consistent");
+    Ok(())
+}
+
+/// Exports blocks from the database into a chain archive.
+async fn export_chain(opt: &Args, from: u64, to: Option<u64>, path: &Path) -> anyhow::Result<()> {
+    let storage = opt.storage().await?;
+    let file = File::create(path).context("cannot create archive")?;
+    let header = smol::unblock(move || {
+        let storage = storage.read();
+        let to = to.unwrap_or_else(|| storage.highest_height());
+        storage::export_chain(&storage, from, to, BufWriter::new(file))
+    })
+    .await?;
+    log::info!(
+        "exported blocks {}..={} to {:?}",
+        header.from,
+        header.to,
+        path
+    );
+    Ok(())
+}
+
+/// Imports a chain archive into the database.
+async fn import_chain(opt: &Args, path: &Path) -> anyhow::Result<()> {
+    let storage = opt.storage().await?;
+    let file = File::open(path).context("cannot open archive")?;
+    let (header, applied) =
+        smol::unblock(move || storage::import_chain(&mut storage.write(), BufReader::new(file)))
+            .await?;
+    log::info!(
+        "imported {} new blocks from archive of blocks {}..={}",
+        applied,
+        header.from,
+        header.to
+    );
+    Ok(())
+}
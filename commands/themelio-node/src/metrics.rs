@@ -0,0 +1,127 @@
+use std::net::SocketAddr;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_gauge, Encoder, HistogramVec,
+    IntCounter, IntGauge, TextEncoder,
+};
+use tide::{Request, Response, StatusCode};
+
+use crate::storage::SharedStorage;
+
+pub static HEIGHT: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("themelio_node_height", "Height of the highest block").unwrap()
+});
+
+pub static BLKSYNC_LAG: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "themelio_node_blksync_lag",
+        "How many blocks behind the last blksync peer we were"
+    )
+    .unwrap()
+});
+
+pub static MEMPOOL_SIZE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "themelio_node_mempool_size",
+        "Pending transactions in the mempool"
+    )
+    .unwrap()
+});
+
+pub static MEMPOOL_REJECTIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "themelio_node_mempool_rejections_total",
+        "Transactions rejected by the mempool"
+    )
+    .unwrap()
+});
+
+pub static SMT_NODES_RECLAIMED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "themelio_node_smt_nodes_reclaimed_total",
+        "SMT nodes deleted by garbage collection"
+    )
+    .unwrap()
+});
+
+pub static PEER_REQUEST_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "themelio_node_peer_request_seconds",
+        "Latency of requests to node peers",
+        &["request"]
+    )
+    .unwrap()
+});
+
+static CACHE_HITS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "themelio_node_blkdb_cache_hits_total",
+        "blkdb state cache hits"
+    )
+    .unwrap()
+});
+
+static CACHE_MISSES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "themelio_node_blkdb_cache_misses_total",
+        "blkdb state cache misses"
+    )
+    .unwrap()
+});
+
+static CACHE_EVICTIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "themelio_node_blkdb_cache_evictions_total",
+        "blkdb state cache evictions"
+    )
+    .unwrap()
+});
+
+static CACHE_LEN: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "themelio_node_blkdb_cache_len",
+        "States in the blkdb state cache"
+    )
+    .unwrap()
+});
+
+/// Serves every registered metric, including novasymph's, in the Prometheus text format at `/metrics`.
+pub async fn serve_metrics(listen: SocketAddr, storage: SharedStorage) -> std::io::Result<()> {
+    let mut app = tide::with_state(storage);
+    app.at("/metrics")
+        .get(|req: Request<SharedStorage>| async move {
+            let storage = req.state().clone();
+            smol::unblock(move || refresh(&storage)).await;
+            let mut buffer = Vec::new();
+            let encoder = TextEncoder::new();
+            encoder.encode(&prometheus::gather(), &mut buffer)?;
+            Ok(Response::builder(StatusCode::Ok)
+                .content_type(encoder.format_type())
+                .body(buffer)
+                .build())
+        });
+    log::info!("serving metrics on {}", listen);
+    app.listen(listen).await
+}
+
+/// Updates the metrics that are read off the storage rather than counted as things happen.
+fn refresh(storage: &SharedStorage) {
+    // the cache counts are totals, so concurrent scrapes must not both add the same difference
+    static REFRESH_LOCK: Lazy<Mutex<()>> = Lazy::new(Default::default);
+    let _guard = REFRESH_LOCK.lock();
+    let storage = storage.read();
+    HEIGHT.set(storage.highest_height() as i64);
+    MEMPOOL_SIZE.set(storage.mempool().len() as i64);
+    let stats = storage.cache_stats();
+    catch_up(&CACHE_HITS, stats.hits);
+    catch_up(&CACHE_MISSES, stats.misses);
+    catch_up(&CACHE_EVICTIONS, stats.evictions);
+    CACHE_LEN.set(stats.len as i64);
+}
+
+/// Brings a counter up to a total that is counted elsewhere.
+fn catch_up(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
@@ -1,25 +1,38 @@
 use std::{
     collections::BTreeMap,
     net::SocketAddr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use futures_util::{StreamExt, TryStreamExt};
 use novasmt::CompressedProof;
-use themelio_stf::{AbbrBlock, Block, ConsensusProof, NetID, SealedState, Transaction};
+use peermgr::PeerManager;
+use themelio_stf::{
+    melvm::Address, AbbrBlock, Block, CoinDataHeight, CoinID, ConsensusProof, NetID, SealedState,
+    State, Transaction, TxHash,
+};
 
-use crate::storage::SharedStorage;
+use crate::{
+    metrics,
+    storage::{AddressIndex, ConsensusError, NodeStorage, SharedStorage, TxLocation},
+};
 use melnet::MelnetError;
 use smol::net::TcpListener;
 use smol_timeout::TimeoutExt;
 use themelio_nodeprot::{NodeClient, NodeResponder, NodeServer, StateSummary, Substate};
 use tmelcrypt::HashVal;
 
+mod rpc;
+mod statesync;
+pub use statesync::state_sync;
+
 /// This encapsulates the node peer-to-peer for both auditors and stakers..
 pub struct NodeProtocol {
     _network_task: smol::Task<()>,
     _blksync_task: smol::Task<()>,
+    _rpc_task: Option<smol::Task<()>>,
 }
 
 fn netname(network: NetID) -> &'static str {
@@ -30,23 +43,36 @@ fn netname(network: NetID) -> &'static str {
 }
 
 impl NodeProtocol {
-    /// Creates a new AuditorProtocol listening on the given address with the given AuditorState.
+    /// Creates a new AuditorProtocol listening on the given address with the given AuditorState. Block sync picks its peers through the given peer manager.
     pub fn new(
         netid: NetID,
         listen_addr: SocketAddr,
         advertise_addr: Option<SocketAddr>,
         bootstrap: Vec<SocketAddr>,
+        rpc_listen: Option<SocketAddr>,
         storage: SharedStorage,
+        peers: Arc<PeerManager>,
     ) -> Self {
         let network = melnet::NetState::new_with_name(netname(netid));
-        for addr in bootstrap {
+        // peers remembered from before help with discovery, just like the bootstrap peers
+        for addr in bootstrap.into_iter().chain(peers.good_peers()) {
             network.add_route(addr);
         }
         if let Some(advertise_addr) = advertise_addr {
             network.add_route(advertise_addr);
         }
         let responder = AuditorResponder::new(netid, storage.clone());
-        network.listen("node", NodeResponder::new(responder));
+        network.listen("node", NodeResponder::new(responder.clone()));
+        statesync::serve_state_sync(&network, storage.clone());
+        let _rpc_task = rpc_listen.map(|rpc_listen| {
+            let network = network.clone();
+            let responder = responder.clone();
+            smolscale::spawn(async move {
+                if let Err(err) = rpc::serve_rpc(rpc_listen, network, responder).await {
+                    log::error!("RPC server on {} died: {:?}", rpc_listen, err)
+                }
+            })
+        });
         let _network_task = smolscale::spawn({
             let network = network.clone();
             async move {
@@ -54,16 +80,25 @@ impl NodeProtocol {
                 network.run_server(listener).await;
             }
         });
-        let _blksync_task = smolscale::spawn(blksync_loop(netid, network, storage));
+        let _blksync_task = smolscale::spawn(blksync_loop(netid, network, storage, peers));
         Self {
             _network_task,
             _blksync_task,
+            _rpc_task,
         }
     }
 }
 
-#[tracing::instrument(skip(network, storage))]
-async fn blksync_loop(netid: NetID, network: melnet::NetState, storage: SharedStorage) {
+/// How long a peer that served an unconfirmed block is excluded from blksync.
+const BAN_TIME: Duration = Duration::from_secs(600);
+
+#[tracing::instrument(skip(network, storage, peers))]
+async fn blksync_loop(
+    netid: NetID,
+    network: melnet::NetState,
+    storage: SharedStorage,
+    peers: Arc<PeerManager>,
+) {
     let tag = || {
         format!(
             "blksync@{:?}",
@@ -72,17 +107,28 @@ async fn blksync_loop(netid: NetID, network: melnet::NetState, storage: SharedSt
     };
     const SLOW_TIME: Duration = Duration::from_millis(5000);
     const FAST_TIME: Duration = Duration::from_millis(10);
-    let mut random_peer = network.routes().first().cloned();
+    let pick_peer = || {
+        peers.add_peers(network.routes());
+        peers.pick()
+    };
+    let mut random_peer = pick_peer();
     loop {
         if let Some(peer) = random_peer {
             log::trace!("{}: picked random peer {} for blksync", tag(), peer);
             let client = NodeClient::new(netid, peer);
 
-            let res = attempt_blksync(&client, &storage).await;
+            let res = attempt_blksync(peer, &client, &storage, &peers).await;
             match res {
                 Err(e) => {
+                    // a proof we merely can't check, e.g. after pruning, isn't the peer's fault
+                    if let Some(ConsensusError::Invalid { .. }) = e.downcast_ref() {
+                        log::warn!("{}: banning {} for serving unconfirmed blocks", tag(), peer);
+                        peers.ban(peer, BAN_TIME);
+                    } else {
+                        peers.record_failure(peer);
+                    }
                     log::warn!("{}: failed to blksync with {}: {:?}", tag(), peer, e);
-                    random_peer = network.routes().first().cloned();
+                    random_peer = pick_peer();
                     smol::Timer::after(FAST_TIME).await;
                 }
                 Ok(blklen) => {
@@ -91,25 +137,34 @@ async fn blksync_loop(netid: NetID, network: melnet::NetState, storage: SharedSt
                         smol::Timer::after(FAST_TIME).await;
                     } else {
                         smol::Timer::after(SLOW_TIME).await;
-                        random_peer = network.routes().first().cloned()
+                        random_peer = pick_peer()
                     }
                 }
             }
         } else {
             smol::Timer::after(SLOW_TIME).await;
-            random_peer = network.routes().first().cloned()
+            random_peer = pick_peer()
         }
     }
 }
 
-/// Attempts a sync using the given given node client.
-async fn attempt_blksync(client: &NodeClient, storage: &SharedStorage) -> anyhow::Result<usize> {
-    let their_highest = client
-        .get_summary()
-        .await
-        .context("cannot get their highest block")?
-        .height;
+/// Attempts a sync using the given given node client, scoring the peer by how fast it tells us its highest block.
+async fn attempt_blksync(
+    peer: SocketAddr,
+    client: &NodeClient,
+    storage: &SharedStorage,
+    peers: &PeerManager,
+) -> anyhow::Result<usize> {
+    let start = Instant::now();
+    let their_highest = {
+        let _timer = request_timer("get_summary");
+        client.get_summary().await
+    }
+    .context("cannot get their highest block")?
+    .height;
+    peers.record_success(peer, start.elapsed());
     let my_highest = storage.read().highest_height();
+    metrics::BLKSYNC_LAG.set(their_highest.saturating_sub(my_highest) as i64);
     if their_highest <= my_highest {
         return Ok(0);
     }
@@ -119,6 +174,7 @@ async fn attempt_blksync(client: &NodeClient, storage: &SharedStorage) -> anyhow
         .map(Ok::<_, anyhow::Error>)
         .try_filter_map(|height| async move {
             Ok(Some(async move {
+                let _timer = request_timer("get_full_block");
                 Ok(client.get_full_block(height, &lookup_tx).await?)
             }))
         })
@@ -137,6 +193,14 @@ async fn attempt_blksync(client: &NodeClient, storage: &SharedStorage) -> anyhow
     Ok(toret)
 }
 
+/// Starts timing a request to a peer, recording its latency when dropped.
+fn request_timer(request: &str) -> prometheus::HistogramTimer {
+    metrics::PEER_REQUEST_LATENCY
+        .with_label_values(&[request])
+        .start_timer()
+}
+
+#[derive(Clone)]
 struct AuditorResponder {
     network: NetID,
     storage: SharedStorage,
@@ -149,6 +213,7 @@ impl NodeServer for AuditorResponder {
         let post_lock = Instant::now();
         storage.mempool_mut().apply_transaction(&tx).map_err(|e| {
             // log::warn!("cannot apply tx: {:?}", e);
+            metrics::MEMPOOL_REJECTIONS.inc();
             MelnetError::Custom(e.to_string())
         })?;
         log::debug!(
@@ -176,9 +241,7 @@ impl NodeServer for AuditorResponder {
 
     fn get_abbr_block(&self, height: u64) -> melnet::Result<(AbbrBlock, ConsensusProof)> {
         let storage = self.storage.read();
-        let state = storage
-            .get_state(height)
-            .ok_or_else(|| MelnetError::Custom(format!("block {} not confirmed yet", height)))?;
+        let state = confirmed_state(&storage, height)?;
         let proof = storage
             .get_consensus(height)
             .ok_or_else(|| MelnetError::Custom(format!("block {} not confirmed yet", height)))?;
@@ -200,10 +263,7 @@ impl NodeServer for AuditorResponder {
     }
 
     fn get_state(&self, height: u64) -> melnet::Result<SealedState> {
-        self.storage
-            .read()
-            .get_state(height)
-            .ok_or_else(|| melnet::MelnetError::Custom("no such height".into()))
+        confirmed_state(&self.storage.read(), height)
     }
 
     fn get_smt_branch(
@@ -212,17 +272,8 @@ impl NodeServer for AuditorResponder {
         elem: Substate,
         key: HashVal,
     ) -> melnet::Result<(Vec<u8>, CompressedProof)> {
-        let state =
-            self.storage.read().get_state(height).ok_or_else(|| {
-                MelnetError::Custom(format!("block {} not confirmed yet", height))
-            })?;
-        let tree = match elem {
-            Substate::Coins => &state.inner_ref().coins.mapping,
-            Substate::History => &state.inner_ref().history.mapping,
-            Substate::Pools => &state.inner_ref().pools.mapping,
-            Substate::Stakes => &state.inner_ref().stakes.mapping,
-            Substate::Transactions => &state.inner_ref().transactions.mapping,
-        };
+        let state = confirmed_state(&self.storage.read(), height)?;
+        let tree = substate_tree(state.inner_ref(), elem);
         let (v, proof) = tree.get_with_proof(key.0);
         if !proof.verify(tree.root_hash(), key.0, &v) {
             panic!(
@@ -237,10 +288,7 @@ impl NodeServer for AuditorResponder {
     }
 
     fn get_stakers_raw(&self, height: u64) -> melnet::Result<BTreeMap<HashVal, Vec<u8>>> {
-        let state =
-            self.storage.read().get_state(height).ok_or_else(|| {
-                MelnetError::Custom(format!("block {} not confirmed yet", height))
-            })?;
+        let state = confirmed_state(&self.storage.read(), height)?;
         let mut accum = BTreeMap::new();
         for (k, v) in state.inner_ref().stakes.mapping.iter() {
             accum.insert(HashVal(k), v.to_vec());
@@ -253,4 +301,82 @@ impl AuditorResponder {
     fn new(network: NetID, storage: SharedStorage) -> Self {
         Self { network, storage }
     }
+
+    /// Looks something up in the address index.
+    fn with_index<T>(&self, f: impl FnOnce(&AddressIndex) -> T) -> melnet::Result<T> {
+        let storage = self.storage.read();
+        let index = storage
+            .index()
+            .ok_or_else(|| MelnetError::Custom("address index not enabled".into()))?;
+        Ok(f(index))
+    }
+
+    fn get_unspent_coins(&self, covhash: Address) -> melnet::Result<Vec<(CoinID, CoinDataHeight)>> {
+        self.with_index(|index| index.unspent_coins(covhash))
+    }
+
+    fn get_address_transactions(&self, covhash: Address) -> melnet::Result<Vec<(u64, TxHash)>> {
+        self.with_index(|index| index.transactions(covhash))
+    }
+
+    /// Looks up a transaction by hash alone, first among confirmed blocks and then in the mempool. Returns `None` if the node knows nothing about it.
+    fn get_tx_status(&self, txhash: TxHash) -> melnet::Result<Option<TxStatus>> {
+        let location = self.storage.read().tx_index().locate(txhash);
+        if let Some(location) = location {
+            let key = tmelcrypt::hash_single(stdcode::serialize(&txhash).unwrap());
+            let (value, proof) =
+                self.get_smt_branch(location.height, Substate::Transactions, key)?;
+            let transaction = stdcode::deserialize(&value).map_err(|_| {
+                MelnetError::Custom(format!(
+                    "transaction {} missing from block {}",
+                    txhash, location.height
+                ))
+            })?;
+            return Ok(Some(TxStatus::Confirmed {
+                location,
+                transaction,
+                proof,
+            }));
+        }
+        Ok(self
+            .storage
+            .read()
+            .mempool()
+            .lookup(txhash)
+            .map(TxStatus::Pending))
+    }
+}
+
+/// What the node knows about a transaction, given only its hash.
+enum TxStatus {
+    /// In a confirmed block, with a proof of its inclusion in the block's transactions.
+    Confirmed {
+        location: TxLocation,
+        transaction: Transaction,
+        proof: CompressedProof,
+    },
+    /// Waiting in the mempool.
+    Pending(Transaction),
+}
+
+/// Returns the state at a height, or an error telling apart blocks that aren't confirmed yet from blocks whose states were pruned.
+fn confirmed_state(storage: &NodeStorage, height: u64) -> melnet::Result<SealedState> {
+    storage.get_state(height).ok_or_else(|| {
+        if storage.is_pruned(height) {
+            MelnetError::Custom(format!("block {} pruned", height))
+        } else {
+            MelnetError::Custom(format!("block {} not confirmed yet", height))
+        }
+    })
+}
+
+/// Returns the SMT holding one part of a state.
+fn substate_tree(state: &State, elem: Substate) -> &novasmt::Tree {
+    match elem {
+        Substate::Coins => &state.coins.mapping,
+        Substate::History => &state.history.mapping,
+        Substate::Pools => &state.pools.mapping,
+        Substate::Stakes => &state.stakes.mapping,
+        Substate::Transactions => &state.transactions.mapping,
+    }
 }
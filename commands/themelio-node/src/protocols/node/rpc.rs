@@ -0,0 +1,410 @@
+use std::{collections::BTreeMap, net::SocketAddr, str::FromStr, sync::Arc};
+
+use melnet::MelnetError;
+use serde::Serialize;
+use themelio_nodeprot::{NodeServer, Substate};
+use themelio_stf::{
+    melvm::Address, Block, CoinDataHeight, CoinID, ConsensusProof, StakeDoc, Transaction, TxHash,
+};
+use tide::{Body, Request, Response, StatusCode};
+use tmelcrypt::HashVal;
+
+use super::{AuditorResponder, TxStatus};
+
+/// State shared by all RPC handlers.
+#[derive(Clone)]
+struct RpcState {
+    responder: Arc<AuditorResponder>,
+    network: melnet::NetState,
+}
+
+/// A block together with its consensus proof.
+#[derive(Serialize)]
+struct BlockResponse {
+    block: Block,
+    proof: ConsensusProof,
+}
+
+/// An unspent coin, as found in the address index.
+#[derive(Serialize)]
+struct CoinResponse {
+    coinid: CoinID,
+    coin: CoinDataHeight,
+}
+
+/// A transaction that spent from or paid to an address, as found in the address index.
+#[derive(Serialize)]
+struct AddressTransaction {
+    height: u64,
+    txhash: TxHash,
+}
+
+/// A transaction looked up by hash alone.
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum TransactionResponse {
+    /// In a confirmed block, with a hex-encoded compressed proof of its inclusion in the block's transactions SMT. `index` is its position in the block, in order of transaction hash.
+    Confirmed {
+        height: u64,
+        index: u32,
+        transaction: Transaction,
+        proof: String,
+    },
+    /// Waiting in the mempool.
+    Pending { transaction: Transaction },
+}
+
+/// A value looked up in a state SMT, together with a hex-encoded compressed proof of its inclusion (or non-inclusion).
+#[derive(Serialize)]
+struct ProvenResponse<T> {
+    value: Option<T>,
+    proof: String,
+}
+
+/// Serves the HTTP/JSON query API. Every endpoint answers through the same [AuditorResponder] that serves melnet requests.
+pub(super) async fn serve_rpc(
+    listen: SocketAddr,
+    network: melnet::NetState,
+    responder: AuditorResponder,
+) -> std::io::Result<()> {
+    log::info!("serving RPC on {}", listen);
+    rpc_app(network, responder).listen(listen).await
+}
+
+/// Routes every endpoint of the HTTP/JSON query API.
+fn rpc_app(network: melnet::NetState, responder: AuditorResponder) -> tide::Server<RpcState> {
+    let mut app = tide::with_state(RpcState {
+        responder: Arc::new(responder),
+        network,
+    });
+    // tide doesn't put error messages in responses by default
+    app.with(tide::utils::After(|mut res: Response| async move {
+        if let Some(err) = res.error() {
+            let msg = err.to_string();
+            res.set_body(msg);
+        }
+        Ok(res)
+    }));
+    app.at("/summary").get(get_summary);
+    app.at("/blocks/:height").get(get_block);
+    app.at("/blocks/by-hash/:blkhash").get(get_block_by_hash);
+    app.at("/blocks/:height/transactions/:txhash")
+        .get(get_transaction);
+    app.at("/blocks/:height/coins/:coinid").get(get_coin);
+    app.at("/blocks/:height/stakers").get(get_stakers);
+    app.at("/transactions").post(send_tx);
+    app.at("/transactions/:txhash").get(get_tx_by_hash);
+    app.at("/addresses/:covhash/coins").get(get_address_coins);
+    app.at("/addresses/:covhash/transactions")
+        .get(get_address_transactions);
+    app.at("/evidence").get(get_evidence);
+    app
+}
+
+async fn get_summary(req: Request<RpcState>) -> tide::Result {
+    let responder = req.state().responder.clone();
+    let summary = smol::unblock(move || responder.get_summary())
+        .await
+        .map_err(not_found)?;
+    json(&summary)
+}
+
+async fn get_block(req: Request<RpcState>) -> tide::Result {
+    let height: u64 = param(&req, "height")?;
+    json(&block_response(req.state().responder.clone(), height).await?)
+}
+
+async fn get_block_by_hash(req: Request<RpcState>) -> tide::Result {
+    let blkhash: HashVal = param(&req, "blkhash")?;
+    let responder = req.state().responder.clone();
+    let height = smol::unblock(move || responder.storage.read().get_height_by_hash(blkhash))
+        .await
+        .ok_or_else(|| tide::Error::from_str(StatusCode::NotFound, "no such block"))?;
+    json(&block_response(req.state().responder.clone(), height).await?)
+}
+
+async fn get_transaction(req: Request<RpcState>) -> tide::Result {
+    let height: u64 = param(&req, "height")?;
+    let txhash: HashVal = param(&req, "txhash")?;
+    let key = tmelcrypt::hash_single(stdcode::serialize(&TxHash(txhash)).unwrap());
+    json(&proven::<Transaction>(req, height, Substate::Transactions, key).await?)
+}
+
+async fn get_coin(req: Request<RpcState>) -> tide::Result {
+    let height: u64 = param(&req, "height")?;
+    let coinid: CoinID = param(&req, "coinid")?;
+    let key = tmelcrypt::hash_single(stdcode::serialize(&coinid).unwrap());
+    json(&proven::<CoinDataHeight>(req, height, Substate::Coins, key).await?)
+}
+
+async fn get_stakers(req: Request<RpcState>) -> tide::Result {
+    let height: u64 = param(&req, "height")?;
+    let responder = req.state().responder.clone();
+    let raw = smol::unblock(move || responder.get_stakers_raw(height))
+        .await
+        .map_err(not_found)?;
+    let mut stakers = BTreeMap::new();
+    for (key, value) in raw {
+        let doc: StakeDoc = stdcode::deserialize(&value)?;
+        stakers.insert(key, doc);
+    }
+    json(&stakers)
+}
+
+async fn send_tx(mut req: Request<RpcState>) -> tide::Result {
+    let tx: Transaction = req.body_json().await?;
+    let txhash = tx.hash_nosigs();
+    let responder = req.state().responder.clone();
+    let network = req.state().network.clone();
+    smol::unblock(move || responder.send_tx(network, tx))
+        .await
+        .map_err(|err| tide::Error::from_str(StatusCode::BadRequest, err.to_string()))?;
+    json(&txhash)
+}
+
+async fn get_tx_by_hash(req: Request<RpcState>) -> tide::Result {
+    let txhash: HashVal = param(&req, "txhash")?;
+    let responder = req.state().responder.clone();
+    let status = smol::unblock(move || responder.get_tx_status(TxHash(txhash)))
+        .await
+        .map_err(not_found)?
+        .ok_or_else(|| tide::Error::from_str(StatusCode::NotFound, "unknown transaction"))?;
+    json(&match status {
+        TxStatus::Confirmed {
+            location,
+            transaction,
+            proof,
+        } => TransactionResponse::Confirmed {
+            height: location.height,
+            index: location.index,
+            transaction,
+            proof: hex::encode(&proof.0),
+        },
+        TxStatus::Pending(transaction) => TransactionResponse::Pending { transaction },
+    })
+}
+
+async fn get_address_coins(req: Request<RpcState>) -> tide::Result {
+    let covhash: Address = param(&req, "covhash")?;
+    let responder = req.state().responder.clone();
+    let coins = smol::unblock(move || responder.get_unspent_coins(covhash))
+        .await
+        .map_err(not_found)?;
+    json(
+        &coins
+            .into_iter()
+            .map(|(coinid, coin)| CoinResponse { coinid, coin })
+            .collect::<Vec<_>>(),
+    )
+}
+
+async fn get_address_transactions(req: Request<RpcState>) -> tide::Result {
+    let covhash: Address = param(&req, "covhash")?;
+    let responder = req.state().responder.clone();
+    let transactions = smol::unblock(move || responder.get_address_transactions(covhash))
+        .await
+        .map_err(not_found)?;
+    json(
+        &transactions
+            .into_iter()
+            .map(|(height, txhash)| AddressTransaction { height, txhash })
+            .collect::<Vec<_>>(),
+    )
+}
+
+async fn get_evidence(req: Request<RpcState>) -> tide::Result {
+    let responder = req.state().responder.clone();
+    let evidence = smol::unblock(move || responder.storage.read().evidence()).await;
+    json(&evidence)
+}
+
+async fn block_response(
+    responder: Arc<AuditorResponder>,
+    height: u64,
+) -> tide::Result<BlockResponse> {
+    smol::unblock(move || {
+        let (_, proof) = responder.get_abbr_block(height)?;
+        let block = responder.get_state(height)?.to_block();
+        Ok(BlockResponse { block, proof })
+    })
+    .await
+    .map_err(not_found)
+}
+
+/// Looks up a value in one of the SMTs of the state at the given height.
+async fn proven<T: serde::de::DeserializeOwned>(
+    req: Request<RpcState>,
+    height: u64,
+    elem: Substate,
+    key: HashVal,
+) -> tide::Result<ProvenResponse<T>> {
+    let responder = req.state().responder.clone();
+    let (value, proof) = smol::unblock(move || responder.get_smt_branch(height, elem, key))
+        .await
+        .map_err(not_found)?;
+    let value = if value.is_empty() {
+        None
+    } else {
+        Some(stdcode::deserialize(&value)?)
+    };
+    Ok(ProvenResponse {
+        value,
+        proof: hex::encode(&proof.0),
+    })
+}
+
+fn param<T: FromStr>(req: &Request<RpcState>, name: &str) -> tide::Result<T> {
+    req.param(name)?
+        .parse()
+        .map_err(|_| tide::Error::from_str(StatusCode::BadRequest, format!("malformed {}", name)))
+}
+
+fn json(value: &impl Serialize) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(value)?)
+        .build())
+}
+
+fn not_found(err: MelnetError) -> tide::Error {
+    tide::Error::from_str(StatusCode::NotFound, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use themelio_stf::{CoinID, NetID};
+    use tide::http::{Method, Url};
+
+    use super::*;
+    use crate::storage::tests::{sign, spend, staker_genesis, temp_storage, FEE};
+
+    #[derive(Deserialize)]
+    struct BlockBody {
+        block: Block,
+    }
+
+    #[derive(Deserialize)]
+    struct ProvenBody<T> {
+        value: Option<T>,
+        proof: String,
+    }
+
+    #[derive(Deserialize)]
+    struct StatusBody {
+        status: String,
+    }
+
+    /// Sends a request to the API, returning the status and the body.
+    fn call(
+        app: &tide::Server<RpcState>,
+        method: Method,
+        path: &str,
+        body: Option<Body>,
+    ) -> (StatusCode, String) {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        if let Some(body) = body {
+            req.set_body(body);
+        }
+        smol::block_on(async {
+            let mut res: tide::http::Response = app.respond(req).await.unwrap();
+            (res.status(), res.body_string().await.unwrap())
+        })
+    }
+
+    /// An API over a fresh storage, with one block that splits the genesis coin in two.
+    fn setup() -> (tide::Server<RpcState>, Block, Transaction) {
+        let staker = tmelcrypt::ed25519_keygen();
+        let genesis = staker_genesis(staker.0);
+        let half = (genesis.init_coindata.value - FEE) / 2;
+        let storage = temp_storage(&genesis).share();
+        let split = spend(CoinID::zero_zero(), vec![half, half]);
+        let mut next = storage.read().highest_state().next_state();
+        next.apply_tx(&split).unwrap();
+        let block = next.seal(None).to_block();
+        let cproof = sign(&block, staker);
+        storage.write().apply_block(block.clone(), cproof).unwrap();
+        let responder = AuditorResponder {
+            network: NetID::Testnet,
+            storage,
+        };
+        let app = rpc_app(melnet::NetState::new_with_name("rpc-test"), responder);
+        (app, block, split)
+    }
+
+    #[test]
+    fn block_and_coin_lookup() {
+        let (app, block, split) = setup();
+        let (status, body) = call(&app, Method::Get, "/blocks/1", None);
+        assert_eq!(status, StatusCode::Ok);
+        let got: BlockBody = serde_json::from_str(&body).unwrap();
+        assert_eq!(got.block.header, block.header);
+        assert_eq!(got.block.transactions, block.transactions);
+
+        let coinid = split.output_coinid(1);
+        let (status, body) = call(
+            &app,
+            Method::Get,
+            &format!("/blocks/1/coins/{}", coinid),
+            None,
+        );
+        assert_eq!(status, StatusCode::Ok);
+        let coin: ProvenBody<CoinDataHeight> = serde_json::from_str(&body).unwrap();
+        let coin = coin.value.unwrap();
+        assert_eq!(coin.height, 1);
+        assert_eq!(coin.coin_data, split.outputs[1]);
+        // the genesis coin is spent by now, which is proven rather than just missing
+        let (status, body) = call(
+            &app,
+            Method::Get,
+            &format!("/blocks/1/coins/{}", CoinID::zero_zero()),
+            None,
+        );
+        assert_eq!(status, StatusCode::Ok);
+        let spent: ProvenBody<CoinDataHeight> = serde_json::from_str(&body).unwrap();
+        assert!(spent.value.is_none());
+        assert!(!spent.proof.is_empty());
+
+        let (status, _) = call(&app, Method::Get, "/blocks/2", None);
+        assert_eq!(status, StatusCode::NotFound);
+        let (status, _) = call(&app, Method::Get, "/blocks/1/coins/garbage", None);
+        assert_eq!(status, StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn send_tx() {
+        let (app, _, split) = setup();
+        let half = split.outputs[0].value;
+        let tx = spend(split.output_coinid(0), vec![half - FEE]);
+        let (status, body) = call(
+            &app,
+            Method::Post,
+            "/transactions",
+            Some(Body::from_json(&tx).unwrap()),
+        );
+        assert_eq!(status, StatusCode::Ok);
+        let txhash: TxHash = serde_json::from_str(&body).unwrap();
+        assert_eq!(txhash, tx.hash_nosigs());
+        let (status, body) = call(
+            &app,
+            Method::Get,
+            &format!("/transactions/{}", txhash.0),
+            None,
+        );
+        assert_eq!(status, StatusCode::Ok);
+        let got: StatusBody = serde_json::from_str(&body).unwrap();
+        assert_eq!(got.status, "pending");
+
+        // spending the same coin again is rejected
+        let double = spend(split.output_coinid(0), vec![half - 2 * FEE, FEE]);
+        let (status, _) = call(
+            &app,
+            Method::Post,
+            "/transactions",
+            Some(Body::from_json(&double).unwrap()),
+        );
+        assert_eq!(status, StatusCode::BadRequest);
+    }
+}
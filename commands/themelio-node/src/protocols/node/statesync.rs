@@ -0,0 +1,471 @@
+use std::{collections::BTreeSet, net::SocketAddr, time::Duration};
+
+use anyhow::Context;
+use novasmt::{BackendDB, BackendNode, Hashed};
+use peermgr::PeerManager;
+use serde::{Deserialize, Serialize};
+use themelio_nodeprot::{NodeClient, Substate};
+use themelio_stf::{Block, ConsensusProof, NetID, SealedState, SmtMapping, State};
+use thiserror::Error;
+use tmelcrypt::HashVal;
+
+use super::{confirmed_state, netname, substate_tree, BAN_TIME};
+use crate::storage::{check_consensus, BoringDbSmt, SharedStorage};
+
+/// The melnet verb for state sync, served next to the regular node protocol.
+const STATE_SYNC_VERB: &str = "state_sync";
+
+/// The most SMT entries that a single [StateSyncRequest::GetSubstate] is answered with.
+const SUBSTATE_PAGE_SIZE: usize = 1000;
+
+/// How many peers, besides the one serving the state, are asked for the tips at the checkpoint. The header doesn't commit to the tips, so they must all agree.
+const TIPS_WITNESSES: usize = 3;
+
+/// How many blocks after the checkpoint are applied to its state before it's installed, looking for the first one with a proposer action. That's the first block whose header depends on the tips.
+const TIPS_CHECK_BLOCKS: u64 = 100;
+
+/// Requests for the bulk contents of a state, so that a fresh node can start from a trusted checkpoint rather than replaying every block.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum StateSyncRequest {
+    /// Asks for the tips collected in the state at a height. Answered with a `u128`.
+    GetTips(u64),
+    /// Asks for the entries of one SMT in the state at a height whose keys come after `after`, in order of key. Answered with up to [SUBSTATE_PAGE_SIZE] key-value pairs, where fewer means there are no more.
+    GetSubstate {
+        height: u64,
+        substate: Substate,
+        after: Option<HashVal>,
+    },
+}
+
+/// Error returned when a peer serves state that doesn't match the trusted checkpoint.
+#[derive(Error, Debug)]
+#[error("state served for checkpoint {height} doesn't match it: {reason}")]
+struct BadCheckpoint {
+    height: u64,
+    reason: String,
+}
+
+/// Answers state sync requests from the given storage.
+pub(super) fn serve_state_sync(network: &melnet::NetState, storage: SharedStorage) {
+    network.listen(
+        STATE_SYNC_VERB,
+        move |req: melnet::Request<StateSyncRequest, Vec<u8>>| {
+            let storage = storage.clone();
+            smolscale::spawn(async move {
+                let body = req.body.clone();
+                let response = smol::unblock(move || respond(&storage, body)).await;
+                req.response.send(response)
+            })
+            .detach();
+        },
+    );
+}
+
+fn respond(storage: &SharedStorage, request: StateSyncRequest) -> melnet::Result<Vec<u8>> {
+    let get_state = |height| confirmed_state(&storage.read(), height);
+    match request {
+        StateSyncRequest::GetTips(height) => {
+            Ok(stdcode::serialize(&get_state(height)?.inner_ref().tips).unwrap())
+        }
+        StateSyncRequest::GetSubstate {
+            height,
+            substate,
+            after,
+        } => {
+            let state = get_state(height)?;
+            let root = substate_tree(state.inner_ref(), substate).root_hash();
+            let smt = storage.read().smt();
+            let page = smt_page(&smt, root, after.map(|after| after.0), SUBSTATE_PAGE_SIZE)
+                .ok_or_else(|| {
+                    melnet::MelnetError::Custom(format!(
+                        "{:?} at {} is incomplete",
+                        substate, height
+                    ))
+                })?
+                .into_iter()
+                .map(|(key, value)| (HashVal(key), value))
+                .collect::<Vec<_>>();
+            Ok(stdcode::serialize(&page).unwrap())
+        }
+    }
+}
+
+/// Reads up to `limit` entries of the SMT with the given root, in order of key, starting right after the key `after`. Subtrees whose keys all come before `after` are skipped without being read, so a page costs the same however far into the SMT it is. Returns `None` if a node is missing.
+fn smt_page(
+    smt: &BoringDbSmt,
+    root: Hashed,
+    after: Option<Hashed>,
+    limit: usize,
+) -> Option<Vec<(Hashed, Vec<u8>)>> {
+    // keys are paths from the root, most significant bit first, with 1 going right
+    let bit = |key: &Hashed, idx: usize| key[idx / 8] & (0x80 >> (idx % 8)) != 0;
+    let mut page = Vec::new();
+    // every node comes with its depth, and whether its path so far is a prefix of `after`
+    let mut stack = vec![(root, 0, after.is_some())];
+    while let Some((hash, depth, on_path)) = stack.pop() {
+        if page.len() >= limit {
+            break;
+        }
+        if hash == [0; 32] {
+            continue;
+        }
+        match smt.get(hash)? {
+            BackendNode::Internal(left, right) => {
+                let go_right = on_path && bit(&after.unwrap(), depth);
+                // the left subtree comes first, so it goes on the stack last
+                stack.push((right, depth + 1, on_path && go_right));
+                if !go_right {
+                    stack.push((left, depth + 1, on_path));
+                }
+            }
+            BackendNode::Leaf(key, value) => {
+                if after.map(|after| key > after).unwrap_or(true) {
+                    page.push((key, value.to_vec()));
+                }
+            }
+        }
+    }
+    Some(page)
+}
+
+/// Brings a node that's behind a trusted checkpoint up to it by downloading the state at the checkpoint from peers, instead of replaying every block before it. The state is checked against the checkpoint's block hash, then installed as the genesis of the block history, so that block sync carries on from there.
+///
+/// Keeps trying different peers until it succeeds. Does nothing if the node already has the checkpoint's height.
+pub async fn state_sync(
+    netid: NetID,
+    storage: SharedStorage,
+    peers: &PeerManager,
+    height: u64,
+    blkhash: HashVal,
+) {
+    if storage.read().highest_height() >= height {
+        log::info!("already past checkpoint {}, not state syncing", height);
+        return;
+    }
+    loop {
+        let peer = if let Some(peer) = peers.pick() {
+            peer
+        } else {
+            log::warn!("no peers to state sync with");
+            smol::Timer::after(Duration::from_secs(5)).await;
+            continue;
+        };
+        log::info!("state syncing to checkpoint {} with {}", height, peer);
+        match attempt_state_sync(netid, peer, peers, &storage, height, blkhash).await {
+            Ok(()) => {
+                log::info!("installed checkpoint {} ({}) as genesis", height, blkhash);
+                return;
+            }
+            Err(err) => {
+                if err.downcast_ref::<BadCheckpoint>().is_some() {
+                    peers.ban(peer, BAN_TIME);
+                } else {
+                    peers.record_failure(peer);
+                }
+                log::warn!("failed to state sync with {}: {:?}", peer, err);
+                smol::Timer::after(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn attempt_state_sync(
+    netid: NetID,
+    peer: SocketAddr,
+    peers: &PeerManager,
+    storage: &SharedStorage,
+    height: u64,
+    blkhash: HashVal,
+) -> anyhow::Result<()> {
+    let bad = |reason: String| BadCheckpoint { height, reason };
+    let (block, cproof) = NodeClient::new(netid, peer)
+        .get_full_block(height, |_| None)
+        .await
+        .context("cannot get the checkpoint block")?;
+    let header = block.header;
+    if header.hash() != blkhash {
+        return Err(bad(format!("block has hash {}", header.hash())).into());
+    }
+
+    // the header commits to the root of every SMT, so each one is checked as soon as it's downloaded
+    let forest = storage.read().forest();
+    let substate = |substate, root| download_substate(netid, peer, &forest, height, substate, root);
+    let history = substate(Substate::History, header.history_hash).await?;
+    let coins = substate(Substate::Coins, header.coins_hash).await?;
+    let pools = substate(Substate::Pools, header.pools_hash).await?;
+    let stakes = substate(Substate::Stakes, header.stakes_hash).await?;
+    let mut transactions = SmtMapping::new(forest.open_tree(HashVal::default().0).unwrap());
+    for tx in block.transactions.iter() {
+        transactions.insert(tx.hash_nosigs(), tx.clone());
+    }
+    if transactions.root_hash() != header.transactions_hash {
+        return Err(bad("transactions don't match".into()).into());
+    }
+    // the header doesn't commit to the tips. If the proposer collected them, we know they're zero, but otherwise we have to ask around
+    let tips = if block.proposer_action.is_some() {
+        0
+    } else {
+        fetch_tips(netid, peer, peers, height).await?
+    };
+
+    let mut state = State {
+        network: header.network,
+        height,
+        history: SmtMapping::new(history),
+        coins: SmtMapping::new(coins),
+        transactions,
+        fee_pool: header.fee_pool,
+        fee_multiplier: header.fee_multiplier,
+        tips,
+        dosc_speed: header.dosc_speed,
+        pools: SmtMapping::new(pools),
+        stakes: SmtMapping::new(stakes),
+    };
+    state.save_smts();
+    // sealing would apply the proposer action all over again, so we "restore" the sealed state instead
+    let sealed = SealedState::from_partial_encoding_infallible(
+        &stdcode::serialize(&(state.partial_encoding(), block.proposer_action)).unwrap(),
+        &forest,
+    );
+    if sealed.header() != header {
+        return Err(bad("rebuilt state has a different header".into()).into());
+    }
+    if block.proposer_action.is_none() {
+        check_following_blocks(netid, peer, &sealed).await?;
+    }
+    storage.write().install_checkpoint(sealed, cproof);
+    Ok(())
+}
+
+/// Gets the tips at a height from the given peer, and checks that up to [TIPS_WITNESSES] other peers agree. Witnesses that can't be reached are skipped, but any disagreement fails the attempt, since we can't tell who's lying.
+async fn fetch_tips(
+    netid: NetID,
+    peer: SocketAddr,
+    peers: &PeerManager,
+    height: u64,
+) -> anyhow::Result<u128> {
+    let get_tips = |peer| async move {
+        let tips: u128 =
+            stdcode::deserialize(&request(netid, peer, StateSyncRequest::GetTips(height)).await?)?;
+        Ok::<_, anyhow::Error>(tips)
+    };
+    let tips = get_tips(peer).await?;
+    let witnesses = (0..TIPS_WITNESSES * 2)
+        .filter_map(|_| peers.pick())
+        .filter(|witness| *witness != peer)
+        .collect::<BTreeSet<_>>();
+    for witness in witnesses.into_iter().take(TIPS_WITNESSES) {
+        match get_tips(witness).await {
+            Ok(theirs) if theirs != tips => anyhow::bail!(
+                "{} and {} disagree on the tips at {}: {} vs {}",
+                peer,
+                witness,
+                height,
+                tips,
+                theirs
+            ),
+            Ok(_) => {}
+            Err(err) => log::debug!("cannot get tips from {}: {:?}", witness, err),
+        }
+    }
+    Ok(tips)
+}
+
+/// Applies the confirmed blocks after a rebuilt checkpoint state, up to the first one with a proposer action, whose header depends on the tips passed on from the checkpoint. This catches bad tips before the state is installed, since afterwards they would wedge block sync for good. If the peer doesn't have those blocks yet, e.g. because the checkpoint is recent, the tips are trusted on the strength of [fetch_tips] alone.
+async fn check_following_blocks(
+    netid: NetID,
+    peer: SocketAddr,
+    checkpoint: &SealedState,
+) -> anyhow::Result<()> {
+    let client = NodeClient::new(netid, peer);
+    let checkpoint_height = checkpoint.inner_ref().height;
+    let bad = |reason: String| BadCheckpoint {
+        height: checkpoint_height,
+        reason,
+    };
+    let mut state = checkpoint.clone();
+    for height in checkpoint_height + 1..=checkpoint_height + TIPS_CHECK_BLOCKS {
+        let (block, cproof) = match client.get_full_block(height, |_| None).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::debug!("cannot check the tips against block {}: {:?}", height, err);
+                return Ok(());
+            }
+        };
+        state = apply_confirmed(&state, &block, &cproof).map_err(|err| bad(err.to_string()))?;
+        if block.proposer_action.is_some() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Applies a block to the state before it, after checking its consensus proof against that state's stakes.
+fn apply_confirmed(
+    state: &SealedState,
+    block: &Block,
+    cproof: &ConsensusProof,
+) -> anyhow::Result<SealedState> {
+    check_consensus(&state.inner_ref().stakes, &block.header, cproof)?;
+    state
+        .apply_block(block)
+        .map_err(|err| anyhow::anyhow!("block {} doesn't apply: {:?}", block.header.height, err))
+}
+
+/// Downloads one SMT of the state at a height into the forest, checking that it has the given root.
+async fn download_substate(
+    netid: NetID,
+    peer: SocketAddr,
+    forest: &novasmt::Forest,
+    height: u64,
+    substate: Substate,
+    root: HashVal,
+) -> anyhow::Result<novasmt::Tree> {
+    let mut tree = forest.open_tree(HashVal::default().0).unwrap();
+    let mut after = None;
+    let mut count = 0;
+    loop {
+        let page: Vec<(HashVal, Vec<u8>)> = stdcode::deserialize(
+            &request(
+                netid,
+                peer,
+                StateSyncRequest::GetSubstate {
+                    height,
+                    substate,
+                    after,
+                },
+            )
+            .await?,
+        )?;
+        count += page.len();
+        let done = page.len() < SUBSTATE_PAGE_SIZE;
+        for (key, value) in page {
+            // an honest peer never goes backwards, which would make us loop forever
+            if after.map(|after| key <= after).unwrap_or(false) {
+                return Err(BadCheckpoint {
+                    height,
+                    reason: format!("{:?} isn't served in order", substate),
+                }
+                .into());
+            }
+            after = Some(key);
+            tree.insert(key.0, value.into());
+        }
+        // keeps the part of the tree held in memory small
+        tree.save();
+        if done {
+            break;
+        }
+    }
+    log::debug!("downloaded {} entries of {:?}", count, substate);
+    if HashVal(tree.root_hash()) != root {
+        return Err(BadCheckpoint {
+            height,
+            reason: format!("{:?} doesn't match", substate),
+        }
+        .into());
+    }
+    Ok(tree)
+}
+
+async fn request(
+    netid: NetID,
+    peer: SocketAddr,
+    request: StateSyncRequest,
+) -> melnet::Result<Vec<u8>> {
+    melnet::request(peer, netname(netid), STATE_SYNC_VERB, request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use themelio_stf::{GenesisConfig, ProposerAction};
+
+    use super::*;
+    use crate::storage::tests::{sign, staker_genesis, temp_storage};
+
+    #[test]
+    fn substate_pages() {
+        let storage = temp_storage(&GenesisConfig::std_testnet());
+        let mut tree = storage.forest().open_tree([0; 32]).unwrap();
+        for i in 0..2500u64 {
+            tree.insert(
+                tmelcrypt::hash_single(i.to_be_bytes()).0,
+                vec![i as u8].into(),
+            );
+        }
+        tree.save();
+        let mut all = tree
+            .iter()
+            .map(|(key, value)| (key, value.to_vec()))
+            .collect::<Vec<_>>();
+        all.sort_unstable();
+
+        // paging through with the last key of every page gets everything, in order
+        let mut paged = vec![];
+        let mut after = None;
+        loop {
+            let page = smt_page(&storage.smt(), tree.root_hash(), after, 1000).unwrap();
+            let done = page.len() < 1000;
+            after = page.last().map(|(key, _)| *key);
+            paged.extend(page);
+            if done {
+                break;
+            }
+        }
+        assert_eq!(paged, all);
+
+        // the cursor doesn't have to be a key in the SMT
+        let mut after = all[1234].0;
+        after[31] ^= 1;
+        let page = smt_page(&storage.smt(), tree.root_hash(), Some(after), 10).unwrap();
+        let expected = all
+            .iter()
+            .filter(|(key, _)| *key > after)
+            .take(10)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(page, expected);
+        assert!(
+            smt_page(&storage.smt(), tree.root_hash(), Some([0xff; 32]), 10)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(smt_page(&storage.smt(), [0; 32], None, 10), Some(vec![]));
+    }
+
+    #[test]
+    fn bad_tips_caught_by_next_proposal() {
+        let (pk, sk) = tmelcrypt::ed25519_keygen();
+        let storage = temp_storage(&staker_genesis(pk));
+        let genesis = storage.highest_state();
+        // the checkpoint is an empty block, so its header doesn't tell us the tips
+        let checkpoint = genesis.next_state().seal(None);
+        let mut lying = genesis.next_state();
+        lying.tips = 1_000_000;
+        let lying = lying.seal(None);
+        assert_eq!(lying.header(), checkpoint.header());
+
+        let empty = checkpoint.next_state().seal(None).to_block();
+        let proposal = checkpoint
+            .next_state()
+            .seal(Some(ProposerAction {
+                fee_multiplier_delta: 0,
+                reward_dest: HashVal::default().into(),
+            }))
+            .to_block();
+        // an empty block doesn't use the tips, but the next proposal does
+        let after_empty = apply_confirmed(&lying, &empty, &sign(&empty, (pk, sk))).unwrap();
+        assert_eq!(after_empty.header(), empty.header);
+        assert!(apply_confirmed(&lying, &proposal, &sign(&proposal, (pk, sk))).is_err());
+        apply_confirmed(&checkpoint, &proposal, &sign(&proposal, (pk, sk))).unwrap();
+
+        // blocks without a valid consensus proof don't count either way
+        let (other_pk, other_sk) = tmelcrypt::ed25519_keygen();
+        assert!(apply_confirmed(
+            &checkpoint,
+            &proposal,
+            &sign(&proposal, (other_pk, other_sk))
+        )
+        .is_err());
+    }
+}
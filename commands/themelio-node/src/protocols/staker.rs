@@ -6,6 +6,7 @@ use themelio_stf::{
 };
 
 use novasymph::BlockBuilder;
+use peermgr::PeerManager;
 use smol::prelude::*;
 use std::{
     net::SocketAddr,
@@ -21,13 +22,16 @@ static MAINNET_START_TIME: Lazy<SystemTime> =
 static TESTNET_START_TIME: Lazy<SystemTime> =
     Lazy::new(|| std::time::UNIX_EPOCH + Duration::from_secs(1617249600)); // Apr 01 2021
 
+/// The most transaction weight we put into a block we propose.
+const MAX_BLOCK_WEIGHT: u128 = 10_000_000;
+
 /// This encapsulates the staker-specific peer-to-peer.
 pub struct StakerProtocol {
     _network_task: smol::Task<()>,
 }
 
 impl StakerProtocol {
-    /// Creates a new instance of the staker protocol.
+    /// Creates a new instance of the staker protocol. Gossip picks its peers through the given peer manager, which outlives the epochs.
     pub fn new(
         addr: SocketAddr,
         bootstrap: Vec<SocketAddr>,
@@ -35,6 +39,7 @@ impl StakerProtocol {
         my_sk: Ed25519SK,
         payout_address: Address,
         target_fee_multiplier: u128,
+        peers: Arc<PeerManager>,
     ) -> anyhow::Result<Self> {
         let _network_task = smolscale::spawn(async move {
             loop {
@@ -63,6 +68,7 @@ impl StakerProtocol {
                         my_sk,
                         payout_address,
                         target_fee_multiplier,
+                        peers.clone(),
                     );
                     let epoch_termination = async {
                         loop {
@@ -84,8 +90,8 @@ impl StakerProtocol {
     }
 }
 
-#[allow(clippy::or_fun_call)]
-#[instrument(skip(storage, my_sk))]
+#[allow(clippy::or_fun_call, clippy::too_many_arguments)]
+#[instrument(skip(storage, my_sk, peers))]
 async fn one_epoch_loop(
     epoch: u64,
     addr: SocketAddr,
@@ -94,6 +100,7 @@ async fn one_epoch_loop(
     my_sk: Ed25519SK,
     payout_covhash: Address,
     target_fee_multiplier: u128,
+    peers: Arc<PeerManager>,
 ) -> anyhow::Result<()> {
     let genesis = storage.read().highest_state();
     let forest = storage.clone().read().forest();
@@ -103,7 +110,8 @@ async fn one_epoch_loop(
     };
     let config = novasymph::EpochConfig {
         listen: addr,
-        bootstrap,
+        // peers remembered from before help with discovery, just like the bootstrap peers
+        bootstrap: bootstrap.into_iter().chain(peers.good_peers()).collect(),
         genesis,
         forest,
         start_time,
@@ -123,8 +131,13 @@ async fn one_epoch_loop(
                     .confirm(storage.get_consensus(height)?, None)
             })
         },
+        peers,
+        store: Some(Arc::new(storage.read().consensus_store(my_sk.to_public()))),
     };
     let protocol = Arc::new(novasymph::EpochProtocol::new(config));
+    for evidence in storage.read().evidence() {
+        protocol.inject_evidence(evidence);
+    }
     let main_loop = async {
         loop {
             let confirmed = protocol.next_confirmed().await;
@@ -149,7 +162,22 @@ async fn one_epoch_loop(
             smol::Timer::after(Duration::from_secs(5)).await;
         }
     };
-    main_loop.race(reset_loop).await
+    let evidence_loop = async {
+        loop {
+            smol::Timer::after(Duration::from_secs(10)).await;
+            let storage = storage.read();
+            for evidence in protocol.evidence() {
+                if storage.insert_evidence(&evidence) {
+                    log::warn!(
+                        "recorded equivocation by {:?} at height {}",
+                        evidence.offender(),
+                        evidence.height()
+                    );
+                }
+            }
+        }
+    };
+    main_loop.race(reset_loop).race(evidence_loop).await
 }
 
 struct StorageBlockBuilder {
@@ -169,7 +197,10 @@ impl BlockBuilder for StorageBlockBuilder {
             },
             reward_dest: self.payout_covhash,
         };
-        let mempool_state = storage.mempool().to_state().seal(Some(proposer_action));
+        let mempool_state = storage
+            .mempool()
+            .build_state(MAX_BLOCK_WEIGHT)
+            .seal(Some(proposer_action));
         if mempool_state.header().previous != tip.header().hash() {
             log::warn!(
                 "mempool {} doesn't extend from tip {}; building quasiempty block",
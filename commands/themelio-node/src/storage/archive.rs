@@ -0,0 +1,219 @@
+use std::{
+    convert::TryInto,
+    io::{self, Read, Write},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use themelio_stf::{Block, ConsensusProof, NetID};
+
+use super::NodeStorage;
+
+/// Magic bytes at the start of every chain archive.
+const MAGIC: &[u8; 8] = b"tmlchain";
+
+/// The current version of the archive format.
+const VERSION: u32 = 1;
+
+/// Archive records larger than this are considered corrupt, rather than allocated.
+const MAX_RECORD_LEN: usize = 64 << 20;
+
+/// The first record of an archive, describing what it contains.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ArchiveHeader {
+    pub version: u32,
+    pub network: NetID,
+    pub from: u64,
+    pub to: u64,
+}
+
+/// Writes the blocks at heights `from..=to`, together with their consensus proofs, into a chain archive.
+///
+/// An archive is the magic bytes followed by length-prefixed, checksummed records: first an [ArchiveHeader], then one `(Block, ConsensusProof)` per height.
+pub fn export_chain(
+    storage: &NodeStorage,
+    from: u64,
+    to: u64,
+    mut out: impl Write,
+) -> anyhow::Result<ArchiveHeader> {
+    let highest = storage.highest_height();
+    if from == 0 || from > to || to > highest {
+        anyhow::bail!(
+            "cannot export heights {}..={} from a chain with heights 1..={}",
+            from,
+            to,
+            highest
+        )
+    }
+    let header = ArchiveHeader {
+        version: VERSION,
+        network: storage.highest_state().inner_ref().network,
+        from,
+        to,
+    };
+    out.write_all(MAGIC)?;
+    write_record(&mut out, &stdcode::serialize(&header)?)?;
+    for height in from..=to {
+        let block = storage
+            .get_state(height)
+            .with_context(|| format!("block {} missing or pruned", height))?
+            .to_block();
+        let cproof = storage
+            .get_consensus(height)
+            .with_context(|| format!("consensus proof for block {} missing", height))?;
+        write_record(&mut out, &stdcode::serialize(&(block, cproof))?)?;
+        if height % 1000 == 0 {
+            log::info!("exported block {}", height);
+        }
+    }
+    out.flush()?;
+    Ok(header)
+}
+
+/// Reads a chain archive, applying every block through [NodeStorage::apply_block] with full validation. Blocks already in the database are skipped, as long as they match. Returns the archive header and the number of blocks applied.
+pub fn import_chain(
+    storage: &mut NodeStorage,
+    mut input: impl Read,
+) -> anyhow::Result<(ArchiveHeader, usize)> {
+    let mut magic = [0u8; 8];
+    input
+        .read_exact(&mut magic)
+        .context("cannot read archive magic")?;
+    if &magic != MAGIC {
+        anyhow::bail!("not a chain archive")
+    }
+    let header: ArchiveHeader =
+        stdcode::deserialize(&read_record(&mut input)?).context("cannot decode archive header")?;
+    if header.version != VERSION {
+        anyhow::bail!("unsupported archive version {}", header.version)
+    }
+    let network = storage.highest_state().inner_ref().network;
+    if header.network != network {
+        anyhow::bail!(
+            "archive is for network {:?}, but the database is for {:?}",
+            header.network,
+            network
+        )
+    }
+    let mut applied = 0;
+    for height in header.from..=header.to {
+        let (block, cproof): (Block, ConsensusProof) =
+            stdcode::deserialize(&read_record(&mut input)?)
+                .with_context(|| format!("cannot decode block {} in archive", height))?;
+        if block.header.height != height {
+            anyhow::bail!(
+                "archive has block {} where block {} should be",
+                block.header.height,
+                height
+            )
+        }
+        if height <= storage.highest_height() {
+            let existing = storage
+                .get_header(height)
+                .with_context(|| format!("block {} missing", height))?;
+            if existing != block.header {
+                anyhow::bail!("block {} in archive conflicts with the database", height)
+            }
+            continue;
+        }
+        storage
+            .apply_block(block, cproof)
+            .with_context(|| format!("cannot apply block {} from archive", height))?;
+        applied += 1;
+        if height % 1000 == 0 {
+            log::info!("imported block {}", height);
+        }
+    }
+    Ok((header, applied))
+}
+
+// Record format: u32 payload length || 32-byte hash of the payload || payload.
+
+fn write_record(out: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    out.write_all(&(payload.len() as u32).to_be_bytes())?;
+    out.write_all(&tmelcrypt::hash_single(payload).0)?;
+    out.write_all(payload)
+}
+
+fn read_record(input: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    let mut header = [0u8; 36];
+    input
+        .read_exact(&mut header)
+        .context("archive is truncated")?;
+    let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    if length > MAX_RECORD_LEN {
+        anyhow::bail!("archive record of length {} is too large", length)
+    }
+    let mut payload = vec![0u8; length];
+    input
+        .read_exact(&mut payload)
+        .context("archive is truncated")?;
+    if tmelcrypt::hash_single(&payload).0[..] != header[4..] {
+        anyhow::bail!("archive record fails its checksum")
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        tests::{sign, staker_genesis, temp_storage},
+        ConsensusError,
+    };
+
+    /// Writes an archive of the given blocks, starting at height 1.
+    fn archive(network: NetID, blocks: &[(Block, ConsensusProof)]) -> Vec<u8> {
+        let header = ArchiveHeader {
+            version: VERSION,
+            network,
+            from: 1,
+            to: blocks.len() as u64,
+        };
+        let mut out = MAGIC.to_vec();
+        write_record(&mut out, &stdcode::serialize(&header).unwrap()).unwrap();
+        for block in blocks {
+            write_record(&mut out, &stdcode::serialize(block).unwrap()).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn rejects_forged_proofs() {
+        let staker = tmelcrypt::ed25519_keygen();
+        let forger = tmelcrypt::ed25519_keygen();
+        let genesis = staker_genesis(staker.0);
+        let mut source = temp_storage(&genesis);
+        let mut blocks = Vec::new();
+        for _ in 0..3 {
+            let block = source.highest_state().next_state().seal(None).to_block();
+            let cproof = sign(&block, staker);
+            source.apply_block(block.clone(), cproof.clone()).unwrap();
+            blocks.push((block, cproof));
+        }
+
+        let mut exported = Vec::new();
+        export_chain(&source, 1, 3, &mut exported).unwrap();
+        let mut target = temp_storage(&genesis);
+        let (_, applied) = import_chain(&mut target, &exported[..]).unwrap();
+        assert_eq!(applied, 3);
+
+        // signed by someone without stake
+        let mut forged = blocks.clone();
+        forged[1].1 = sign(&forged[1].0, forger);
+        let mut target = temp_storage(&genesis);
+        let err = import_chain(&mut target, &archive(genesis.network, &forged)[..]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ConsensusError::Invalid { height: 2, .. })
+        ));
+        assert_eq!(target.highest_height(), 1);
+
+        // a signature over a different block
+        let mut forged = blocks;
+        forged[2].1 = forged[1].1.clone();
+        let mut target = temp_storage(&genesis);
+        assert!(import_chain(&mut target, &archive(genesis.network, &forged)[..]).is_err());
+        assert_eq!(target.highest_height(), 2);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::TryInto,
+};
+
+use themelio_stf::{melvm::Address, CoinDataHeight, CoinID, SealedState, TxHash};
+use tmelcrypt::HashVal;
+
+use super::{clear_index, indexed_height, set_indexed_height, txn_remove};
+
+const COIN_PREFIX: u8 = b'u';
+const HISTORY_PREFIX: u8 = b't';
+
+/// An index of coins and transactions by address, for wallets that would otherwise have to scan every block.
+///
+/// Blocks are indexed in order, as they're applied, each in one transaction. Coins that were created before the lowest indexed block, e.g. because the node was state synced to a checkpoint, are never indexed.
+pub struct AddressIndex {
+    dict: boringdb::Dict,
+}
+
+impl AddressIndex {
+    /// Opens the index stored in the given dictionary.
+    pub(super) fn new(dict: boringdb::Dict) -> Self {
+        Self { dict }
+    }
+
+    /// Returns the height of the highest indexed block, or `None` if nothing is indexed yet.
+    pub fn indexed_height(&self) -> Option<u64> {
+        indexed_height(&self.dict)
+    }
+
+    /// Indexes the block that produced the given state. `previous` is the state it was applied to, or `None` if it's the lowest indexed block.
+    pub(super) fn index_block(&self, previous: Option<&SealedState>, state: &SealedState) {
+        let changes = BlockChanges::new(previous, state);
+        let mut txn = self.dict.transaction().unwrap();
+        for key in changes.history {
+            txn.insert(key, Vec::new()).unwrap();
+        }
+        for (key, _) in changes.spent {
+            txn_remove(&mut txn, &key);
+        }
+        for (key, cdh) in changes.created {
+            txn.insert(key, stdcode::serialize(&cdh).unwrap()).unwrap();
+        }
+        set_indexed_height(&mut txn, Some(state.inner_ref().height));
+    }
+
+    /// Takes the highest indexed block, which produced the given state, back out of the index. `previous` must be what was given to [AddressIndex::index_block] for it.
+    pub(super) fn unindex_block(&self, previous: Option<&SealedState>, state: &SealedState) {
+        let changes = BlockChanges::new(previous, state);
+        let mut txn = self.dict.transaction().unwrap();
+        for key in changes.history {
+            txn_remove(&mut txn, &key);
+        }
+        for (key, _) in changes.created {
+            txn_remove(&mut txn, &key);
+        }
+        for (key, cdh) in changes.spent {
+            txn.insert(key, stdcode::serialize(&cdh).unwrap()).unwrap();
+        }
+        set_indexed_height(
+            &mut txn,
+            previous.map(|previous| previous.inner_ref().height),
+        );
+    }
+
+    /// Deletes everything in the index.
+    pub(super) fn clear(&self) {
+        clear_index(&self.dict)
+    }
+
+    /// Returns the unspent coins belonging to an address.
+    pub fn unspent_coins(&self, covhash: Address) -> Vec<(CoinID, CoinDataHeight)> {
+        self.scan(&prefix_key(COIN_PREFIX, covhash))
+            .map(|(key, value)| {
+                let coinid = CoinID {
+                    txhash: TxHash(HashVal(key[33..65].try_into().unwrap())),
+                    index: key[65],
+                };
+                (coinid, stdcode::deserialize(&value).expect("corrupt index"))
+            })
+            .collect()
+    }
+
+    /// Returns the hashes of the transactions that spent from or paid to an address, along with the heights of their blocks, in order of height.
+    pub fn transactions(&self, covhash: Address) -> Vec<(u64, TxHash)> {
+        self.scan(&prefix_key(HISTORY_PREFIX, covhash))
+            .map(|(key, _)| {
+                let height = u64::from_be_bytes(key[33..41].try_into().unwrap());
+                (height, TxHash(HashVal(key[41..73].try_into().unwrap())))
+            })
+            .collect()
+    }
+
+    /// Iterates through the keys and values of every entry whose key starts with the given prefix.
+    fn scan(&self, prefix: &[u8]) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        let prefix = prefix.to_vec();
+        let start = prefix.clone();
+        self.dict
+            .range(start..)
+            .unwrap()
+            .map(|kv| kv.unwrap())
+            .take_while(move |(key, _)| key.starts_with(&prefix))
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+    }
+}
+
+/// The entries that indexing a block adds to and removes from the index.
+struct BlockChanges {
+    /// History entries for every address a transaction in the block touched.
+    history: Vec<Vec<u8>>,
+    /// Unspent coin entries for the indexed coins that the block spent.
+    spent: Vec<(Vec<u8>, CoinDataHeight)>,
+    /// Unspent coin entries for the coins that the block created, and didn't spend itself.
+    created: Vec<(Vec<u8>, CoinDataHeight)>,
+}
+
+impl BlockChanges {
+    fn new(previous: Option<&SealedState>, state: &SealedState) -> Self {
+        let height = state.inner_ref().height;
+        let block = state.to_block();
+        // coins created in this block, so that spending them within the block can be traced
+        let created = block
+            .transactions
+            .iter()
+            .flat_map(|tx| {
+                tx.outputs
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, output)| (tx.output_coinid(i as u8), output.covhash))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut history = Vec::new();
+        let mut spent = Vec::new();
+        for tx in block.transactions.iter() {
+            let txhash = tx.hash_nosigs();
+            let mut touched = tx
+                .outputs
+                .iter()
+                .map(|output| output.covhash)
+                .collect::<BTreeSet<_>>();
+            for input in tx.inputs.iter() {
+                if let Some(owner) = created.get(input) {
+                    touched.insert(*owner);
+                } else if let Some(cdh) = previous.and_then(|p| p.inner_ref().coins.get(input).0) {
+                    touched.insert(cdh.coin_data.covhash);
+                    spent.push((coin_key(cdh.coin_data.covhash, *input), cdh));
+                }
+            }
+            history.extend(
+                touched
+                    .into_iter()
+                    .map(|covhash| history_key(covhash, height, txhash)),
+            );
+        }
+        // the state has the final word on which coins the block created, since e.g. melswap rewrites the outputs of some transactions
+        let candidates = created
+            .keys()
+            .copied()
+            .chain(std::iter::once(CoinID::proposer_reward(height)))
+            .chain(previous.is_none().then(CoinID::zero_zero));
+        let created = candidates
+            .filter_map(|coinid| {
+                let cdh = state.inner_ref().coins.get(&coinid).0?;
+                Some((coin_key(cdh.coin_data.covhash, coinid), cdh))
+            })
+            .collect();
+        Self {
+            history,
+            spent,
+            created,
+        }
+    }
+}
+
+fn prefix_key(prefix: u8, covhash: Address) -> Vec<u8> {
+    let mut key = vec![prefix];
+    key.extend_from_slice(&covhash.0 .0);
+    key
+}
+
+/// Unspent coins are keyed by address, then coin ID.
+fn coin_key(covhash: Address, coinid: CoinID) -> Vec<u8> {
+    let mut key = prefix_key(COIN_PREFIX, covhash);
+    key.extend_from_slice(&coinid.txhash.0 .0);
+    key.push(coinid.index);
+    key
+}
+
+/// Transaction history is keyed by address, then height, then transaction hash, so that it comes out in order of height.
+fn history_key(covhash: Address, height: u64, txhash: TxHash) -> Vec<u8> {
+    let mut key = prefix_key(HISTORY_PREFIX, covhash);
+    key.extend_from_slice(&height.to_be_bytes());
+    key.extend_from_slice(&txhash.0 .0);
+    key
+}
@@ -1,14 +1,79 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 use lru::LruCache;
-use themelio_stf::{State, StateError, Transaction, TxHash};
-use tmelcrypt::HashVal;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use themelio_stf::{
+    CoinDataHeight, CoinID, SmtMapping, StakeDoc, State, StateError, Transaction, TxHash, TxKind,
+};
+use thiserror::Error;
+
+/// The default bound on the total size of the pending transactions in the mempool.
+pub const DEFAULT_MEMPOOL_BYTES: usize = 100 << 20;
+
+/// Fee-per-weight is computed in units of 2^-16 micromel per weight unit, so that cheap transactions aren't all rounded to zero.
+const FEE_PER_WEIGHT_SCALE: u32 = 16;
+
+/// Error returned when a transaction cannot be added to the mempool.
+#[derive(Error, Debug)]
+pub enum MempoolError {
+    #[error("invalid transaction: {0}")]
+    Invalid(#[from] StateError),
+    #[error("mempool is full, and the transaction pays too little to evict anything")]
+    FeeTooLow,
+}
+
+/// A serializable snapshot of a mempool's contents, used to persist the mempool across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MempoolSnapshot {
+    /// The pending transactions, in the order they arrived.
+    pub pending: Vec<Transaction>,
+    /// The recently seen transactions, least recently used first.
+    pub seen: Vec<Transaction>,
+}
+
+/// A pending transaction, with its precomputed priority.
+struct PendingTx {
+    tx: Transaction,
+    weight: u128,
+    size: usize,
+    priority: Priority,
+    /// What applying the transaction changed in the provisional state.
+    undo: Undo,
+    /// The pending transactions whose outputs this one spends.
+    parents: Vec<TxHash>,
+    /// The pending transactions that spend this one's outputs.
+    children: HashSet<TxHash>,
+}
+
+/// Where a transaction sorts in the mempool: lowest fee-per-weight first, and among equals, the most recent first. The lowest priority is evicted first.
+type Priority = (u128, u64, TxHash);
+
+/// What applying a transaction changed in a state, so that the transaction can be taken out of the state again without replaying everything applied after it.
+#[derive(Debug, Clone, Default)]
+struct Undo {
+    /// Every coin the transaction may have changed, with what it was before.
+    coins: Vec<(CoinID, Option<CoinDataHeight>)>,
+    /// The transaction's entry in the transactions mapping, as it was before.
+    transaction: Option<Transaction>,
+    /// The transaction's entry in the stakes mapping, as it was before.
+    stake: Option<StakeDoc>,
+    /// How much the transaction added to the fee pool.
+    fee_pool: u128,
+    /// How much the transaction added to the tips.
+    tips: u128,
+}
 
 /// Mempool encapsulates a "mempool" --- a provisional state that is used to form new blocks by stakers, or provisionally validate transactions by auditors.
+///
+/// Besides the provisional state, the mempool keeps every pending transaction, indexed by fee-per-weight. When the pending transactions exceed the memory bound, the lowest-paying ones are evicted, along with the transactions that spend their outputs, and taken out of the provisional state one by one. After a rebase, the pending transactions that are still valid are re-applied onto the new state.
 pub struct Mempool {
     provisional_state: State,
     last_rebase: State,
-    txx_in_state: HashSet<TxHash>,
+    pending: HashMap<TxHash, PendingTx>,
+    by_priority: BTreeSet<Priority>,
+    pending_bytes: usize,
+    max_bytes: usize,
+    next_seq: u64,
     seen: LruCache<TxHash, Transaction>, // TODO: caches if benchmarks prove them helpful
 }
 
@@ -18,29 +83,94 @@ impl Mempool {
         Self {
             provisional_state: state.clone(),
             last_rebase: state,
-            txx_in_state: Default::default(),
+            pending: Default::default(),
+            by_priority: Default::default(),
+            pending_bytes: 0,
+            max_bytes: DEFAULT_MEMPOOL_BYTES,
+            next_seq: 0,
             seen: LruCache::new(100000),
         }
     }
+
+    /// Sets the bound on the total size of pending transactions, evicting the lowest-paying transactions if needed.
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        self.evict();
+    }
+
     /// Creates a State based on the present state of the mempool.
     pub fn to_state(&self) -> State {
         self.provisional_state.clone()
     }
 
+    /// Creates a State containing the highest-paying set of pending transactions whose total weight is within the given limit.
+    pub fn build_state(&self, max_weight: u128) -> State {
+        self.fill_state(self.last_rebase.clone(), max_weight).0
+    }
+
+    /// Returns the number of pending transactions.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns whether there are no pending transactions.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
     /// Tries to add a transaction to the mempool.
-    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), StateError> {
-        // if self.seen.put(tx.hash_nosigs(), tx.clone()).is_some() {
-        //     return Err(StateError::DuplicateTx);
-        // }
-        if !self.txx_in_state.insert(tx.hash_nosigs()) {
-            return Err(StateError::DuplicateTx);
-        }
-        self.provisional_state.apply_tx(tx)?;
-        self.seen.put(tx.hash_nosigs(), tx.clone());
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), MempoolError> {
+        let txhash = tx.hash_nosigs();
+        if self.pending.contains_key(&txhash) {
+            return Err(StateError::DuplicateTx.into());
+        }
+        let weight = tx.weight();
+        let size = stdcode::serialize(tx).unwrap().len();
+        let priority = (
+            tx.fee.saturating_mul(1 << FEE_PER_WEIGHT_SCALE) / weight.max(1),
+            u64::MAX - self.next_seq,
+            txhash,
+        );
+        // don't bother validating transactions that would be evicted right away
+        if self.pending_bytes + size > self.max_bytes {
+            match self.by_priority.iter().next() {
+                Some(lowest) if lowest.0 < priority.0 => {}
+                _ => return Err(MempoolError::FeeTooLow),
+            }
+        }
+        let undo = apply_undoable(&mut self.provisional_state, tx)?;
+        let parents = self.pending_parents(tx);
+        for parent in parents.iter() {
+            self.pending
+                .get_mut(parent)
+                .unwrap()
+                .children
+                .insert(txhash);
+        }
+        self.next_seq += 1;
+        self.seen.put(txhash, tx.clone());
+        self.pending_bytes += size;
+        self.by_priority.insert(priority);
+        self.pending.insert(
+            txhash,
+            PendingTx {
+                tx: tx.clone(),
+                weight,
+                size,
+                priority,
+                undo,
+                parents,
+                children: HashSet::new(),
+            },
+        );
+        self.evict();
+        if !self.pending.contains_key(&txhash) {
+            return Err(MempoolError::FeeTooLow);
+        }
         Ok(())
     }
 
-    /// Forcibly replaces the internal state of the mempool with the given state.
+    /// Forcibly replaces the internal state of the mempool with the given state, re-applying the pending transactions that are still valid.
     pub fn rebase(&mut self, state: State) {
         if state.height > self.provisional_state.height {
             log::trace!(
@@ -48,14 +178,9 @@ impl Mempool {
                 self.provisional_state.height,
                 state.height
             );
-            if self.provisional_state.transactions.root_hash() != HashVal::default() {
-                let count = self.provisional_state.transactions.val_iter().count();
-                log::warn!("*** THROWING AWAY {} MEMPOOL TXX ***", count);
-            }
             assert!(state.transactions.is_empty());
-            self.provisional_state = state.clone();
             self.last_rebase = state;
-            self.txx_in_state.clear();
+            self.rebuild();
         }
     }
 
@@ -65,4 +190,562 @@ impl Mempool {
             .cloned()
             .or_else(|| self.provisional_state.transactions.get(&hash).0)
     }
+
+    /// Takes a snapshot of the pending and recently seen transactions.
+    pub fn snapshot(&self) -> MempoolSnapshot {
+        let mut pending = self.pending.values().collect::<Vec<_>>();
+        pending.sort_unstable_by_key(|pending| std::cmp::Reverse(pending.priority.1));
+        MempoolSnapshot {
+            pending: pending
+                .into_iter()
+                .map(|pending| pending.tx.clone())
+                .collect(),
+            seen: self.seen.iter().rev().map(|(_, tx)| tx.clone()).collect(),
+        }
+    }
+
+    /// Restores a snapshot, re-validating every pending transaction against the current state. Returns how many pending transactions were restored.
+    pub fn restore(&mut self, snapshot: MempoolSnapshot) -> usize {
+        for tx in snapshot.seen {
+            self.seen.put(tx.hash_nosigs(), tx);
+        }
+        snapshot
+            .pending
+            .iter()
+            .filter(|tx| self.apply_transaction(tx).is_ok())
+            .count()
+    }
+
+    /// Evicts the lowest-paying transactions, and whatever spends their outputs, until the pending transactions fit within the memory bound.
+    fn evict(&mut self) {
+        let mut evicted = 0;
+        while self.pending_bytes > self.max_bytes {
+            let lowest = *self.by_priority.iter().next().unwrap();
+            evicted += self.remove_pending(lowest.2);
+        }
+        if evicted > 0 {
+            log::debug!("evicted {} low-fee mempool txx", evicted);
+        }
+    }
+
+    /// Re-applies the pending transactions onto the last rebase, dropping those that no longer apply.
+    fn rebuild(&mut self) {
+        let (state, applied) = self.fill_state(self.last_rebase.clone(), u128::MAX);
+        let mut old = std::mem::take(&mut self.pending);
+        if old.len() > applied.len() {
+            log::debug!(
+                "dropping {} mempool txx that are no longer valid",
+                old.len() - applied.len()
+            );
+        }
+        self.by_priority.clear();
+        self.pending_bytes = 0;
+        for (txhash, undo) in applied {
+            let mut pending = old.remove(&txhash).unwrap();
+            pending.undo = undo;
+            pending.children.clear();
+            self.by_priority.insert(pending.priority);
+            self.pending_bytes += pending.size;
+            self.pending.insert(txhash, pending);
+        }
+        // some parents may have been confirmed, or dropped along with their children
+        let links = self
+            .pending
+            .values()
+            .map(|pending| (pending.priority.2, self.pending_parents(&pending.tx)))
+            .collect::<Vec<_>>();
+        for (txhash, parents) in links {
+            for parent in parents.iter() {
+                self.pending
+                    .get_mut(parent)
+                    .unwrap()
+                    .children
+                    .insert(txhash);
+            }
+            self.pending.get_mut(&txhash).unwrap().parents = parents;
+        }
+        self.provisional_state = state;
+    }
+
+    /// Applies pending transactions onto the given state, highest fee-per-weight first, except that a transaction spending the outputs of another pending transaction waits until that one is applied. Transactions that fail or would exceed the weight limit are skipped, along with whatever spends their outputs. Returns the state and the transactions that were applied, in order, with what applying each one changed.
+    fn fill_state(&self, mut state: State, max_weight: u128) -> (State, Vec<(TxHash, Undo)>) {
+        let mut applied = Vec::new();
+        let mut applied_set = HashSet::new();
+        // transactions that failed or didn't fit, along with whatever spends their outputs
+        let mut dead = HashSet::new();
+        // transactions waiting for pending parents, and how many parents each one still waits for
+        let mut waiting: HashMap<TxHash, Vec<TxHash>> = HashMap::new();
+        let mut missing: HashMap<TxHash, usize> = HashMap::new();
+        let mut total_weight: u128 = 0;
+        for priority in self.by_priority.iter().rev() {
+            let txhash = priority.2;
+            let pending = &self.pending[&txhash];
+            // outputs of pending transactions that aren't applied yet, e.g. because they pay less
+            let unapplied = pending
+                .tx
+                .inputs
+                .iter()
+                .filter(|input| {
+                    input.txhash != txhash
+                        && self.pending.contains_key(&input.txhash)
+                        && !applied_set.contains(&input.txhash)
+                        && state.coins.get(input).0.is_none()
+                })
+                .map(|input| input.txhash)
+                .collect::<BTreeSet<_>>();
+            if unapplied.iter().any(|parent| dead.contains(parent)) {
+                dead.insert(txhash);
+                continue;
+            }
+            if !unapplied.is_empty() {
+                missing.insert(txhash, unapplied.len());
+                for parent in unapplied {
+                    waiting.entry(parent).or_default().push(txhash);
+                }
+                continue;
+            }
+            let mut ready = vec![txhash];
+            while let Some(txhash) = ready.pop() {
+                let pending = &self.pending[&txhash];
+                if total_weight.saturating_add(pending.weight) > max_weight {
+                    dead.insert(txhash);
+                    continue;
+                }
+                match apply_undoable(&mut state, &pending.tx) {
+                    Ok(undo) => {
+                        total_weight += pending.weight;
+                        applied.push((txhash, undo));
+                        applied_set.insert(txhash);
+                        for child in waiting.remove(&txhash).unwrap_or_default() {
+                            let count = missing.get_mut(&child).unwrap();
+                            *count -= 1;
+                            if *count == 0 {
+                                ready.push(child);
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        dead.insert(txhash);
+                    }
+                }
+            }
+        }
+        (state, applied)
+    }
+
+    /// Returns the pending transactions whose outputs the given transaction spends.
+    fn pending_parents(&self, tx: &Transaction) -> Vec<TxHash> {
+        let txhash = tx.hash_nosigs();
+        tx.inputs
+            .iter()
+            .map(|input| input.txhash)
+            .filter(|parent| *parent != txhash && self.pending.contains_key(parent))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Removes a pending transaction, along with every pending transaction that spends its outputs, directly or not, taking them out of the provisional state. Returns how many transactions were removed.
+    fn remove_pending(&mut self, txhash: TxHash) -> usize {
+        // descendants are taken out first, since they spent the outputs of their ancestors
+        let mut order = Vec::new();
+        let mut stack = vec![(txhash, false)];
+        let mut seen = HashSet::new();
+        while let Some((top, expanded)) = stack.pop() {
+            if expanded {
+                order.push(top);
+                continue;
+            }
+            if !self.pending.contains_key(&top) || !seen.insert(top) {
+                continue;
+            }
+            stack.push((top, true));
+            stack.extend(
+                self.pending[&top]
+                    .children
+                    .iter()
+                    .map(|child| (*child, false)),
+            );
+        }
+        for txhash in order.iter() {
+            let pending = self.pending.remove(txhash).unwrap();
+            self.by_priority.remove(&pending.priority);
+            self.pending_bytes -= pending.size;
+            for parent in pending.parents.iter() {
+                if let Some(parent) = self.pending.get_mut(parent) {
+                    parent.children.remove(txhash);
+                }
+            }
+            unapply(&mut self.provisional_state, &pending.tx, &pending.undo);
+        }
+        order.len()
+    }
+}
+
+/// Applies a transaction to a state, recording what every entry it may touch was before, so that it can be [unapply]-ed.
+fn apply_undoable(state: &mut State, tx: &Transaction) -> Result<Undo, StateError> {
+    let txhash = tx.hash_nosigs();
+    let outputs = (0..tx.outputs.len()).map(|index| tx.output_coinid(index as u8));
+    // the marker that themelio-stf uses to stop faucet transactions from being applied twice
+    let faucet_marker = (tx.kind == TxKind::Faucet).then(|| CoinID {
+        txhash: tmelcrypt::hash_keyed(b"fdp", txhash.0).into(),
+        index: 0,
+    });
+    let coins = tx
+        .inputs
+        .iter()
+        .copied()
+        .chain(outputs)
+        .chain(faucet_marker)
+        .map(|coinid| (coinid, state.coins.get(&coinid).0))
+        .collect();
+    let transaction = state.transactions.get(&txhash).0;
+    let stake = state.stakes.get(&txhash).0;
+    let (fee_pool, tips) = (state.fee_pool, state.tips);
+    state.apply_tx(tx)?;
+    Ok(Undo {
+        coins,
+        transaction,
+        stake,
+        fee_pool: state.fee_pool - fee_pool,
+        tips: state.tips - tips,
+    })
+}
+
+/// Takes a transaction applied with [apply_undoable] back out of a state, putting back everything it may have touched. Anything applied after it must not depend on it.
+///
+/// The DOSC speed isn't restored, since it's a running maximum. It only matters when sealing, and blocks are built from the last rebase rather than from the provisional state.
+fn unapply(state: &mut State, tx: &Transaction, undo: &Undo) {
+    let txhash = tx.hash_nosigs();
+    for (coinid, before) in undo.coins.iter() {
+        restore(&mut state.coins, coinid, before);
+    }
+    restore(&mut state.transactions, &txhash, &undo.transaction);
+    restore(&mut state.stakes, &txhash, &undo.stake);
+    state.fee_pool -= undo.fee_pool;
+    state.tips -= undo.tips;
+}
+
+/// Puts an entry of a mapping back to what it was.
+fn restore<K: Serialize + Clone, V: Serialize + DeserializeOwned + Clone>(
+    mapping: &mut SmtMapping<K, V>,
+    key: &K,
+    before: &Option<V>,
+) {
+    match before {
+        Some(before) => mapping.insert(key.clone(), before.clone()),
+        None => mapping.delete(key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use themelio_stf::{melvm::Covenant, CoinData, Denom, GenesisConfig};
+
+    use super::*;
+
+    const COIN_VALUE: u128 = 1_000_000_000;
+
+    fn tx(inputs: Vec<CoinID>, values: Vec<u128>, fee: u128) -> Transaction {
+        Transaction {
+            kind: TxKind::Normal,
+            inputs,
+            outputs: values
+                .into_iter()
+                .map(|value| CoinData {
+                    covhash: Covenant::always_true().hash(),
+                    value,
+                    denom: Denom::Mel,
+                    additional_data: vec![],
+                })
+                .collect(),
+            fee,
+            scripts: vec![Covenant::always_true()],
+            data: vec![],
+            sigs: vec![],
+        }
+    }
+
+    /// Spends a coin worth `value`, paying the given fee.
+    fn spend(coinid: CoinID, value: u128, fee: u128) -> Transaction {
+        tx(vec![coinid], vec![value - fee], fee)
+    }
+
+    /// A state with the given number of coins worth [COIN_VALUE], which anyone can spend.
+    fn setup(count: usize) -> (State, Vec<CoinID>) {
+        let forest = novasmt::Forest::new(novasmt::InMemoryBackend::default());
+        let fee = 100_000_000;
+        let mut genesis = GenesisConfig::std_testnet();
+        genesis.init_coindata = CoinData {
+            covhash: Covenant::always_true().hash(),
+            value: COIN_VALUE * count as u128 + fee,
+            denom: Denom::Mel,
+            additional_data: vec![],
+        };
+        let mut state = State::genesis(&forest, genesis);
+        let split = tx(vec![CoinID::zero_zero()], vec![COIN_VALUE; count], fee);
+        state.apply_tx(&split).unwrap();
+        let coins = (0..count).map(|i| split.output_coinid(i as u8)).collect();
+        (state.seal(None).next_state(), coins)
+    }
+
+    fn size(tx: &Transaction) -> usize {
+        stdcode::serialize(tx).unwrap().len()
+    }
+
+    /// Checks that taking transactions out of the provisional state left it exactly as if only the remaining ones had been applied.
+    fn assert_consistent(mempool: &Mempool) {
+        let provisional = mempool.to_state();
+        let rebuilt = mempool.build_state(u128::MAX);
+        assert_eq!(provisional.coins.root_hash(), rebuilt.coins.root_hash());
+        assert_eq!(
+            provisional.transactions.root_hash(),
+            rebuilt.transactions.root_hash()
+        );
+        assert_eq!(provisional.stakes.root_hash(), rebuilt.stakes.root_hash());
+        assert_eq!(provisional.fee_pool, rebuilt.fee_pool);
+        assert_eq!(provisional.tips, rebuilt.tips);
+    }
+
+    fn contains(state: &State, tx: &Transaction) -> bool {
+        state.transactions.get(&tx.hash_nosigs()).0.is_some()
+    }
+
+    #[test]
+    fn priority_order() {
+        let (state, coins) = setup(3);
+        let mut mempool = Mempool::new(state);
+        let low = spend(coins[0], COIN_VALUE, 10_000_000);
+        let high = spend(coins[1], COIN_VALUE, 30_000_000);
+        let mid = spend(coins[2], COIN_VALUE, 20_000_000);
+        for tx in [&low, &high, &mid] {
+            mempool.apply_transaction(tx).unwrap();
+        }
+        let built = mempool.build_state(high.weight() + mid.weight());
+        assert!(contains(&built, &high));
+        assert!(contains(&built, &mid));
+        assert!(!contains(&built, &low));
+
+        // a transaction that pays well for spending a low-paying one still gets in, right after it
+        let child = spend(low.output_coinid(0), COIN_VALUE - low.fee, 90_000_000);
+        mempool.apply_transaction(&child).unwrap();
+        let built = mempool.build_state(u128::MAX);
+        assert!(contains(&built, &low));
+        assert!(contains(&built, &child));
+        assert_consistent(&mempool);
+    }
+
+    #[test]
+    fn eviction() {
+        let (state, coins) = setup(5);
+        let mut mempool = Mempool::new(state);
+        let txx = [20_000_000, 30_000_000, 40_000_000]
+            .iter()
+            .zip(coins.iter())
+            .map(|(fee, coinid)| spend(*coinid, COIN_VALUE, *fee))
+            .collect::<Vec<_>>();
+        mempool.set_max_bytes(txx.iter().map(size).sum());
+        for tx in txx.iter() {
+            mempool.apply_transaction(tx).unwrap();
+        }
+
+        // paying less than everything else isn't enough to get in
+        let cheap = spend(coins[3], COIN_VALUE, 10_000_000);
+        assert!(matches!(
+            mempool.apply_transaction(&cheap),
+            Err(MempoolError::FeeTooLow)
+        ));
+        assert!(!contains(&mempool.to_state(), &cheap));
+
+        // paying more evicts the lowest-paying transaction, whose coin can then be spent again
+        let generous = spend(coins[4], COIN_VALUE, 50_000_000);
+        mempool.apply_transaction(&generous).unwrap();
+        assert_eq!(mempool.len(), 3);
+        let provisional = mempool.to_state();
+        assert!(!contains(&provisional, &txx[0]));
+        assert!(provisional.coins.get(&coins[0]).0.is_some());
+        assert!(provisional.coins.get(&txx[0].output_coinid(0)).0.is_none());
+        assert_consistent(&mempool);
+    }
+
+    #[test]
+    fn eviction_takes_dependents() {
+        let (state, coins) = setup(2);
+        let mut mempool = Mempool::new(state);
+        let parent = spend(coins[0], COIN_VALUE, 20_000_000);
+        let child = spend(parent.output_coinid(0), COIN_VALUE - parent.fee, 40_000_000);
+        let other = spend(coins[1], COIN_VALUE, 30_000_000);
+        mempool.set_max_bytes(size(&parent) + size(&child));
+        mempool.apply_transaction(&parent).unwrap();
+        mempool.apply_transaction(&child).unwrap();
+
+        // evicting the parent leaves the child spending a coin that doesn't exist, so it goes too
+        mempool.apply_transaction(&other).unwrap();
+        assert_eq!(mempool.len(), 1);
+        let provisional = mempool.to_state();
+        assert!(contains(&provisional, &other));
+        assert!(!contains(&provisional, &parent));
+        assert!(!contains(&provisional, &child));
+        assert!(provisional.coins.get(&coins[0]).0.is_some());
+        assert_consistent(&mempool);
+    }
+
+    #[test]
+    fn eviction_undoes_faucet() {
+        let (state, coins) = setup(1);
+        let mut mempool = Mempool::new(state);
+        let faucet = Transaction {
+            kind: TxKind::Faucet,
+            ..tx(vec![], vec![COIN_VALUE], 20_000_000)
+        };
+        let other = spend(coins[0], COIN_VALUE, 30_000_000);
+        mempool.set_max_bytes(size(&other));
+        mempool.apply_transaction(&faucet).unwrap();
+
+        // the faucet's marker goes along with its outputs, so it could be applied again
+        mempool.apply_transaction(&other).unwrap();
+        assert_eq!(mempool.len(), 1);
+        let provisional = mempool.to_state();
+        assert!(!contains(&provisional, &faucet));
+        assert!(provisional.coins.get(&faucet.output_coinid(0)).0.is_none());
+        let marker = CoinID {
+            txhash: tmelcrypt::hash_keyed(b"fdp", faucet.hash_nosigs().0).into(),
+            index: 0,
+        };
+        assert!(provisional.coins.get(&marker).0.is_none());
+        assert_consistent(&mempool);
+    }
+
+    #[test]
+    fn eviction_undoes_stake() {
+        let (state, coins) = setup(2);
+        let mut mempool = Mempool::new(state);
+        let syms = 1_000_000;
+        let mut faucet = Transaction {
+            kind: TxKind::Faucet,
+            ..tx(vec![], vec![syms], 20_000_000)
+        };
+        faucet.outputs[0].denom = Denom::Sym;
+        let doc = StakeDoc {
+            pubkey: tmelcrypt::ed25519_keygen().0,
+            e_start: 0,
+            e_post_end: 1,
+            syms_staked: syms,
+        };
+        let mut stake = Transaction {
+            kind: TxKind::Stake,
+            data: stdcode::serialize(&doc).unwrap(),
+            ..tx(
+                vec![faucet.output_coinid(0), coins[0]],
+                vec![syms, COIN_VALUE - 40_000_000],
+                40_000_000,
+            )
+        };
+        stake.outputs[0].denom = Denom::Sym;
+        let other = spend(coins[1], COIN_VALUE, 30_000_000);
+        mempool.set_max_bytes(size(&faucet) + size(&stake));
+        mempool.apply_transaction(&faucet).unwrap();
+        mempool.apply_transaction(&stake).unwrap();
+        assert!(mempool
+            .to_state()
+            .stakes
+            .get(&stake.hash_nosigs())
+            .0
+            .is_some());
+
+        // evicting the faucet takes the stake spending its output, and the stake itself goes with it
+        mempool.apply_transaction(&other).unwrap();
+        assert_eq!(mempool.len(), 1);
+        let provisional = mempool.to_state();
+        assert!(!contains(&provisional, &stake));
+        assert!(provisional.stakes.get(&stake.hash_nosigs()).0.is_none());
+        assert!(provisional.coins.get(&coins[0]).0.is_some());
+        assert_consistent(&mempool);
+    }
+
+    #[test]
+    fn tie_breaking() {
+        let (state, coins) = setup(2);
+        let mut mempool = Mempool::new(state);
+        let first = spend(coins[0], COIN_VALUE, 20_000_000);
+        let second = spend(coins[1], COIN_VALUE, 20_000_000);
+        mempool.apply_transaction(&first).unwrap();
+        mempool.apply_transaction(&second).unwrap();
+        // among equally paying transactions, the most recent is evicted first
+        mempool.set_max_bytes(size(&first));
+        assert_eq!(mempool.len(), 1);
+        assert!(contains(&mempool.to_state(), &first));
+        assert!(!contains(&mempool.to_state(), &second));
+        assert_consistent(&mempool);
+    }
+
+    #[test]
+    fn rebase_drops_invalid() {
+        let (state, coins) = setup(3);
+        let mut mempool = Mempool::new(state.clone());
+        let confirmed = spend(coins[0], COIN_VALUE, 20_000_000);
+        let child = spend(
+            confirmed.output_coinid(0),
+            COIN_VALUE - confirmed.fee,
+            20_000_000,
+        );
+        let conflicting = spend(coins[1], COIN_VALUE, 20_000_000);
+        let untouched = spend(coins[2], COIN_VALUE, 20_000_000);
+        for tx in [&confirmed, &child, &conflicting, &untouched] {
+            mempool.apply_transaction(tx).unwrap();
+        }
+
+        // the next block confirms one transaction, and double-spends the coin of another
+        let mut next = state;
+        next.apply_tx(&confirmed).unwrap();
+        next.apply_tx(&spend(coins[1], COIN_VALUE, 30_000_000))
+            .unwrap();
+        mempool.rebase(next.seal(None).next_state());
+
+        assert_eq!(mempool.len(), 2);
+        let provisional = mempool.to_state();
+        assert!(contains(&provisional, &child));
+        assert!(contains(&provisional, &untouched));
+        assert!(!contains(&provisional, &confirmed));
+        assert!(!contains(&provisional, &conflicting));
+        assert_consistent(&mempool);
+    }
+
+    #[test]
+    fn restore_drops_invalid() {
+        let (state, coins) = setup(3);
+        let mut mempool = Mempool::new(state.clone());
+        let confirmed = spend(coins[0], COIN_VALUE, 20_000_000);
+        let child = spend(
+            confirmed.output_coinid(0),
+            COIN_VALUE - confirmed.fee,
+            20_000_000,
+        );
+        let conflicting = spend(coins[1], COIN_VALUE, 20_000_000);
+        let untouched = spend(coins[2], COIN_VALUE, 30_000_000);
+        for tx in [&confirmed, &child, &conflicting, &untouched] {
+            mempool.apply_transaction(tx).unwrap();
+        }
+        let snapshot = stdcode::serialize(&mempool.snapshot()).unwrap();
+
+        // while we were down, a block confirmed one transaction and double-spent the coin of another
+        let mut next = state;
+        next.apply_tx(&confirmed).unwrap();
+        next.apply_tx(&spend(coins[1], COIN_VALUE, 30_000_000))
+            .unwrap();
+        let mut mempool = Mempool::new(next.seal(None).next_state());
+        let snapshot: MempoolSnapshot = stdcode::deserialize(&snapshot).unwrap();
+        assert_eq!(snapshot.pending.len(), 4);
+        assert_eq!(mempool.restore(snapshot), 2);
+
+        assert_eq!(mempool.len(), 2);
+        let provisional = mempool.to_state();
+        assert!(contains(&provisional, &child));
+        assert!(contains(&provisional, &untouched));
+        assert!(!contains(&provisional, &confirmed));
+        assert!(!contains(&provisional, &conflicting));
+        // the dropped transactions are still remembered as seen
+        assert!(mempool.lookup(conflicting.hash_nosigs()).is_some());
+        assert_consistent(&mempool);
+    }
 }
@@ -1,14 +1,43 @@
 #![allow(clippy::upper_case_acronyms)]
 
+mod archive;
+mod index;
 mod mempool;
 mod smt;
-use std::sync::Arc;
+mod txindex;
+use std::{convert::TryInto, net::SocketAddr, sync::Arc};
 
-use self::mempool::Mempool;
-use blkdb::{traits::DbBackend, BlockTree};
+use self::mempool::{Mempool, MempoolSnapshot};
+use anyhow::Context;
+pub use archive::*;
+use blkdb::{
+    traits::{DbBackend, WriteBatch},
+    BlockTree, BlockTreeEvent,
+};
+pub use index::AddressIndex;
+use novasymph::Evidence;
 use parking_lot::RwLock;
+use smol::channel::Receiver;
 pub use smt::*;
-use themelio_stf::{ConsensusProof, GenesisConfig, SealedState, State};
+use themelio_stf::{
+    ConsensusProof, GenesisConfig, Header, SealedState, StakeMapping, State, STAKE_EPOCH,
+};
+use thiserror::Error;
+pub use txindex::{TxIndex, TxLocation};
+
+/// The key under which the mempool snapshot is stored.
+const MEMPOOL_KEY: &[u8] = b"snapshot";
+
+/// Error returned when a block's consensus proof doesn't hold, or can't be checked.
+#[derive(Error, Debug)]
+pub enum ConsensusError {
+    /// The proof itself is bad, so whoever served it is at fault.
+    #[error("invalid consensus proof for block {height}: {reason}")]
+    Invalid { height: u64, reason: String },
+    /// The state needed to check the proof isn't here, e.g. because it was pruned or lies below a checkpoint.
+    #[error("cannot verify the consensus proof for block {0}: no preceding state")]
+    Unverifiable(u64),
+}
 
 /// An alias for a shared NodeStorage.
 pub type SharedStorage = Arc<RwLock<NodeStorage>>;
@@ -16,9 +45,19 @@ pub type SharedStorage = Arc<RwLock<NodeStorage>>;
 /// NodeStorage encapsulates all storage used by a Themelio full node (auditor or staker).
 pub struct NodeStorage {
     mempool: Mempool,
+    mempool_dict: boringdb::Dict,
+    evidence_dict: boringdb::Dict,
+    consensus_dict: boringdb::Dict,
+    peers_dict: boringdb::Dict,
+    index_dict: boringdb::Dict,
 
     history: BlockTree<BoringDbBackend>,
+    history_events: Receiver<BlockTreeEvent>,
     forest: novasmt::Forest,
+    smt: BoringDbSmt,
+    prune_keep: Option<u64>,
+    index: Option<AddressIndex>,
+    tx_index: TxIndex,
 }
 
 impl NodeStorage {
@@ -34,10 +73,15 @@ impl NodeStorage {
 
     /// Opens a NodeStorage, given a sled database.
     pub fn new(db: boringdb::Database, genesis: GenesisConfig) -> Self {
-        // Identify the genesis by the genesis ID
-        let genesis_id = tmelcrypt::hash_single(stdcode::serialize(&genesis).unwrap());
-        let dict = db.open_dict(&format!("genesis{}", genesis_id)).unwrap();
-        let forest = novasmt::Forest::new(BoringDbSmt::new(dict.clone()));
+        let dict = genesis_dict(&db, &genesis);
+        let mempool_dict = mempool_dict(&db, &genesis);
+        let evidence_dict = evidence_dict(&db, &genesis);
+        let consensus_dict = consensus_dict(&db, &genesis);
+        let peers_dict = peers_dict(&db, &genesis);
+        let index_dict = index_dict(&db, &genesis);
+        let tx_index = TxIndex::new(txindex_dict(&db, &genesis));
+        let smt = BoringDbSmt::new(dict.clone());
+        let forest = novasmt::Forest::new(smt.clone());
         let mut history = BlockTree::new(BoringDbBackend { dict }, forest.clone(), true);
 
         // initialize stuff
@@ -45,11 +89,188 @@ impl NodeStorage {
             history.set_genesis(State::genesis(&forest, genesis).seal(None), &[]);
         }
 
+        let history_events = history.subscribe();
+
         let mempool_state = history.get_tips()[0].to_state().next_state();
-        Self {
-            mempool: Mempool::new(mempool_state),
+        let mut mempool = Mempool::new(mempool_state);
+        if let Some(snapshot) = mempool_dict.get(MEMPOOL_KEY).unwrap() {
+            match stdcode::deserialize::<MempoolSnapshot>(&snapshot) {
+                Ok(snapshot) => {
+                    let total = snapshot.pending.len();
+                    let restored = mempool.restore(snapshot);
+                    log::info!("restored {}/{} mempool txx", restored, total);
+                }
+                Err(err) => log::warn!("discarding undecodable mempool snapshot: {:?}", err),
+            }
+        }
+        let storage = Self {
+            mempool,
+            mempool_dict,
+            evidence_dict,
+            consensus_dict,
+            peers_dict,
+            index_dict,
             history,
+            history_events,
             forest,
+            smt,
+            prune_keep: None,
+            index: None,
+            tx_index,
+        };
+        storage.catch_up_tx_index();
+        storage
+    }
+
+    /// Indexes the transactions in every block not indexed yet, e.g. on the first start after upgrading. Blocks whose states were pruned are skipped.
+    fn catch_up_tx_index(&self) {
+        let highest = self.highest_height();
+        let lowest = self.lowest_height();
+        if let Some(indexed) = self.tx_index.indexed_height() {
+            if indexed > highest || indexed + 1 < lowest {
+                log::warn!(
+                    "transaction index doesn't line up with the block history, rebuilding it"
+                );
+                self.tx_index.clear();
+            }
+        }
+        let start = self
+            .tx_index
+            .indexed_height()
+            .map(|h| h + 1)
+            .unwrap_or(lowest);
+        for height in start..=highest {
+            if let Some(state) = self.get_state(height) {
+                self.tx_index.index_block(&state);
+            }
+            if height % 1000 == 0 {
+                log::info!("backfilled the transaction index up to block {}", height);
+            }
+        }
+    }
+
+    /// Starts maintaining the [AddressIndex], first backfilling it with every block not indexed yet. Fails if the states of those blocks were pruned.
+    pub fn enable_index(&mut self) -> anyhow::Result<()> {
+        let index = AddressIndex::new(self.index_dict.clone());
+        let highest = self.highest_height();
+        let lowest = self.lowest_height();
+        if let Some(indexed) = index.indexed_height() {
+            // e.g. after an emergency reset, or a checkpoint installed while the index was disabled
+            if indexed > highest || indexed + 1 < lowest {
+                log::warn!("address index doesn't line up with the block history, rebuilding it");
+                index.clear();
+            }
+        }
+        let start = index.indexed_height().map(|h| h + 1).unwrap_or(lowest);
+        let mut previous = None;
+        for height in start..=highest {
+            if previous.is_none() && height > lowest {
+                previous = Some(self.get_state(height - 1).with_context(|| {
+                    format!(
+                        "cannot backfill the address index: block {} pruned",
+                        height - 1
+                    )
+                })?);
+            }
+            let state = self.get_state(height).with_context(|| {
+                format!("cannot backfill the address index: block {} pruned", height)
+            })?;
+            index.index_block(previous.as_ref(), &state);
+            previous = Some(state);
+            if height % 1000 == 0 {
+                log::info!("backfilled the address index up to block {}", height);
+            }
+        }
+        self.index = Some(index);
+        Ok(())
+    }
+
+    /// Gets the address index, if it's enabled.
+    pub fn index(&self) -> Option<&AddressIndex> {
+        self.index.as_ref()
+    }
+
+    /// Gets the transaction index.
+    pub fn tx_index(&self) -> &TxIndex {
+        &self.tx_index
+    }
+
+    /// Sets how many blocks below the highest one keep their states. Older blocks are pruned down to their headers and consensus proofs as new blocks are applied. `None`, the default, keeps everything.
+    pub fn set_prune_keep(&mut self, prune_keep: Option<u64>) {
+        self.prune_keep = prune_keep;
+    }
+
+    /// Saves a snapshot of the mempool to the database, so that it survives restarts.
+    pub fn save_mempool(&self) {
+        let snapshot = stdcode::serialize(&self.mempool.snapshot()).unwrap();
+        self.mempool_dict
+            .insert(MEMPOOL_KEY.to_vec(), snapshot)
+            .unwrap();
+    }
+
+    /// Persists a piece of equivocation evidence, returning whether it was new.
+    pub fn insert_evidence(&self, evidence: &Evidence) -> bool {
+        self.evidence_dict
+            .insert(
+                evidence.hash().0.to_vec(),
+                stdcode::serialize(evidence).unwrap(),
+            )
+            .unwrap()
+            .is_none()
+    }
+
+    /// Returns all the persisted equivocation evidence.
+    pub fn evidence(&self) -> Vec<Evidence> {
+        self.evidence_dict
+            .range::<&[u8], _>(..)
+            .unwrap()
+            .filter_map(|kv| stdcode::deserialize(&kv.unwrap().1).ok())
+            .collect()
+    }
+
+    /// Remembers the good peers of a network, so that they can be reached again after a restart.
+    pub fn save_peers(&self, network: &str, peers: &[SocketAddr]) {
+        self.peers_dict
+            .insert(
+                network.as_bytes().to_vec(),
+                stdcode::serialize(&peers).unwrap(),
+            )
+            .unwrap();
+    }
+
+    /// Returns the peers of a network remembered from a previous run.
+    pub fn saved_peers(&self, network: &str) -> Vec<SocketAddr> {
+        self.peers_dict
+            .get(network.as_bytes())
+            .unwrap()
+            .and_then(|peers| stdcode::deserialize(&peers).ok())
+            .unwrap_or_default()
+    }
+
+    /// Returns durable storage for the consensus state of the given staker. Its own proposals, votes and confirmations reach disk before returning, since the store is also their write-ahead log.
+    pub fn consensus_store(&self, owner: tmelcrypt::Ed25519PK) -> novasymph::ChainStore {
+        let dict = self.consensus_dict.clone();
+        novasymph::ChainStore::buffered(BoringDbBackend { dict: dict.clone() }, owner, move || {
+            dict.flush().unwrap()
+        })
+    }
+
+    /// Checks the block history in the given database for inconsistencies, repairing them if asked to. Unlike [NodeStorage::new], this works even on databases too corrupt to open normally.
+    pub fn check_history(
+        db: boringdb::Database,
+        genesis: GenesisConfig,
+        repair: bool,
+    ) -> blkdb::RepairReport {
+        let dict = genesis_dict(&db, &genesis);
+        let forest = novasmt::Forest::new(BoringDbSmt::new(dict.clone()));
+        let mut history = BlockTree::open_for_repair(BoringDbBackend { dict }, forest, true);
+        if repair {
+            history.repair()
+        } else {
+            blkdb::RepairReport {
+                repaired: vec![],
+                remaining: history.verify(),
+            }
         }
     }
 
@@ -70,14 +291,56 @@ impl NodeStorage {
         tips.into_iter().map(|v| v.header().height).max().unwrap()
     }
 
-    /// Obtain a historical SealedState.
+    /// Obtain the lowest height, i.e. the height of the genesis block or checkpoint the history starts at.
+    pub fn lowest_height(&self) -> u64 {
+        // heights are contiguous, so we binary-search for the lowest one that has a block
+        let (mut low, mut high) = (0, self.highest_height());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.history.get_at_height(mid).is_empty() {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    /// Obtain a historical SealedState. Returns `None` if there's no block at that height, or if its state was pruned.
     pub fn get_state(&self, height: u64) -> Option<SealedState> {
         self.history
             .get_at_height(height)
             .get(0)
+            .filter(|v| !v.is_pruned())
             .map(|v| v.to_state())
     }
 
+    /// Obtain a historical header. Unlike states, headers are never pruned.
+    pub fn get_header(&self, height: u64) -> Option<Header> {
+        self.history
+            .get_at_height(height)
+            .into_iter()
+            .next()
+            .map(|v| v.header())
+    }
+
+    /// Whether or not the state at the given height was pruned.
+    pub fn is_pruned(&self, height: u64) -> bool {
+        self.history
+            .get_at_height(height)
+            .into_iter()
+            .next()
+            .map(|v| v.is_pruned())
+            .unwrap_or(false)
+    }
+
+    /// Obtain the height of a block, given its hash.
+    pub fn get_height_by_hash(&self, blkhash: tmelcrypt::HashVal) -> Option<u64> {
+        self.history
+            .get_cursor(blkhash)
+            .map(|cursor| cursor.header().height)
+    }
+
     /// Obtain a historical ConsensusProof.
     pub fn get_consensus(&self, height: u64) -> Option<ConsensusProof> {
         let height = self
@@ -89,7 +352,21 @@ impl NodeStorage {
         stdcode::deserialize(height.metadata()).ok()
     }
 
-    /// Consumes a block, applying it to the current state.
+    /// Checks that a consensus proof carries more than 2/3 of the stake of the block's epoch, as recorded in the preceding state. Invalid signatures make the whole proof invalid.
+    pub fn verify_consensus(
+        &self,
+        header: &Header,
+        cproof: &ConsensusProof,
+    ) -> Result<(), ConsensusError> {
+        let previous = header
+            .height
+            .checked_sub(1)
+            .and_then(|height| self.get_state(height))
+            .ok_or(ConsensusError::Unverifiable(header.height))?;
+        check_consensus(&previous.inner_ref().stakes, header, cproof)
+    }
+
+    /// Consumes a block, applying it to the current state. The block must be confirmed by its consensus proof, as checked by [NodeStorage::verify_consensus].
     pub fn apply_block(
         &mut self,
         blk: themelio_stf::Block,
@@ -103,15 +380,53 @@ impl NodeStorage {
                 highest_height
             );
         }
+        self.verify_consensus(&blk.header, &cproof)?;
 
+        let previous = self.highest_state();
         self.history
             .apply_block(&blk, &stdcode::serialize(&cproof).unwrap())?;
         log::debug!("applied block {}", blk.header.height);
-        let next = self.highest_state().next_state();
-        self.mempool_mut().rebase(next);
+        self.tx_index.index_block(&self.highest_state());
+        if let Some(index) = &self.index {
+            index.index_block(Some(&previous), &self.highest_state());
+        }
+        if let Some(keep) = self.prune_keep {
+            self.history
+                .prune_states(blk.header.height.saturating_sub(keep));
+        }
+        self.follow_history();
         Ok(())
     }
 
+    /// Replaces the whole block history with a single state, which becomes the new genesis. Used to start from a trusted checkpoint, so the state must already be verified.
+    pub fn install_checkpoint(&mut self, state: SealedState, cproof: ConsensusProof) {
+        self.history
+            .set_genesis(state, &stdcode::serialize(&cproof).unwrap());
+        self.tx_index.clear();
+        self.tx_index.index_block(&self.highest_state());
+        if let Some(index) = &self.index {
+            // the blocks between the old history and the checkpoint will never be indexed, so we start over
+            index.clear();
+            index.index_block(None, &self.highest_state());
+        }
+        self.follow_history();
+    }
+
+    /// Catches up with the changes to the block history since the last call: the mempool is rebased onto the highest tip, if the tips changed.
+    fn follow_history(&mut self) {
+        let mut tips = None;
+        while let Ok(event) = self.history_events.try_recv() {
+            if let BlockTreeEvent::TipChanged(new_tips) = event {
+                tips = Some(new_tips);
+            }
+        }
+        let highest = tips.and_then(|tips| tips.into_iter().max_by_key(|tip| tip.height));
+        if let Some(cursor) = highest.and_then(|tip| self.history.get_cursor(tip.hash())) {
+            let next = cursor.to_state().next_state();
+            self.mempool.rebase(next);
+        }
+    }
+
     /// Convenience method to "share" storage.
     pub fn share(self) -> SharedStorage {
         Arc::new(RwLock::new(self))
@@ -122,10 +437,110 @@ impl NodeStorage {
         self.forest.clone()
     }
 
-    /// Gets the blockdb.
-    pub fn history_mut(&mut self) -> &mut blkdb::BlockTree<impl DbBackend> {
-        &mut self.history
+    /// Gets the database underlying the forest, e.g. to collect its garbage.
+    pub fn smt(&self) -> BoringDbSmt {
+        self.smt.clone()
+    }
+
+    /// Gets statistics about the blockdb's state cache.
+    pub fn cache_stats(&self) -> blkdb::CacheStats {
+        self.history.cache_stats()
+    }
+
+    /// Deletes every block above the given height, taking them out of the indexes too. This is used to roll back history in emergencies.
+    pub fn reset_to(&mut self, height: u64) {
+        while self
+            .history
+            .get_tips()
+            .iter()
+            .any(|tip| tip.header().height > height)
+        {
+            let highest = self.highest_state();
+            let previous = highest
+                .inner_ref()
+                .height
+                .checked_sub(1)
+                .and_then(|height| self.get_state(height));
+            if highest.inner_ref().height > height {
+                self.tx_index.unindex_block(&highest);
+                if let Some(index) = &self.index {
+                    index.unindex_block(previous.as_ref(), &highest);
+                }
+            }
+            self.history.delete_tips();
+        }
+        self.follow_history();
+    }
+}
+
+/// Checks that a consensus proof carries more than 2/3 of the stake of the block's epoch, according to the stakes in the preceding state.
+pub fn check_consensus(
+    stakes: &StakeMapping,
+    header: &Header,
+    cproof: &ConsensusProof,
+) -> Result<(), ConsensusError> {
+    let invalid = |reason| ConsensusError::Invalid {
+        height: header.height,
+        reason,
+    };
+    let blkhash = header.hash();
+    let mut sum_weights = 0.0;
+    for (pubkey, signature) in cproof.iter() {
+        if !pubkey.verify(&blkhash, signature) {
+            return Err(invalid(format!("invalid signature from {:?}", pubkey)));
+        }
+        sum_weights += stakes.vote_power(header.height / STAKE_EPOCH, *pubkey);
+    }
+    if sum_weights <= 0.67 {
+        return Err(invalid(format!(
+            "only carries {:.2} of the stake",
+            sum_weights
+        )));
     }
+    Ok(())
+}
+
+/// Opens the dictionary holding everything for a particular genesis.
+fn genesis_dict(db: &boringdb::Database, genesis: &GenesisConfig) -> boringdb::Dict {
+    // Identify the genesis by the genesis ID
+    let genesis_id = tmelcrypt::hash_single(stdcode::serialize(genesis).unwrap());
+    db.open_dict(&format!("genesis{}", genesis_id)).unwrap()
+}
+
+/// Opens the dictionary holding the mempool snapshot for a particular genesis.
+fn mempool_dict(db: &boringdb::Database, genesis: &GenesisConfig) -> boringdb::Dict {
+    let genesis_id = tmelcrypt::hash_single(stdcode::serialize(genesis).unwrap());
+    db.open_dict(&format!("mempool{}", genesis_id)).unwrap()
+}
+
+/// Opens the dictionary holding equivocation evidence for a particular genesis.
+fn evidence_dict(db: &boringdb::Database, genesis: &GenesisConfig) -> boringdb::Dict {
+    let genesis_id = tmelcrypt::hash_single(stdcode::serialize(genesis).unwrap());
+    db.open_dict(&format!("evidence{}", genesis_id)).unwrap()
+}
+
+/// Opens the dictionary holding the staker's consensus state for a particular genesis.
+fn consensus_dict(db: &boringdb::Database, genesis: &GenesisConfig) -> boringdb::Dict {
+    let genesis_id = tmelcrypt::hash_single(stdcode::serialize(genesis).unwrap());
+    db.open_dict(&format!("consensus{}", genesis_id)).unwrap()
+}
+
+/// Opens the dictionary holding the address index for a particular genesis.
+fn index_dict(db: &boringdb::Database, genesis: &GenesisConfig) -> boringdb::Dict {
+    let genesis_id = tmelcrypt::hash_single(stdcode::serialize(genesis).unwrap());
+    db.open_dict(&format!("index{}", genesis_id)).unwrap()
+}
+
+/// Opens the dictionary holding the transaction index for a particular genesis.
+fn txindex_dict(db: &boringdb::Database, genesis: &GenesisConfig) -> boringdb::Dict {
+    let genesis_id = tmelcrypt::hash_single(stdcode::serialize(genesis).unwrap());
+    db.open_dict(&format!("txindex{}", genesis_id)).unwrap()
+}
+
+/// Opens the dictionary holding the known-good peers for a particular genesis.
+fn peers_dict(db: &boringdb::Database, genesis: &GenesisConfig) -> boringdb::Dict {
+    let genesis_id = tmelcrypt::hash_single(stdcode::serialize(genesis).unwrap());
+    db.open_dict(&format!("peers{}", genesis_id)).unwrap()
 }
 
 struct BoringDbBackend {
@@ -155,4 +570,229 @@ impl DbBackend for BoringDbBackend {
             .map(|v| v.unwrap().0.to_vec())
             .collect()
     }
+
+    fn write_batch(&mut self, batch: WriteBatch) {
+        // everything written within one boringdb transaction reaches disk together
+        {
+            let mut txn = self.dict.transaction().unwrap();
+            for (key, value) in batch {
+                match value {
+                    Some(value) => txn.insert(key, value).unwrap(),
+                    None => txn_remove(&mut txn, &key),
+                }
+            }
+        }
+    }
+}
+
+/// Removes a key within a transaction. boringdb transactions silently skip removals of uncached keys, so we cache the key first.
+fn txn_remove(txn: &mut boringdb::Transaction<'_>, key: &[u8]) {
+    txn.insert(key.to_vec(), Vec::new()).unwrap();
+    txn.remove(key).unwrap();
+}
+
+/// The key under which an index stores the height of the highest block it has indexed.
+const INDEXED_HEIGHT_KEY: &[u8] = b"indexed";
+
+/// Returns the height of the highest block indexed in an index's dictionary, or `None` if nothing is indexed yet.
+fn indexed_height(dict: &boringdb::Dict) -> Option<u64> {
+    read_height(dict, INDEXED_HEIGHT_KEY)
+}
+
+/// Records the height of the highest indexed block within a transaction, or that nothing is indexed.
+fn set_indexed_height(txn: &mut boringdb::Transaction<'_>, height: Option<u64>) {
+    write_height(txn, INDEXED_HEIGHT_KEY, height)
+}
+
+/// Reads a height stored under a key.
+fn read_height(dict: &boringdb::Dict, key: &[u8]) -> Option<u64> {
+    dict.get(key)
+        .unwrap()
+        .map(|height| u64::from_be_bytes(height.as_ref().try_into().expect("corrupt index")))
+}
+
+/// Stores a height under a key within a transaction, or removes the key if there's no height.
+fn write_height(txn: &mut boringdb::Transaction<'_>, key: &[u8], height: Option<u64>) {
+    match height {
+        Some(height) => txn
+            .insert(key.to_vec(), height.to_be_bytes().to_vec())
+            .unwrap(),
+        None => txn_remove(txn, key),
+    };
+}
+
+/// Deletes everything in an index's dictionary.
+fn clear_index(dict: &boringdb::Dict) {
+    let keys = dict
+        .range::<&[u8], _>(..)
+        .unwrap()
+        .map(|kv| kv.unwrap().0)
+        .collect::<Vec<_>>();
+    let mut txn = dict.transaction().unwrap();
+    for key in keys {
+        txn_remove(&mut txn, &key);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use themelio_stf::{
+        melvm::Covenant, Block, CoinData, CoinID, Denom, StakeDoc, Transaction, TxKind,
+    };
+    use tmelcrypt::{Ed25519PK, Ed25519SK};
+
+    use super::*;
+
+    /// Opens a NodeStorage backed by a fresh database.
+    pub(crate) fn temp_storage(genesis: &GenesisConfig) -> NodeStorage {
+        let path = std::env::temp_dir().join(format!("storage-test-{}.sqlite3", fastrand::u64(..)));
+        NodeStorage::new(boringdb::Database::open(path).unwrap(), genesis.clone())
+    }
+
+    /// A testnet genesis where the given key holds all the stake.
+    pub(crate) fn staker_genesis(staker: Ed25519PK) -> GenesisConfig {
+        let mut genesis = GenesisConfig::std_testnet();
+        genesis.stakes = std::iter::once((
+            tmelcrypt::hash_single(staker.0).into(),
+            StakeDoc {
+                pubkey: staker,
+                e_start: 0,
+                e_post_end: 1 << 32,
+                syms_staked: 1,
+            },
+        ))
+        .collect();
+        genesis
+    }
+
+    /// Signs a consensus proof for a block.
+    pub(crate) fn sign(block: &Block, signer: (Ed25519PK, Ed25519SK)) -> ConsensusProof {
+        let (pk, sk) = signer;
+        std::iter::once((pk, sk.sign(&block.header.hash()))).collect()
+    }
+
+    pub(crate) const FEE: u128 = 10_000_000;
+
+    /// Spends a coin into outputs of the given values, which must add up to its value minus [FEE].
+    pub(crate) fn spend(coinid: CoinID, values: Vec<u128>) -> Transaction {
+        Transaction {
+            kind: TxKind::Normal,
+            inputs: vec![coinid],
+            outputs: values
+                .into_iter()
+                .map(|value| CoinData {
+                    covhash: Covenant::always_true().hash(),
+                    value,
+                    denom: Denom::Mel,
+                    additional_data: vec![],
+                })
+                .collect(),
+            fee: FEE,
+            scripts: vec![Covenant::always_true()],
+            data: vec![],
+            sigs: vec![],
+        }
+    }
+
+    #[test]
+    fn checkpoint_shares_smts() {
+        let staker = tmelcrypt::ed25519_keygen();
+        let genesis = staker_genesis(staker.0);
+        let path = std::env::temp_dir().join(format!("storage-test-{}.sqlite3", fastrand::u64(..)));
+        let mut storage =
+            NodeStorage::new(boringdb::Database::open(&path).unwrap(), genesis.clone());
+        for _ in 0..3 {
+            let block = storage.highest_state().next_state().seal(None).to_block();
+            let cproof = sign(&block, staker);
+            storage.apply_block(block, cproof).unwrap();
+            // the mempool follows the new tip
+            assert_eq!(
+                storage.mempool().to_state().height,
+                storage.highest_height() + 1
+            );
+        }
+        // nothing is staked after genesis, so the checkpoint has the same stakes as the history it replaces
+        let checkpoint = storage.get_state(2).unwrap();
+        assert_eq!(
+            checkpoint.header().stakes_hash,
+            storage.get_state(0).unwrap().header().stakes_hash
+        );
+        let cproof = sign(&checkpoint.to_block(), staker);
+        storage.install_checkpoint(checkpoint.clone(), cproof);
+        assert_eq!(storage.lowest_height(), 2);
+        drop(checkpoint);
+        drop(storage);
+
+        let storage = NodeStorage::new(boringdb::Database::open(&path).unwrap(), genesis);
+        let state = storage.get_state(2).unwrap();
+        assert_eq!(state.inner_ref().stakes.val_iter().count(), 1);
+        let next = storage.highest_state().next_state().seal(None).to_block();
+        storage
+            .verify_consensus(&next.header, &sign(&next, staker))
+            .unwrap();
+    }
+
+    #[test]
+    fn reset_rewinds_indexes() {
+        let staker = tmelcrypt::ed25519_keygen();
+        let genesis = staker_genesis(staker.0);
+        let owner = genesis.init_coindata.covhash;
+        let half = (genesis.init_coindata.value - FEE) / 2;
+        let mut storage = temp_storage(&genesis);
+        storage.enable_index().unwrap();
+        let apply = |storage: &mut NodeStorage, txx: &[&Transaction]| {
+            let mut next = storage.highest_state().next_state();
+            for tx in txx {
+                next.apply_tx(tx).unwrap();
+            }
+            let block = next.seal(None).to_block();
+            let cproof = sign(&block, staker);
+            storage.apply_block(block, cproof).unwrap();
+        };
+
+        let split = spend(CoinID::zero_zero(), vec![half, half]);
+        apply(&mut storage, &[&split]);
+        let index = storage.index().unwrap();
+        let mut coins_before = index.unspent_coins(owner);
+        coins_before.sort_by_key(|(coinid, _)| *coinid);
+        let history_before = index.transactions(owner);
+        let first = spend(split.output_coinid(0), vec![half - FEE]);
+        let second = spend(split.output_coinid(1), vec![half - FEE]);
+        apply(&mut storage, &[&first]);
+        apply(&mut storage, &[&second]);
+        assert_eq!(
+            storage
+                .tx_index()
+                .locate(second.hash_nosigs())
+                .unwrap()
+                .height,
+            3
+        );
+
+        storage.reset_to(1);
+        assert_eq!(storage.highest_height(), 1);
+        let tx_index = storage.tx_index();
+        assert_eq!(tx_index.indexed_height(), Some(1));
+        assert_eq!(tx_index.locate(split.hash_nosigs()).unwrap().height, 1);
+        assert!(tx_index.locate(first.hash_nosigs()).is_none());
+        assert!(tx_index.locate(second.hash_nosigs()).is_none());
+        let index = storage.index().unwrap();
+        assert_eq!(index.indexed_height(), Some(1));
+        let mut coins_after = index.unspent_coins(owner);
+        coins_after.sort_by_key(|(coinid, _)| *coinid);
+        assert_eq!(
+            stdcode::serialize(&coins_after).unwrap(),
+            stdcode::serialize(&coins_before).unwrap()
+        );
+        assert_eq!(index.transactions(owner), history_before);
+
+        // the history carries on from the reset height, with different blocks
+        apply(&mut storage, &[&second]);
+        let history = storage.index().unwrap().transactions(owner);
+        assert_eq!(history.last(), Some(&(2, second.hash_nosigs())));
+        assert_eq!(history.len(), history_before.len() + 1);
+        let location = storage.tx_index().locate(second.hash_nosigs()).unwrap();
+        assert_eq!(location.height, 2);
+        assert!(storage.tx_index().locate(first.hash_nosigs()).is_none());
+    }
 }
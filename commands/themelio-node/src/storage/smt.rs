@@ -1,28 +1,204 @@
+use std::{
+    collections::HashSet,
+    convert::TryInto,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use novasmt::{BackendNode, Hashed};
+use parking_lot::RwLock;
+
+use super::txn_remove;
+
+/// The prefix of the keys of roots queued for deletion. Nodes are keyed by their 32-byte hashes, and the block history sharing the dictionary uses 40-byte keys, so a key with this prefix and a 32-byte root after it can't be anything else.
+const QUEUE_PREFIX: &[u8] = b"smt-gc/";
+
+/// Present once roots queued by older versions, which were keyed by `0xff` and the root, have been dropped from the queue.
+const QUEUE_MIGRATED_KEY: &[u8] = b"smt-gc";
 
 /// A boringdb-backed `autosmt` database.
+///
+/// Every node is stored with a count of the stored nodes that point to it, and deleting a root deletes every node that's only reachable through it. Roots that can't be deleted right away, because trees over them are still open, are queued instead, and deleted by [BoringDbSmt::collect_garbage] unless they are opened or saved again in the meantime.
+#[derive(Clone)]
 pub struct BoringDbSmt {
     disk_tree: boringdb::Dict,
+    // the roots in the on-disk queue, so that reads don't have to go to disk to check whether they're reopening one
+    queued: Arc<RwLock<HashSet<Hashed>>>,
+}
+
+/// The outcome of [BoringDbSmt::collect_garbage].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SmtGcReport {
+    /// Queued roots that were deleted.
+    pub roots: usize,
+    /// Nodes reclaimed by deleting those roots.
+    pub nodes: usize,
+    /// Queued roots left alone, since they were queued too recently.
+    pub pending: usize,
 }
 
 impl BoringDbSmt {
     /// Creates a new database based on a tree.
     pub fn new(disk_tree: boringdb::Dict) -> Self {
-        Self { disk_tree }
+        let this = Self {
+            disk_tree,
+            queued: Default::default(),
+        };
+        this.drop_legacy_queue();
+        *this.queued.write() = this.queue().into_iter().map(|(root, _)| root).collect();
+        this
+    }
+
+    /// Drops roots queued by older versions. Their keys are the only 33-byte ones in the dictionary.
+    ///
+    /// Those versions reset the refcount of a node whenever it was saved again, so deleting their roots could delete nodes that live trees still share. We leak them instead.
+    fn drop_legacy_queue(&self) {
+        if self.disk_tree.get(QUEUE_MIGRATED_KEY).unwrap().is_some() {
+            return;
+        }
+        let legacy = self
+            .disk_tree
+            .range::<&[u8], _>(&[0xff][..]..)
+            .unwrap()
+            .map(|kv| kv.unwrap().0)
+            .filter(|key| key.len() == 33)
+            .collect::<Vec<_>>();
+        let mut tree = self.disk_tree.transaction().unwrap();
+        for key in legacy.iter() {
+            txn_remove(&mut tree, key);
+        }
+        tree.insert(QUEUE_MIGRATED_KEY, Vec::new()).unwrap();
+        if !legacy.is_empty() {
+            log::warn!(
+                "dropped {} SMT roots queued for deletion by an older version; they will never be deleted",
+                legacy.len()
+            );
+        }
+    }
+
+    /// Every root queued for deletion, with when it was queued.
+    fn queue(&self) -> Vec<(Hashed, u64)> {
+        self.disk_tree
+            .range::<&[u8], _>(QUEUE_PREFIX..)
+            .unwrap()
+            .map(|kv| kv.unwrap())
+            .take_while(|(key, _)| key.starts_with(QUEUE_PREFIX))
+            // a node whose hash happens to start with the prefix is shorter
+            .filter(|(key, _)| key.len() == QUEUE_PREFIX.len() + 32)
+            .map(|(key, value)| {
+                (
+                    key[QUEUE_PREFIX.len()..].try_into().unwrap(),
+                    queued_at(&value),
+                )
+            })
+            .collect()
+    }
+
+    /// Takes a root off the deletion queue, since a tree over it was opened again.
+    fn unqueue(&self, key: Hashed) {
+        let mut queued = self.queued.write();
+        if queued.remove(&key) {
+            log::debug!("root {} reopened, no longer deleting it", hex::encode(key));
+            self.disk_tree.remove(&queue_key(key)).unwrap();
+        }
+    }
+
+    /// Deletes every root that was queued for deletion before the given time, along with all the nodes only reachable through it. Roots queued later are left alone: trees over them may still be open, since the queue is only written to when they are.
+    pub fn collect_garbage(&self, queued_before: SystemTime) -> SmtGcReport {
+        let cutoff = unix_secs(queued_before);
+        let queued = self.queue();
+        let mut report = SmtGcReport::default();
+        for (root, queued_at) in queued {
+            if queued_at >= cutoff {
+                report.pending += 1;
+                continue;
+            }
+            // the root may have been saved again since we looked, which takes it off the queue
+            if let Some(nodes) = self.delete(root, Some(cutoff)) {
+                report.roots += 1;
+                report.nodes += nodes;
+            }
+        }
+        if report.roots > 0 {
+            log::debug!("collected SMT garbage: {:?}", report);
+        }
+        report
+    }
+
+    /// Deletes a root, and every node only reachable through it, in one transaction. Returns how many nodes were deleted. If `queued_before` is given, does nothing and returns `None` unless the root is queued for deletion since before then.
+    fn delete(&self, key: Hashed, queued_before: Option<u64>) -> Option<usize> {
+        let mut tree = self.disk_tree.transaction().unwrap();
+        if let Some(cutoff) = queued_before {
+            match tree.get(&queue_key(key)).unwrap() {
+                Some(value) if queued_at(&value) < cutoff => {}
+                _ => return None,
+            }
+        }
+        txn_remove(&mut tree, &queue_key(key));
+        self.queued.write().remove(&key);
+        let mut deleted = 0;
+        // DFS. Unlike the nodes below it, the root isn't pointed to by the node we came from
+        let mut dfs_stack: Vec<(Hashed, bool)> = vec![(key, true)];
+        while let Some((top, is_root)) = dfs_stack.pop() {
+            if top == [0; 32] {
+                continue;
+            }
+            let (bnode, mut rcount): BackendNodeRc = match tree.get(&top).unwrap() {
+                Some(val) => stdcode::deserialize(&val).unwrap(),
+                None => {
+                    log::warn!("SMT node {} is already gone", hex::encode(top));
+                    continue;
+                }
+            };
+            if !is_root {
+                rcount = rcount.saturating_sub(1);
+            }
+            log::trace!("rcount {}", rcount);
+            if rcount > 0 {
+                if !is_root {
+                    tree.insert(top.to_vec(), stdcode::serialize(&(bnode, rcount)).unwrap())
+                        .unwrap();
+                }
+                continue;
+            }
+            log::trace!("deleting {}", hex::encode(top));
+            txn_remove(&mut tree, &top);
+            deleted += 1;
+            if let BackendNode::Internal(left, right) = bnode {
+                dfs_stack.push((left, false));
+                dfs_stack.push((right, false));
+            }
+        }
+        Some(deleted)
     }
 }
 
-fn to_delete_tmrw(key: [u8; 32]) -> [u8; 33] {
-    let mut toret = [0; 33];
-    toret[0] = 0xff;
-    toret[1..].copy_from_slice(&key);
+fn queue_key(key: Hashed) -> [u8; 39] {
+    let mut toret = [0; 39];
+    toret[..QUEUE_PREFIX.len()].copy_from_slice(QUEUE_PREFIX);
+    toret[QUEUE_PREFIX.len()..].copy_from_slice(&key);
     toret
 }
 
+/// When a root was queued for deletion, in seconds since the epoch. Markers that don't record the time never expire.
+fn queued_at(value: &[u8]) -> u64 {
+    value.try_into().map(u64::from_be_bytes).unwrap_or(u64::MAX)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 type BackendNodeRc = (BackendNode, u64);
 
 impl novasmt::BackendDB for BoringDbSmt {
     fn get(&self, key: Hashed) -> Option<BackendNode> {
+        // opening a tree reads its root, which cancels any pending deletion, just like it does in the forest
+        if self.queued.read().contains(&key) {
+            self.unqueue(key);
+        }
         if let Some(val) = self.disk_tree.get(&key).unwrap() {
             let (bnode, _): BackendNodeRc = stdcode::deserialize(&val).unwrap();
             Some(bnode)
@@ -37,14 +213,22 @@ impl novasmt::BackendDB for BoringDbSmt {
         log::trace!("inserting {} pairs", kvv.len());
         // first insert all the new elements with refcount 0, while keeping track of what to increment
         for (k, v) in kvv {
+            // also delete from "delete tomorrow"
+            let marker = queue_key(*k);
+            if tree.get(&marker).unwrap().is_some() {
+                txn_remove(&mut tree, &marker);
+                self.queued.write().remove(k);
+            }
+            // nodes are content-addressed, so a node that's already stored already has its children counted
+            if tree.get(k).unwrap().is_some() {
+                continue;
+            }
             if let BackendNode::Internal(left, right) = v {
                 increment.push(*left);
                 increment.push(*right);
             }
             tree.insert(k.to_vec(), stdcode::serialize(&(v.clone(), 0)).unwrap())
                 .unwrap();
-            // also delete from "delete tomorrow"
-            tree.remove(to_delete_tmrw(*k).as_ref()).unwrap();
         }
         // go through increment
         for increment in increment {
@@ -59,31 +243,197 @@ impl novasmt::BackendDB for BoringDbSmt {
     }
 
     fn delete_root(&self, key: Hashed) {
-        let mut tree = self.disk_tree.transaction().unwrap();
-        tree.remove(to_delete_tmrw(key).as_ref()).unwrap();
-        // DFS
-        let mut dfs_stack: Vec<Hashed> = vec![key];
-        while let Some(top) = dfs_stack.pop() {
-            if top != [0; 32] {
-                let (bnode, mut rcount): BackendNodeRc =
-                    stdcode::deserialize(&tree.get(&top).unwrap().unwrap()).unwrap();
-                log::debug!("rcount {}", rcount);
-                rcount = rcount.saturating_sub(1);
-                if rcount == 0 {
-                    log::debug!("deleting {}", hex::encode(&top));
-                    tree.remove(&top).unwrap();
-                    if let BackendNode::Internal(left, right) = bnode {
-                        dfs_stack.push(left);
-                        dfs_stack.push(right);
-                    }
-                }
-            }
-        }
+        self.delete(key, None);
     }
 
     fn delete_root_tomorrow(&self, key: Hashed) {
+        let mut queued = self.queued.write();
         self.disk_tree
-            .insert(to_delete_tmrw(key).to_vec(), b"dummy".to_vec())
+            .insert(
+                queue_key(key).to_vec(),
+                unix_secs(SystemTime::now()).to_be_bytes().to_vec(),
+            )
+            .unwrap();
+        queued.insert(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use novasmt::BackendDB;
+
+    use super::*;
+
+    fn key(i: u64) -> Hashed {
+        tmelcrypt::hash_single(i.to_be_bytes()).0
+    }
+
+    /// A database with nothing in it.
+    fn setup() -> (BoringDbSmt, novasmt::Forest) {
+        let path = std::env::temp_dir().join(format!("smt-test-{}.sqlite3", fastrand::u64(..)));
+        let db = boringdb::Database::open(path).unwrap();
+        let smt = BoringDbSmt::new(db.open_dict("smt").unwrap());
+        let forest = novasmt::Forest::new(smt.clone());
+        (smt, forest)
+    }
+
+    /// Saves a tree with a hundred entries, one of which may be changed, returning its root. Trees saved like this share all the subtrees that don't contain the changed entry.
+    fn save_tree(forest: &novasmt::Forest, changed: Option<u64>) -> Hashed {
+        let mut tree = forest.open_tree([0; 32]).unwrap();
+        for i in 0..100 {
+            tree.insert(key(i), value(i, changed).into());
+        }
+        tree.save();
+        tree.root_hash()
+    }
+
+    fn value(i: u64, changed: Option<u64>) -> Vec<u8> {
+        if Some(i) == changed {
+            b"changed".to_vec()
+        } else {
+            vec![i as u8; 10]
+        }
+    }
+
+    fn node_count(smt: &BoringDbSmt) -> usize {
+        smt.disk_tree
+            .range::<&[u8], _>(..)
+            .unwrap()
+            .filter(|kv| kv.as_ref().unwrap().0.len() == 32)
+            .count()
+    }
+
+    /// Checks that a tree saved by [save_tree] can be read back in full, through a forest that has nothing cached.
+    fn assert_intact(smt: &BoringDbSmt, root: Hashed, changed: Option<u64>) {
+        let tree = novasmt::Forest::new(smt.clone()).open_tree(root).unwrap();
+        for i in 0..100 {
+            assert_eq!(tree.get_with_proof(key(i)).0.to_vec(), value(i, changed));
+        }
+    }
+
+    #[test]
+    fn shared_subtrees() {
+        let (smt, forest) = setup();
+        let first = save_tree(&forest, None);
+        let alone = node_count(&smt);
+        let second = save_tree(&forest, Some(42));
+        let both = node_count(&smt);
+        assert!(both > alone);
+        // saving the same tree again changes nothing
+        assert_eq!(save_tree(&forest, Some(42)), second);
+        assert_eq!(node_count(&smt), both);
+
+        // deleting one tree only deletes the path to the changed entry, which isn't shared
+        smt.delete_root(first);
+        assert_eq!(node_count(&smt), alone);
+        assert!(novasmt::Forest::new(smt.clone()).open_tree(first).is_none());
+        assert_intact(&smt, second, Some(42));
+
+        smt.delete_root(second);
+        assert_eq!(node_count(&smt), 0);
+    }
+
+    #[test]
+    fn collect_garbage() {
+        let (smt, forest) = setup();
+        let first = save_tree(&forest, None);
+        let alone = node_count(&smt);
+        let second = save_tree(&forest, Some(7));
+        let both = node_count(&smt);
+        smt.delete_root_tomorrow(first);
+
+        // nothing happens within the grace period
+        let report = smt.collect_garbage(SystemTime::now() - Duration::from_secs(3600));
+        assert_eq!(
+            report,
+            SmtGcReport {
+                roots: 0,
+                nodes: 0,
+                pending: 1
+            }
+        );
+        assert_eq!(node_count(&smt), both);
+
+        let report = smt.collect_garbage(SystemTime::now() + Duration::from_secs(1));
+        assert_eq!(
+            report,
+            SmtGcReport {
+                roots: 1,
+                nodes: both - alone,
+                pending: 0
+            }
+        );
+        assert_intact(&smt, second, Some(7));
+        // the queue is empty now
+        let report = smt.collect_garbage(SystemTime::now() + Duration::from_secs(1));
+        assert_eq!(report, SmtGcReport::default());
+
+        // saving a queued root again takes it off the queue
+        smt.delete_root_tomorrow(second);
+        assert_eq!(save_tree(&forest, Some(7)), second);
+        let report = smt.collect_garbage(SystemTime::now() + Duration::from_secs(1));
+        assert_eq!(report, SmtGcReport::default());
+        assert_intact(&smt, second, Some(7));
+
+        // roots queued by older versions can't be trusted, so they're forgotten rather than collected
+        let mut legacy = vec![0xff];
+        legacy.extend_from_slice(&second);
+        smt.disk_tree
+            .insert(legacy.clone(), b"dummy".to_vec())
+            .unwrap();
+        smt.disk_tree.remove(QUEUE_MIGRATED_KEY).unwrap();
+        // block history keys for heights near the maximum start with 0xff too
+        let history_key = [0xff; 40];
+        smt.disk_tree
+            .insert(history_key.to_vec(), b"tip".to_vec())
+            .unwrap();
+        let smt = BoringDbSmt::new(smt.disk_tree.clone());
+        assert!(smt.disk_tree.get(&legacy).unwrap().is_none());
+        let report = smt.collect_garbage(SystemTime::now() + Duration::from_secs(1));
+        assert_eq!(report, SmtGcReport::default());
+        assert_intact(&smt, second, Some(7));
+        assert!(smt.disk_tree.get(&history_key).unwrap().is_some());
+
+        // so are markers without a time, which are left over from migrating them
+        smt.disk_tree
+            .insert(queue_key(second).to_vec(), Vec::new())
+            .unwrap();
+        let report = smt.collect_garbage(SystemTime::now() + Duration::from_secs(1));
+        assert_eq!(report.pending, 1);
+        assert_intact(&smt, second, Some(7));
+    }
+
+    #[test]
+    fn reopened_root_survives() {
+        let (smt, forest) = setup();
+        let root = save_tree(&forest, None);
+        let nodes = node_count(&smt);
+        // the root is queued since a tree over it is open when it's deleted
+        let open = forest.open_tree(root).unwrap();
+        forest.delete_tree(root);
+        assert_eq!(smt.queue().len(), 1);
+        // opening it again, without saving anything, cancels the deletion
+        let reopened = forest.open_tree(root).unwrap();
+        drop(open);
+        drop(reopened);
+        assert!(smt.queue().is_empty());
+        let report = smt.collect_garbage(SystemTime::now() + Duration::from_secs(1));
+        assert_eq!(report, SmtGcReport::default());
+        assert_eq!(node_count(&smt), nodes);
+        assert_intact(&smt, root, None);
+
+        // the same goes for a node that crashed with the tree still open, and only has the queue on disk
+        let open = forest.open_tree(root).unwrap();
+        forest.delete_tree(root);
+        std::mem::forget(open);
+        let restarted = BoringDbSmt::new(smt.disk_tree.clone());
+        novasmt::Forest::new(restarted.clone())
+            .open_tree(root)
             .unwrap();
+        let report = restarted.collect_garbage(SystemTime::now() + Duration::from_secs(1));
+        assert_eq!(report, SmtGcReport::default());
+        assert_intact(&restarted, root, None);
     }
 }
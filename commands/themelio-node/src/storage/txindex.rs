@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use themelio_stf::{SealedState, TxHash};
+
+use super::{
+    clear_index, indexed_height, read_height, set_indexed_height, txn_remove, write_height,
+};
+
+/// The key under which the lowest indexed height is stored.
+const LOWEST_KEY: &[u8] = b"lowest";
+
+/// Where a confirmed transaction is.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxLocation {
+    /// Height of the block containing the transaction.
+    pub height: u64,
+    /// Position of the transaction within the block, in order of transaction hash.
+    pub index: u32,
+}
+
+/// An index of where every confirmed transaction is, so that transactions can be looked up by hash alone.
+///
+/// Unlike the [AddressIndex](super::AddressIndex), this is always maintained. Transactions in blocks whose states were pruned before they could be indexed are never indexed.
+pub struct TxIndex {
+    dict: boringdb::Dict,
+}
+
+impl TxIndex {
+    /// Opens the index stored in the given dictionary.
+    pub(super) fn new(dict: boringdb::Dict) -> Self {
+        Self { dict }
+    }
+
+    /// Returns the height of the highest indexed block, or `None` if nothing is indexed yet.
+    pub fn indexed_height(&self) -> Option<u64> {
+        indexed_height(&self.dict)
+    }
+
+    /// Indexes the transactions in the block that produced the given state.
+    pub(super) fn index_block(&self, state: &SealedState) {
+        let height = state.inner_ref().height;
+        let mut txhashes = state
+            .inner_ref()
+            .transactions
+            .val_iter()
+            .map(|tx| tx.hash_nosigs())
+            .collect::<Vec<_>>();
+        txhashes.sort_unstable();
+        // the transaction locks the dictionary, so this must be read first
+        let first = self.indexed_height().is_none();
+        let mut txn = self.dict.transaction().unwrap();
+        for (index, txhash) in txhashes.into_iter().enumerate() {
+            let location = TxLocation {
+                height,
+                index: index as u32,
+            };
+            txn.insert(txhash.0.to_vec(), stdcode::serialize(&location).unwrap())
+                .unwrap();
+        }
+        if first {
+            write_height(&mut txn, LOWEST_KEY, Some(height));
+        }
+        set_indexed_height(&mut txn, Some(height));
+    }
+
+    /// Takes the highest indexed block, which produced the given state, back out of the index. Blocks below the lowest indexed one, e.g. before a state sync checkpoint, were never indexed, so taking out the lowest one leaves nothing indexed.
+    pub(super) fn unindex_block(&self, state: &SealedState) {
+        let height = state.inner_ref().height;
+        let lowest = read_height(&self.dict, LOWEST_KEY);
+        let mut txn = self.dict.transaction().unwrap();
+        for tx in state.inner_ref().transactions.val_iter() {
+            txn_remove(&mut txn, &tx.hash_nosigs().0);
+        }
+        let remaining = height.checked_sub(1).filter(|_| lowest < Some(height));
+        if remaining.is_none() {
+            write_height(&mut txn, LOWEST_KEY, None);
+        }
+        set_indexed_height(&mut txn, remaining);
+    }
+
+    /// Deletes everything in the index.
+    pub(super) fn clear(&self) {
+        clear_index(&self.dict)
+    }
+
+    /// Finds a confirmed transaction.
+    pub fn locate(&self, txhash: TxHash) -> Option<TxLocation> {
+        self.dict
+            .get(&txhash.0)
+            .unwrap()
+            .map(|location| stdcode::deserialize(&location).expect("corrupt index"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use themelio_stf::{GenesisConfig, State};
+
+    use super::*;
+
+    #[test]
+    fn unindex_lowest_block() {
+        let path = std::env::temp_dir().join(format!("txindex-test-{}.sqlite3", fastrand::u64(..)));
+        let db = boringdb::Database::open(path).unwrap();
+        let index = TxIndex::new(db.open_dict("txindex").unwrap());
+        let forest = novasmt::Forest::new(novasmt::InMemoryBackend::default());
+        let genesis = State::genesis(&forest, GenesisConfig::std_testnet()).seal(None);
+        let first = genesis.next_state().seal(None);
+        let second = first.next_state().seal(None);
+        let third = second.next_state().seal(None);
+
+        // like after state syncing to a checkpoint, nothing below the second block is indexed
+        index.index_block(&second);
+        index.index_block(&third);
+        assert_eq!(index.indexed_height(), Some(3));
+        index.unindex_block(&third);
+        assert_eq!(index.indexed_height(), Some(2));
+        index.unindex_block(&second);
+        assert_eq!(index.indexed_height(), None);
+
+        // starting over, the new lowest block is what counts
+        index.index_block(&first);
+        index.index_block(&second);
+        index.unindex_block(&second);
+        assert_eq!(index.indexed_height(), Some(1));
+        index.unindex_block(&first);
+        assert_eq!(index.indexed_height(), None);
+    }
+}
@@ -0,0 +1,548 @@
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use parking_lot::Mutex;
+
+use crate::traits::{DbBackend, WriteBatch};
+
+/// Magic bytes at the start of every log file, including the format version.
+const MAGIC: &[u8; 8] = b"blkdblg1";
+
+/// Compaction never happens on logs smaller than this.
+const MIN_COMPACT_SIZE: u64 = 1 << 20;
+
+/// Compaction splits the live values into records of roughly this many payload bytes, so that no record gets anywhere near the 4 GiB limit on its length.
+const MAX_COMPACT_RECORD: u64 = 1 << 20;
+
+/// The (offset, length) of a value within the log file.
+type ValueLocation = (u64, u32);
+
+const OP_INSERT: u8 = 0;
+const OP_REMOVE: u8 = 1;
+
+/// A durable DbBackend, stored as an append-only log on disk with an in-memory index.
+///
+/// Every write batch becomes one checksummed record, so batches are atomic: a torn record at the end of the log (e.g. from a crash halfway through a write) is detected and discarded on the next open, while a corrupt record anywhere else is reported as an error. Once most of the log consists of overwritten values, it is compacted by rewriting the live values into a fresh file and atomically renaming it into place.
+pub struct LogDb {
+    path: PathBuf,
+    file: Mutex<File>,
+    index: BTreeMap<Vec<u8>, ValueLocation>,
+    file_len: u64,
+    live_bytes: u64,
+    sync: bool,
+}
+
+impl LogDb {
+    /// Opens a log file, creating it if it does not exist and discarding any torn record at its end. Fails if a record before the end is corrupt, since truncating there would throw away committed writes.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        if contents.is_empty() {
+            file.write_all(MAGIC)?;
+            file.sync_all()?;
+            sync_parent_dir(&path)?;
+            contents.extend_from_slice(MAGIC);
+        } else if !contents.starts_with(MAGIC) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a blkdb log file",
+            ));
+        }
+
+        let mut toret = Self {
+            path,
+            file: Mutex::new(file),
+            index: BTreeMap::new(),
+            file_len: MAGIC.len() as u64,
+            live_bytes: 0,
+            sync: true,
+        };
+        let mut offset = MAGIC.len();
+        while offset < contents.len() {
+            match decode_record(&contents, offset) {
+                Ok((payload_start, payload)) => {
+                    toret.index_payload(payload, payload_start as u64);
+                    offset = payload_start + payload.len();
+                }
+                // a torn write can only ever be the very last record
+                Err(BadRecord::Corrupt(record_end)) if record_end < contents.len() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "corrupt record at offset {} of {:?}, followed by {} more bytes",
+                            offset,
+                            toret.path,
+                            contents.len() - record_end
+                        ),
+                    ));
+                }
+                Err(_) => break,
+            }
+        }
+        if offset < contents.len() {
+            log::warn!(
+                "discarding {} bytes of torn records at the end of {:?}",
+                contents.len() - offset,
+                toret.path
+            );
+            toret.file.get_mut().set_len(offset as u64)?;
+            toret.file.get_mut().sync_all()?;
+        }
+        toret.file_len = offset as u64;
+        Ok(toret)
+    }
+
+    /// Sets whether every write is synced to disk before returning. Defaults to true; turning it off trades durability of the most recent writes for speed, but never atomicity.
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    /// Rewrites the log to contain only the live values.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("compact");
+        let mut new_index = BTreeMap::new();
+        let mut new_len = MAGIC.len() as u64;
+        {
+            let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+            tmp.write_all(MAGIC)?;
+            let mut batch = WriteBatch::new();
+            let mut batch_size = 0;
+            for key in self.index.keys() {
+                let value = self.read_value(key)?.expect("indexed key is gone");
+                batch_size += entry_len(key, value.len());
+                batch.insert(key, &value);
+                if batch_size >= MAX_COMPACT_RECORD {
+                    write_compacted(&mut tmp, &mut new_len, &batch, &mut new_index)?;
+                    batch = WriteBatch::new();
+                    batch_size = 0;
+                }
+            }
+            if !batch.is_empty() {
+                write_compacted(&mut tmp, &mut new_len, &batch, &mut new_index)?;
+            }
+            tmp.into_inner()?.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+        sync_parent_dir(&self.path)?;
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.live_bytes = new_index
+            .iter()
+            .map(|(key, (_, length))| entry_len(key, *length as usize))
+            .sum();
+        self.index = new_index;
+        *self.file.get_mut() = file;
+        self.file_len = new_len;
+        log::debug!("compacted {:?} down to {} bytes", self.path, self.file_len);
+        Ok(())
+    }
+
+    /// Whether enough of the log is overwritten or removed values to be worth compacting.
+    fn needs_compaction(&self) -> bool {
+        self.file_len > MIN_COMPACT_SIZE && self.live_bytes * 2 < self.file_len
+    }
+
+    /// Appends a batch to the log, updating the index.
+    fn append(&mut self, batch: &WriteBatch) -> io::Result<()> {
+        let (record, _) = encode_record(batch)?;
+        let record_start = self.file_len;
+        {
+            let file = self.file.get_mut();
+            file.seek(SeekFrom::Start(record_start))?;
+            file.write_all(&record)?;
+            if self.sync {
+                file.sync_data()?;
+            }
+        }
+        self.file_len += record.len() as u64;
+        self.index_payload(
+            &record[RECORD_HEADER_LEN..],
+            record_start + RECORD_HEADER_LEN as u64,
+        );
+        if self.needs_compaction() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Applies the operations in a record's payload, which starts at the given file offset, to the index.
+    fn index_payload(&mut self, payload: &[u8], payload_offset: u64) {
+        for (key, value) in decode_payload(payload) {
+            let old = match value {
+                Some((offset, length)) => self
+                    .index
+                    .insert(key.to_vec(), (payload_offset + offset, length)),
+                None => self.index.remove(key),
+            };
+            if let Some((_, length)) = old {
+                self.live_bytes -= entry_len(key, length as usize);
+            }
+            if let Some((_, length)) = value {
+                self.live_bytes += entry_len(key, length as usize);
+            }
+        }
+    }
+
+    fn read_value(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let (offset, length) = match self.index.get(key) {
+            Some(v) => *v,
+            None => return Ok(None),
+        };
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0u8; length as usize];
+        file.read_exact(&mut buf)?;
+        Ok(Some(buf))
+    }
+}
+
+impl DbBackend for LogDb {
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        let previous = self.get(key);
+        let mut batch = WriteBatch::new();
+        batch.insert(key, value);
+        self.write_batch(batch);
+        previous
+    }
+    fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let previous = self.get(key);
+        let mut batch = WriteBatch::new();
+        batch.remove(key);
+        self.write_batch(batch);
+        previous
+    }
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.read_value(key).expect("cannot read from blkdb log")
+    }
+    fn key_range(&self, start: &[u8], end: &[u8]) -> Vec<Vec<u8>> {
+        self.index
+            .range(start.to_vec()..=end.to_vec())
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+    fn write_batch(&mut self, batch: WriteBatch) {
+        self.append(&batch).expect("cannot write to blkdb log")
+    }
+}
+
+// Record format: u32 payload length || 8-byte checksum || payload.
+// Payload format: a sequence of (u8 op || u32 key length || key [|| u32 value length || value]).
+const RECORD_HEADER_LEN: usize = 12;
+
+/// How many payload bytes it takes to store the given key-value pair.
+fn entry_len(key: &[u8], value_len: usize) -> u64 {
+    (1 + 4 + key.len() + 4 + value_len) as u64
+}
+
+/// Encodes a length as a u32, failing rather than silently wrapping around.
+fn encode_len(len: usize) -> io::Result<[u8; 4]> {
+    let len = u32::try_from(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} bytes is too long for a blkdb log", len),
+        )
+    })?;
+    Ok(len.to_be_bytes())
+}
+
+/// Encodes a batch into a record, also returning the offset (relative to the payload start) and length of every inserted value.
+fn encode_record(batch: &WriteBatch) -> io::Result<(Vec<u8>, Vec<ValueLocation>)> {
+    let mut payload = Vec::new();
+    let mut value_offsets = Vec::new();
+    for (key, value) in batch.iter() {
+        match value {
+            Some(value) => {
+                payload.push(OP_INSERT);
+                payload.extend_from_slice(&encode_len(key.len())?);
+                payload.extend_from_slice(key);
+                payload.extend_from_slice(&encode_len(value.len())?);
+                value_offsets.push((payload.len() as u64, value.len() as u32));
+                payload.extend_from_slice(value);
+            }
+            None => {
+                payload.push(OP_REMOVE);
+                payload.extend_from_slice(&encode_len(key.len())?);
+                payload.extend_from_slice(key);
+            }
+        }
+    }
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&encode_len(payload.len())?);
+    record.extend_from_slice(&checksum(&payload));
+    record.extend_from_slice(&payload);
+    Ok((record, value_offsets))
+}
+
+/// Writes a batch of live values as one record of a compacted log, at the given offset, and indexes the values.
+fn write_compacted(
+    out: &mut impl Write,
+    offset: &mut u64,
+    batch: &WriteBatch,
+    index: &mut BTreeMap<Vec<u8>, ValueLocation>,
+) -> io::Result<()> {
+    let (record, value_offsets) = encode_record(batch)?;
+    out.write_all(&record)?;
+    let payload_start = *offset + RECORD_HEADER_LEN as u64;
+    for ((key, _), (value_offset, length)) in batch.iter().zip(value_offsets) {
+        index.insert(key.to_vec(), (payload_start + value_offset, length));
+    }
+    *offset += record.len() as u64;
+    Ok(())
+}
+
+/// Why a record could not be decoded.
+enum BadRecord {
+    /// The record runs past the end of the file.
+    Truncated,
+    /// The record fits in the file but fails its checksum. Contains the offset right after the record.
+    Corrupt(usize),
+}
+
+/// Decodes the record at the given offset, returning the payload and its offset.
+fn decode_record(contents: &[u8], offset: usize) -> Result<(usize, &[u8]), BadRecord> {
+    let header = contents
+        .get(offset..offset + RECORD_HEADER_LEN)
+        .ok_or(BadRecord::Truncated)?;
+    let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    let payload_start = offset + RECORD_HEADER_LEN;
+    let payload = contents
+        .get(payload_start..payload_start + length)
+        .ok_or(BadRecord::Truncated)?;
+    if header[4..] != checksum(payload) {
+        return Err(BadRecord::Corrupt(payload_start + length));
+    }
+    Ok((payload_start, payload))
+}
+
+/// Syncs the directory containing the given path, so that creating or renaming a file there is durable.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Decodes a payload that already passed its checksum into (key, Some(value location relative to the payload)) pairs, or (key, None) for removals.
+fn decode_payload(payload: &[u8]) -> Vec<(&[u8], Option<ValueLocation>)> {
+    let mut toret = Vec::new();
+    let mut ptr = 0;
+    let read_len = |ptr: &mut usize| {
+        let len = u32::from_be_bytes(payload[*ptr..*ptr + 4].try_into().unwrap());
+        *ptr += 4;
+        len
+    };
+    while ptr < payload.len() {
+        let op = payload[ptr];
+        ptr += 1;
+        let key_len = read_len(&mut ptr) as usize;
+        let key = &payload[ptr..ptr + key_len];
+        ptr += key_len;
+        if op == OP_INSERT {
+            let value_len = read_len(&mut ptr);
+            toret.push((key, Some((ptr as u64, value_len))));
+            ptr += value_len as usize;
+        } else {
+            toret.push((key, None));
+        }
+    }
+    toret
+}
+
+fn checksum(payload: &[u8]) -> [u8; 8] {
+    tmelcrypt::hash_single(payload).0[..8].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use themelio_stf::{GenesisConfig, ProposerAction, State};
+    use tmelcrypt::HashVal;
+
+    use super::*;
+    use crate::BlockTree;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("blkdb-logdb-test-{}", fastrand::u64(..)))
+    }
+
+    #[test]
+    fn persistence_and_recovery() {
+        let path = temp_path();
+        {
+            let mut db = LogDb::open(&path).unwrap();
+            db.insert(b"hello", b"world");
+            db.insert(b"goodbye", b"world");
+            let mut batch = WriteBatch::new();
+            batch.insert(b"foo", b"bar");
+            batch.remove(b"goodbye");
+            db.write_batch(batch);
+        }
+        // simulate a torn write at the end of the log
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[0, 0, 0, 42, 1, 2, 3]).unwrap();
+        }
+        {
+            let mut db = LogDb::open(&path).unwrap();
+            assert_eq!(db.get(b"hello"), Some(b"world".to_vec()));
+            assert_eq!(db.get(b"goodbye"), None);
+            assert_eq!(db.get(b"foo"), Some(b"bar".to_vec()));
+            assert_eq!(db.key_range(b"a", b"z").len(), 2);
+            db.insert(b"hello", b"again");
+            db.compact().unwrap();
+            assert_eq!(db.get(b"hello"), Some(b"again".to_vec()));
+        }
+        let db = LogDb::open(&path).unwrap();
+        assert_eq!(db.get(b"hello"), Some(b"again".to_vec()));
+        assert_eq!(db.get(b"foo"), Some(b"bar".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn small_values_compaction() {
+        let path = temp_path();
+        let mut db = LogDb::open(&path).unwrap();
+        db.set_sync(false);
+        // lots of live entries whose keys and framing outweigh their values
+        for i in 0..500u64 {
+            let mut batch = WriteBatch::new();
+            for j in 0..100u64 {
+                batch.insert(&(i * 100 + j).to_be_bytes(), &[1; 8]);
+            }
+            db.write_batch(batch);
+        }
+        assert!(db.file_len > MIN_COMPACT_SIZE);
+        assert!(!db.needs_compaction());
+        db.compact().unwrap();
+        assert!(!db.needs_compaction());
+        // overwriting the same small value over and over does make compaction worthwhile, but only once in a while
+        let mut compactions = 0;
+        for i in 0..50000u64 {
+            let before = db.file_len;
+            db.insert(b"counter", &i.to_be_bytes());
+            if db.file_len < before {
+                compactions += 1;
+            }
+        }
+        assert!(compactions > 0);
+        assert!(compactions < 5);
+        drop(db);
+        // the compacted log is made of several records, all of which must be readable
+        let db = LogDb::open(&path).unwrap();
+        assert_eq!(db.key_range(&[0; 8], &[0xff; 8]).len(), 50001);
+        assert_eq!(db.get(&49999u64.to_be_bytes()), Some(vec![1; 8]));
+        assert_eq!(db.get(b"counter"), Some(49999u64.to_be_bytes().to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corruption_before_the_end() {
+        let path = temp_path();
+        {
+            let mut db = LogDb::open(&path).unwrap();
+            db.insert(b"hello", b"world");
+            db.insert(b"goodbye", b"world");
+        }
+        let contents = std::fs::read(&path).unwrap();
+        let first_record_end = MAGIC.len() + RECORD_HEADER_LEN + entry_len(b"hello", 5) as usize;
+
+        // a garbled last record is a torn write, which is discarded
+        let mut torn = contents.clone();
+        *torn.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path, &torn).unwrap();
+        let db = LogDb::open(&path).unwrap();
+        assert_eq!(db.get(b"hello"), Some(b"world".to_vec()));
+        assert_eq!(db.get(b"goodbye"), None);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            first_record_end as u64
+        );
+        drop(db);
+
+        // but a garbled record followed by more records can't be a torn write, so we refuse to throw them away
+        let mut corrupt = contents.clone();
+        corrupt[first_record_end - 1] ^= 0xff;
+        std::fs::write(&path, &corrupt).unwrap();
+        assert_eq!(
+            LogDb::open(&path).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), corrupt);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Opens a BlockTree backed by a LogDb at the given path.
+    fn open_tree(path: &Path, forest: &novasmt::Forest) -> BlockTree<LogDb> {
+        BlockTree::new(LogDb::open(path).unwrap(), forest.clone(), true)
+    }
+
+    /// Every block in the tree, with its metadata, in a canonical order.
+    fn tree_contents(tree: &BlockTree<LogDb>) -> Vec<(HashVal, Vec<u8>)> {
+        let mut toret = vec![];
+        let mut stack = tree.get_tips();
+        while let Some(top) = stack.pop() {
+            toret.push((top.header().hash(), top.metadata().to_vec()));
+            stack.extend(top.parent());
+        }
+        toret.sort_unstable();
+        toret.dedup();
+        toret
+    }
+
+    #[test]
+    fn block_tree_over_logdb() {
+        let path = temp_path();
+        let forest = novasmt::Forest::new(novasmt::InMemoryBackend::default());
+        let genesis = State::genesis(&forest, GenesisConfig::std_testnet()).seal(None);
+        let mut chain = vec![genesis.clone()];
+        let before = {
+            let mut tree = open_tree(&path, &forest);
+            tree.set_genesis(genesis, b"genesis");
+            for height in 1..=10u8 {
+                let next = chain.last().unwrap().next_state().seal(None);
+                tree.apply_block(&next.to_block(), &[height]).unwrap();
+                chain.push(next);
+            }
+            let fork = chain[5].next_state().seal(Some(ProposerAction {
+                fee_multiplier_delta: 0,
+                reward_dest: HashVal::default().into(),
+            }));
+            tree.apply_block(&fork.to_block(), b"fork").unwrap();
+            tree_contents(&tree)
+        };
+        assert_eq!(before.len(), 12);
+        {
+            let tree = open_tree(&path, &forest);
+            assert!(tree.verify().is_empty());
+            assert_eq!(tree_contents(&tree), before);
+            assert_eq!(tree.get_tips().len(), 2);
+        }
+
+        // tear the last block in half, as if we crashed while applying it
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 10)
+            .unwrap();
+        let tree = open_tree(&path, &forest);
+        assert!(tree.verify().is_empty());
+        let after = tree_contents(&tree);
+        assert_eq!(after.len(), 11);
+        assert!(after.iter().all(|block| before.contains(block)));
+        assert_eq!(tree.get_tips().len(), 1);
+        assert_eq!(tree.get_tips()[0].header(), chain.last().unwrap().header());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
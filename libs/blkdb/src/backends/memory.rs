@@ -1,6 +1,6 @@
 use std::collections::BTreeMap;
 
-use crate::traits::DbBackend;
+use crate::traits::{DbBackend, WriteBatch};
 
 /// An in-memory DbBackend.
 #[derive(Default, Debug)]
@@ -29,4 +29,12 @@ impl DbBackend for InMemoryDb {
             })
             .collect()
     }
+    fn write_batch(&mut self, batch: WriteBatch) {
+        for (key, value) in batch {
+            match value {
+                Some(value) => self.inner.insert(key, value),
+                None => self.inner.remove(&key),
+            };
+        }
+    }
 }
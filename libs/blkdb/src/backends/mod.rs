@@ -1,2 +1,7 @@
 mod memory;
 pub use memory::*;
+
+#[cfg(feature = "logdb")]
+mod log;
+#[cfg(feature = "logdb")]
+pub use self::log::*;
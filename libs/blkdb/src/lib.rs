@@ -5,9 +5,23 @@ pub use tree::*;
 
 #[cfg(test)]
 mod tests {
-    use themelio_stf::{GenesisConfig, State};
+    use std::{
+        collections::HashSet,
+        panic::AssertUnwindSafe,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
 
-    use crate::{backends::InMemoryDb, BlockTree};
+    use parking_lot::Mutex;
+    use themelio_stf::{GenesisConfig, ProposerAction, SealedState, State};
+
+    use crate::{
+        backends::InMemoryDb,
+        traits::{DbBackend, WriteBatch},
+        ApplyBlockErr, BlockTree, BlockTreeEvent,
+    };
 
     #[test]
     fn simple_test() {
@@ -27,4 +41,445 @@ mod tests {
         }
         eprintln!("{}", tree.debug_graphviz(|_| "gray".into()));
     }
+
+    /// A backend that "crashes" by panicking, before writing anything, once it runs out of write budget.
+    #[derive(Clone, Default)]
+    struct CrashingDb {
+        inner: Arc<Mutex<InMemoryDb>>,
+        budget: Arc<AtomicUsize>,
+    }
+
+    impl CrashingDb {
+        fn spend(&self) {
+            if self
+                .budget
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| v.checked_sub(1))
+                .is_err()
+            {
+                panic!("simulated crash")
+            }
+        }
+    }
+
+    impl DbBackend for CrashingDb {
+        fn insert(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+            self.spend();
+            self.inner.lock().insert(key, value)
+        }
+        fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+            self.spend();
+            self.inner.lock().remove(key)
+        }
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.inner.lock().get(key)
+        }
+        fn key_range(&self, start: &[u8], end: &[u8]) -> Vec<Vec<u8>> {
+            self.inner.lock().key_range(start, end)
+        }
+        fn write_batch(&mut self, batch: WriteBatch) {
+            self.spend();
+            self.inner.lock().write_batch(batch)
+        }
+    }
+
+    /// An SMT backend that never deletes anything, so that SMTs shared between pruned and surviving blocks stay around.
+    #[derive(Default)]
+    struct KeepAllSmt(novasmt::InMemoryBackend);
+
+    impl novasmt::BackendDB for KeepAllSmt {
+        fn set_batch(&self, kvv: &[(novasmt::Hashed, novasmt::BackendNode)]) {
+            self.0.set_batch(kvv)
+        }
+        fn get(&self, key: novasmt::Hashed) -> Option<novasmt::BackendNode> {
+            self.0.get(key)
+        }
+        fn delete_root(&self, _key: novasmt::Hashed) {}
+        fn delete_root_tomorrow(&self, _key: novasmt::Hashed) {}
+    }
+
+    /// Like [KeepAllSmt], but logs the roots that it's asked to delete, whether right away or once they're no longer open.
+    #[derive(Default)]
+    struct DeletionLogSmt {
+        inner: KeepAllSmt,
+        deleted: Arc<Mutex<Vec<novasmt::Hashed>>>,
+    }
+
+    impl novasmt::BackendDB for DeletionLogSmt {
+        fn set_batch(&self, kvv: &[(novasmt::Hashed, novasmt::BackendNode)]) {
+            self.inner.set_batch(kvv)
+        }
+        fn get(&self, key: novasmt::Hashed) -> Option<novasmt::BackendNode> {
+            self.inner.get(key)
+        }
+        fn delete_root(&self, key: novasmt::Hashed) {
+            self.deleted.lock().push(key)
+        }
+        fn delete_root_tomorrow(&self, key: novasmt::Hashed) {
+            self.deleted.lock().push(key)
+        }
+    }
+
+    /// Runs a workload exercising every kind of mutation: forks, metadata updates, tip deletion and genesis resets, both to a block in the tree and to one that isn't.
+    fn workload(tree: &mut BlockTree<CrashingDb>, genesis: &SealedState) {
+        tree.set_genesis(genesis.clone(), &[]);
+        let mut next_state = genesis.clone();
+        let mut new_genesis = None;
+        for height in 1..=8 {
+            // fork off a sibling block that has a proposer action
+            let sibling = next_state.next_state().seal(Some(ProposerAction {
+                fee_multiplier_delta: 0,
+                reward_dest: tmelcrypt::HashVal::default().into(),
+            }));
+            tree.apply_block(&sibling.to_block(), b"sibling").unwrap();
+            next_state = next_state.next_state().seal(None);
+            tree.apply_block(&next_state.to_block(), &[]).unwrap();
+            tree.get_cursor_mut(next_state.header().hash())
+                .unwrap()
+                .set_metadata(&[height as u8]);
+            if height == 4 {
+                new_genesis = Some(next_state.clone());
+            }
+        }
+        tree.delete_tips();
+        tree.set_genesis(new_genesis.unwrap(), &[]);
+        // a checkpoint that isn't in the tree yet replaces the whole history, like after a state sync
+        let mut checkpoint = next_state;
+        for _ in 0..3 {
+            checkpoint = checkpoint.next_state().seal(None);
+        }
+        tree.set_genesis(checkpoint, b"checkpoint");
+    }
+
+    /// Checks that the tree is internally consistent, panicking otherwise.
+    fn assert_consistent(tree: &BlockTree<CrashingDb>) {
+        let tips = tree
+            .get_tips()
+            .iter()
+            .map(|v| v.header().hash())
+            .collect::<HashSet<_>>();
+        let mut roots = HashSet::new();
+        for tip in tree.get_tips() {
+            assert!(tip.children().is_empty(), "tip has children");
+            let mut cursor = tip;
+            while let Some(parent) = cursor.parent() {
+                assert!(parent
+                    .children()
+                    .iter()
+                    .any(|v| v.header() == cursor.header()));
+                cursor = parent;
+            }
+            roots.insert(cursor.header());
+            // walk down from the root, checking every edge
+            let mut stack = vec![cursor];
+            while let Some(top) = stack.pop() {
+                assert!(tree
+                    .get_at_height(top.header().height)
+                    .iter()
+                    .any(|v| v.header() == top.header()));
+                let children = top.children();
+                if children.is_empty() {
+                    assert!(tips.contains(&top.header().hash()), "leaf is not a tip");
+                }
+                for child in children {
+                    assert_eq!(child.parent().unwrap().header(), top.header());
+                    stack.push(child);
+                }
+            }
+        }
+        assert!(roots.len() <= 1, "tree has {} roots", roots.len());
+    }
+
+    #[test]
+    fn crash_consistency() {
+        let forest = novasmt::Forest::new(KeepAllSmt::default());
+        let genesis = State::genesis(&forest, GenesisConfig::std_testnet()).seal(None);
+        for crash_point in 0.. {
+            let backend = CrashingDb::default();
+            backend.budget.store(crash_point, Ordering::SeqCst);
+            let crashed = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut tree = BlockTree::new(backend.clone(), forest.clone(), true);
+                workload(&mut tree, &genesis);
+            }))
+            .is_err();
+            // "reboot" the database, which must be consistent no matter where we crashed
+            backend.budget.store(usize::MAX, Ordering::SeqCst);
+            let tree = BlockTree::new(backend, forest.clone(), true);
+            assert_consistent(&tree);
+            assert!(tree.verify().is_empty());
+            if !crashed {
+                assert!(crash_point > 0);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn verify_and_repair() {
+        let forest = novasmt::Forest::new(KeepAllSmt::default());
+        let genesis = State::genesis(&forest, GenesisConfig::std_testnet()).seal(None);
+        let backend = CrashingDb::default();
+        backend.budget.store(usize::MAX, Ordering::SeqCst);
+        let mut tree = BlockTree::new(backend.clone(), forest.clone(), true);
+        tree.set_genesis(genesis.clone(), &[]);
+        let mut next_state = genesis;
+        for _ in 0..5 {
+            next_state = next_state.next_state().seal(None);
+            tree.apply_block(&next_state.to_block(), &[]).unwrap();
+        }
+        assert!(tree.verify().is_empty());
+
+        // corrupt the database behind the tree's back: drop the tip block, and add a garbage tip
+        {
+            let mut raw = backend.inner.lock();
+            let tip_height = next_state.header().height;
+            let keys = raw.key_range(&[0x00; 40], &[0xff; 40]);
+            let tip_block_key = keys
+                .iter()
+                .find(|k| k[..8] == tip_height.to_be_bytes())
+                .unwrap()
+                .clone();
+            raw.remove(&tip_block_key);
+            let mut bogus_tip = (u64::MAX - 1).to_be_bytes().to_vec();
+            bogus_tip.extend_from_slice(&[0x42; 32]);
+            raw.insert(&bogus_tip, &stdcode::serialize(&1u64).unwrap());
+        }
+        let issues = tree.verify();
+        assert!(!issues.is_empty());
+        assert!(issues.iter().all(|v| v.is_repairable()));
+
+        let report = tree.repair();
+        assert!(report.remaining.is_empty());
+        assert!(report.repaired.len() >= issues.len());
+        assert!(tree.verify().is_empty());
+        assert_eq!(tree.get_tips().len(), 1);
+        assert_eq!(
+            tree.get_tips()[0].header().height,
+            next_state.header().height - 1
+        );
+    }
+
+    #[test]
+    fn refcount_migration() {
+        let smt = DeletionLogSmt::default();
+        let deleted = smt.deleted.clone();
+        let forest = novasmt::Forest::new(smt);
+        let genesis = State::genesis(&forest, GenesisConfig::std_testnet()).seal(None);
+        let backend = CrashingDb::default();
+        backend.budget.store(usize::MAX, Ordering::SeqCst);
+        let mut tree = BlockTree::new(backend.clone(), forest.clone(), true);
+        tree.set_genesis(genesis.clone(), &[]);
+        let mut next_state = genesis;
+        for _ in 0..5 {
+            next_state = next_state.next_state().seal(None);
+            tree.apply_block(&next_state.to_block(), &[]).unwrap();
+        }
+        let header = next_state.header();
+        drop(tree);
+
+        // strip the reference counts, like in a database written before they existed
+        {
+            let mut raw = backend.inner.lock();
+            let mut start = (u64::MAX - 4).to_be_bytes().to_vec();
+            start.extend_from_slice(&[0x00; 32]);
+            let mut end = (u64::MAX - 3).to_be_bytes().to_vec();
+            end.extend_from_slice(&[0xff; 32]);
+            let keys = raw.key_range(&start, &end);
+            assert!(!keys.is_empty());
+            for key in keys {
+                raw.remove(&key);
+            }
+        }
+        let mut tree = BlockTree::new(backend, forest, true);
+        assert!(tree.verify().is_empty());
+        assert_eq!(tree.prune_states(5), 5);
+        assert!(tree.verify().is_empty());
+        for root in [
+            header.coins_hash,
+            header.transactions_hash,
+            header.pools_hash,
+            header.stakes_hash,
+        ] {
+            assert!(!deleted.lock().contains(&root.0));
+        }
+    }
+
+    #[test]
+    fn events() {
+        let forest = novasmt::Forest::new(novasmt::InMemoryBackend::default());
+        let genesis = State::genesis(&forest, GenesisConfig::std_testnet()).seal(None);
+        let mut tree = BlockTree::new(InMemoryDb::default(), forest, false);
+        tree.set_genesis(genesis.clone(), &[]);
+        let events = tree.subscribe();
+
+        let next_state = genesis.next_state().seal(None);
+        tree.apply_block(&next_state.to_block(), &[]).unwrap();
+        assert!(
+            matches!(events.try_recv(), Ok(BlockTreeEvent::BlockInserted(h)) if h == next_state.header())
+        );
+        assert!(
+            matches!(events.try_recv(), Ok(BlockTreeEvent::TipChanged(tips)) if tips == vec![next_state.header()])
+        );
+
+        tree.get_cursor_mut(next_state.header().hash())
+            .unwrap()
+            .set_metadata(b"hello");
+        assert!(
+            matches!(events.try_recv(), Ok(BlockTreeEvent::MetadataUpdated(h)) if h == next_state.header())
+        );
+        assert!(events.try_recv().is_err());
+
+        tree.delete_tips();
+        assert!(
+            matches!(events.try_recv(), Ok(BlockTreeEvent::BranchPruned(pruned)) if pruned == vec![next_state.header()])
+        );
+        assert!(
+            matches!(events.try_recv(), Ok(BlockTreeEvent::TipChanged(tips)) if tips == vec![genesis.header()])
+        );
+    }
+
+    #[test]
+    fn ancestry() {
+        let forest = novasmt::Forest::new(novasmt::InMemoryBackend::default());
+        let genesis = State::genesis(&forest, GenesisConfig::std_testnet()).seal(None);
+        let mut tree = BlockTree::new(InMemoryDb::default(), forest, false);
+        tree.set_genesis(genesis.clone(), &[]);
+        let mut main_chain = vec![genesis.clone()];
+        for _ in 0..40 {
+            let next = main_chain.last().unwrap().next_state().seal(None);
+            tree.apply_block(&next.to_block(), &[]).unwrap();
+            main_chain.push(next);
+        }
+        // a fork off height 20
+        let mut fork = main_chain[20].clone();
+        for _ in 0..5 {
+            fork = fork.next_state().seal(Some(ProposerAction {
+                fee_multiplier_delta: 0,
+                reward_dest: tmelcrypt::HashVal::default().into(),
+            }));
+            tree.apply_block(&fork.to_block(), &[]).unwrap();
+        }
+
+        let main_tip = main_chain.last().unwrap().header().hash();
+        for (height, state) in main_chain.iter().enumerate() {
+            let ancestor = tree.ancestor_at_height(main_tip, height as u64).unwrap();
+            assert_eq!(ancestor.header(), state.header());
+            assert!(tree.is_ancestor_of(state.header().hash(), main_tip));
+            assert_eq!(
+                tree.is_ancestor_of(state.header().hash(), fork.header().hash()),
+                height <= 20
+            );
+        }
+        assert!(tree.ancestor_at_height(main_tip, 41).is_none());
+
+        let lca = tree
+            .lowest_common_ancestor(main_tip, fork.header().hash())
+            .unwrap();
+        assert_eq!(lca.header(), main_chain[20].header());
+
+        let path = tree.path_between(fork.header().hash(), main_tip).unwrap();
+        assert_eq!(path.len(), 5 + 1 + 20);
+        assert_eq!(path[0], fork.header().hash());
+        assert_eq!(path[5], main_chain[20].header().hash());
+        assert_eq!(*path.last().unwrap(), main_tip);
+    }
+
+    #[test]
+    fn state_cache() {
+        let forest = novasmt::Forest::new(KeepAllSmt::default());
+        let genesis = State::genesis(&forest, GenesisConfig::std_testnet()).seal(None);
+        let mut tree = BlockTree::new(InMemoryDb::default(), forest, true);
+        tree.set_cache_capacity(4);
+        tree.set_genesis(genesis.clone(), &[]);
+        let mut chain = vec![genesis];
+        for _ in 0..10 {
+            let next = chain.last().unwrap().next_state().seal(None);
+            tree.apply_block(&next.to_block(), &[]).unwrap();
+            chain.push(next);
+        }
+        let stats = tree.cache_stats();
+        assert_eq!(stats.capacity, 4);
+        assert!(stats.len <= 4);
+
+        // the state of the tip is decoded once, and then served from the cache
+        let tip = chain.last().unwrap().header().hash();
+        let before = tree.cache_stats();
+        let state = tree.get_cursor(tip).unwrap().to_state();
+        assert_eq!(state.header(), chain.last().unwrap().header());
+        tree.get_cursor(tip).unwrap().to_state();
+        let after = tree.cache_stats();
+        assert_eq!(after.hits + after.misses, before.hits + before.misses + 2);
+        assert!(after.hits > before.hits);
+
+        // walking the whole chain must evict, but never grow past capacity
+        for state in chain.iter() {
+            let cursor = tree.get_cursor(state.header().hash()).unwrap();
+            assert_eq!(cursor.to_state().header(), state.header());
+        }
+        let stats = tree.cache_stats();
+        assert!(stats.evictions > 0);
+        assert_eq!(stats.len, 4);
+
+        tree.set_cache_capacity(2);
+        let stats = tree.cache_stats();
+        assert_eq!((stats.len, stats.capacity), (2, 2));
+    }
+
+    #[test]
+    fn prune_states() {
+        let smt = DeletionLogSmt::default();
+        let deleted = smt.deleted.clone();
+        let forest = novasmt::Forest::new(smt);
+        let genesis = State::genesis(&forest, GenesisConfig::std_testnet()).seal(None);
+        let mut tree = BlockTree::new(InMemoryDb::default(), forest, true);
+        tree.set_genesis(genesis.clone(), &[]);
+        let mut chain = vec![genesis];
+        for _ in 0..10 {
+            let next = chain.last().unwrap().next_state().seal(None);
+            tree.apply_block(&next.to_block(), &[]).unwrap();
+            chain.push(next);
+        }
+        assert_eq!(tree.prune_states(6), 6);
+        // nothing is left to prune the second time
+        assert_eq!(tree.prune_states(6), 0);
+        for state in chain.iter() {
+            let cursor = tree.get_cursor(state.header().hash()).unwrap();
+            assert_eq!(cursor.header(), state.header());
+            assert_eq!(cursor.is_pruned(), state.header().height < 6);
+            if !cursor.is_pruned() {
+                assert_eq!(cursor.to_state().header(), state.header());
+            }
+        }
+        assert!(tree.verify().is_empty());
+
+        // empty blocks share most of their SMTs, which must survive as long as a retained state uses them
+        let deleted = deleted.lock().clone();
+        for state in chain[6..].iter() {
+            let header = state.header();
+            for root in [
+                header.coins_hash,
+                header.transactions_hash,
+                header.pools_hash,
+                header.stakes_hash,
+                header.history_hash,
+            ] {
+                assert!(!deleted.contains(&root.0));
+            }
+        }
+        for state in chain[1..6].iter() {
+            assert!(deleted.contains(&state.header().history_hash.0));
+        }
+
+        // pruned states can't be built upon
+        let fork = chain[3].next_state().seal(Some(ProposerAction {
+            fee_multiplier_delta: 0,
+            reward_dest: tmelcrypt::HashVal::default().into(),
+        }));
+        assert!(matches!(
+            tree.apply_block(&fork.to_block(), &[]),
+            Err(ApplyBlockErr::ParentPruned(_))
+        ));
+        let next = chain.last().unwrap().next_state().seal(None);
+        tree.apply_block(&next.to_block(), &[]).unwrap();
+        assert_eq!(tree.prune_states(7), 1);
+    }
 }
@@ -8,4 +8,55 @@ pub trait DbBackend: Send + Sync + 'static {
     fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
     /// Iterates over a range of keys, returning a vector of actually existing keys.
     fn key_range(&self, start: &[u8], end: &[u8]) -> Vec<Vec<u8>>;
+    /// Atomically applies a batch of writes, in order. After a crash, either all or none of the writes must be visible.
+    fn write_batch(&mut self, batch: WriteBatch);
+}
+
+/// A batch of insertions and removals, applied atomically through [DbBackend::write_batch].
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues the insertion of a key-value pair.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.ops.push((key.to_vec(), Some(value.to_vec())));
+    }
+
+    /// Queues the deletion of a key.
+    pub fn remove(&mut self, key: &[u8]) {
+        self.ops.push((key.to_vec(), None));
+    }
+
+    /// Returns the number of queued writes.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Iterates over the queued writes in order. A value of `None` denotes a removal.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.ops
+            .iter()
+            .map(|(k, v)| (k.as_slice(), v.as_ref().map(|v| v.as_slice())))
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = (Vec<u8>, Option<Vec<u8>>);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
 }
@@ -1,7 +1,11 @@
-use crate::traits::DbBackend;
-use dashmap::DashMap;
+use crate::traits::{DbBackend, WriteBatch};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Write};
+use smol::channel::Sender;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
 use std::{
     collections::{BTreeSet, HashSet},
     convert::TryInto,
@@ -10,6 +14,18 @@ use themelio_stf::{Block, Header, ProposerAction, SealedState};
 use thiserror::Error;
 use tmelcrypt::HashVal;
 
+mod ancestry;
+mod cache;
+mod events;
+mod prune;
+mod verify;
+use ancestry::skip_height;
+use cache::StateCache;
+pub use cache::{CacheStats, DEFAULT_CACHE_CAPACITY};
+pub use events::*;
+use prune::smt_roots;
+pub use verify::*;
+
 /// A block tree, stored on a particular backend.
 pub struct BlockTree<B: DbBackend> {
     inner: Inner<B>,
@@ -20,18 +36,34 @@ pub struct BlockTree<B: DbBackend> {
 impl<B: DbBackend> BlockTree<B> {
     /// Create a new BlockTree.
     pub fn new(backend: B, forest: novasmt::Forest, canonical: bool) -> Self {
+        let mut toret = Self::open_for_repair(backend, forest, canonical);
+        toret.initial_refcounts();
+        toret.initial_tip_cleanup();
+        toret
+    }
+
+    /// Opens a BlockTree without performing any startup cleanup, which may panic on a corrupt database. Only [BlockTree::verify] and [BlockTree::repair] are guaranteed to work on such a tree.
+    pub fn open_for_repair(backend: B, forest: novasmt::Forest, canonical: bool) -> Self {
         let inner = Inner {
             backend,
             canonical,
-            cache: Default::default(),
+            cache: StateCache::new(canonical, DEFAULT_CACHE_CAPACITY),
+            subscribers: Default::default(),
         };
-        let mut toret = Self {
+        Self {
             inner,
             forest,
             canonical,
-        };
-        toret.initial_tip_cleanup();
-        toret
+        }
+    }
+
+    /// Initial migration: count the SMT references of databases written before reference counts existed.
+    fn initial_refcounts(&mut self) {
+        if self.inner.view().has_refcounts() {
+            return;
+        }
+        log::warn!("counting SMT references for the first time");
+        self.inner.transact(|txn| txn.rebuild_refcounts());
     }
 
     /// Initial cleanup: delete tips that are ancestors of other tips.
@@ -50,9 +82,7 @@ impl<B: DbBackend> BlockTree<B> {
                 to_delete.push(tip.header().hash())
             }
         }
-        for to_delete in to_delete {
-            self.inner.tip_remove(to_delete);
-        }
+        self.inner.remove_tips(&to_delete);
     }
 
     /// Attempts to apply a block.
@@ -68,6 +98,9 @@ impl<B: DbBackend> BlockTree<B> {
                 Some(block.header.height.saturating_sub(1)),
             )
             .ok_or(ApplyBlockErr::ParentNotFound(block.header.previous))?;
+        if previous.is_pruned() {
+            return Err(ApplyBlockErr::ParentPruned(block.header.previous));
+        }
         let previous = previous.to_state(&self.forest, &self.inner.cache);
         let next_state = previous
             .apply_block(block)
@@ -124,62 +157,92 @@ impl<B: DbBackend> BlockTree<B> {
             .collect()
     }
 
-    /// Sets the genesis block of the tree. This also prunes all elements that do not belong to the given genesis block.
+    /// Sets how many SealedStates the cache keeps in canonical mode. Non-canonical trees keep every state of every block in the tree, regardless of this setting.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.inner.cache.set_capacity(capacity)
+    }
+
+    /// Gets statistics about the state cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.inner.cache.stats()
+    }
+
+    /// Sets the genesis block of the tree. This also prunes all elements that do not belong to the given genesis block. Inserting the genesis and removing everything else is one atomic write, so a crash can't leave the tree with two roots.
     pub fn set_genesis(&mut self, state: SealedState, init_metadata: &[u8]) {
         let state_hash = state.header().hash();
-        if self.get_cursor(state.header().hash()).is_none() {
-            self.inner.insert_block(state, init_metadata);
-        }
-
-        let old_genesis = self.get_tips().into_iter().next().map(|v| {
-            let mut v = v;
-            while let Some(parent) = v.parent() {
-                v = parent;
+        let inserted = if self.inner.get_block(state_hash, None).is_none() {
+            Some(self.inner.stabilize(state, init_metadata))
+        } else {
+            None
+        };
+        let (removed, live_roots) = self.inner.transact(|txn| {
+            if let Some((_, value)) = &inserted {
+                txn.insert_block(value);
             }
-            v
-        });
-
-        // remove all non-descendants
-        let mut descendants = HashSet::new();
-        {
-            let mut stack: Vec<Cursor<_>> = vec![self
-                .get_cursor(state_hash)
-                .expect("just-set genesis is gone?!")];
+            // the genesis and its descendants stay, along with their SMTs
+            let mut live = HashSet::new();
+            let mut live_roots = HashSet::new();
+            let mut stack = vec![state_hash];
             while let Some(top) = stack.pop() {
-                descendants.insert(top.header().hash());
-                for child in top.children() {
-                    stack.push(child);
-                }
+                let block = txn.get_block(top, None).expect("dangling child pointer");
+                live.insert(top);
+                live_roots.extend(smt_roots(&block.header));
+                stack.extend(block.next.iter().copied());
             }
-        }
-        let mut todel = HashSet::new();
-        if let Some(old_genesis) = old_genesis {
-            if old_genesis.header().hash() != state_hash {
-                // use this cursor to traverse
-                let mut stack: Vec<Cursor<_>> = vec![old_genesis];
-                while let Some(top) = stack.pop() {
-                    if !descendants.contains(&top.header().hash()) {
-                        // this is a damned one!
-                        todel.insert(top.header());
-                        for child in top.children() {
-                            stack.push(child)
+            // everything else is reachable from the roots above the tips
+            let mut seen = HashSet::new();
+            let mut roots = Vec::new();
+            for tip in txn.all_tips() {
+                let mut block = txn.get_block(tip, None).expect("dangling tip");
+                loop {
+                    if !seen.insert(block.header.hash()) {
+                        break;
+                    }
+                    match txn.get_block(
+                        block.header.previous,
+                        Some(block.header.height.saturating_sub(1)),
+                    ) {
+                        Some(parent) => block = parent,
+                        None => {
+                            roots.push(block.header.hash());
+                            break;
                         }
                     }
                 }
             }
+            let mut removed = Vec::new();
+            let mut stack = roots;
+            while let Some(top) = stack.pop() {
+                if live.contains(&top) {
+                    continue;
+                }
+                let block = txn.get_block(top, None).expect("dangling child pointer");
+                stack.extend(block.next.iter().copied());
+                removed.push(block);
+            }
+            // parents go before their children
+            removed.sort_unstable_by_key(|block| block.header.height);
+            for block in removed.iter() {
+                txn.remove_orphan(block.header.hash(), Some(block.header.height));
+            }
+            (removed, live_roots)
+        });
+        if let Some((state, _)) = inserted {
+            self.inner.cache_inserted(state);
+        }
+        for block in removed.iter() {
+            self.inner.cache.remove(&block.header.hash());
         }
-        // okay now we go through the whole todel sequence
-        let mut todel = todel.into_iter().collect::<Vec<_>>();
-        todel.sort_unstable_by_key(|v| v.height);
-        for todel in todel {
-            self.inner.remove_orphan(todel.hash(), Some(todel.height));
-            if self.canonical {
-                // we also delete all the SMTs
-                self.forest.delete_tree(todel.coins_hash.0);
-                self.forest.delete_tree(todel.pools_hash.0);
-                self.forest.delete_tree(todel.stakes_hash.0);
-                self.forest.delete_tree(todel.transactions_hash.0);
-                self.forest.delete_tree(todel.history_hash.0);
+        if self.canonical {
+            // the new genesis often shares SMTs with the old history (e.g. the stakes, when nothing was staked), and those must stay around. Blocks whose states were pruned no longer have SMTs to delete.
+            let dead = removed
+                .iter()
+                .filter(|block| !block.is_pruned())
+                .flat_map(|block| smt_roots(&block.header))
+                .filter(|root| !live_roots.contains(root))
+                .collect::<HashSet<_>>();
+            for root in dead {
+                self.forest.delete_tree(root.0);
             }
         }
     }
@@ -191,9 +254,7 @@ impl<B: DbBackend> BlockTree<B> {
             .iter()
             .map(|v| v.header().hash())
             .collect::<Vec<_>>();
-        for tip in tips {
-            self.inner.remove_childless(tip, None);
-        }
+        self.inner.remove_childless(&tips);
     }
 
     /// Creates a GraphViz string that represents all the blocks in the tree.
@@ -260,7 +321,7 @@ impl<'a, B: DbBackend> Clone for Cursor<'a, B> {
 }
 
 impl<'a, B: DbBackend> Cursor<'a, B> {
-    /// Converts to a SealedState.
+    /// Converts to a SealedState. Panics if the state was pruned.
     pub fn to_state(&self) -> SealedState {
         self.internal
             .to_state(&self.tree.forest, &self.tree.inner.cache)
@@ -271,6 +332,11 @@ impl<'a, B: DbBackend> Cursor<'a, B> {
         self.internal.header
     }
 
+    /// Whether or not the state of this block was pruned by [BlockTree::prune_states].
+    pub fn is_pruned(&self) -> bool {
+        self.internal.is_pruned()
+    }
+
     /// Extracts the metadata.
     pub fn metadata(&self) -> &[u8] {
         &self.internal.metadata
@@ -298,7 +364,7 @@ pub struct CursorMut<'a, B: DbBackend> {
 }
 
 impl<'a, B: DbBackend> CursorMut<'a, B> {
-    /// Converts to a SealedState.
+    /// Converts to a SealedState. Panics if the state was pruned.
     pub fn to_state(&self) -> SealedState {
         self.internal
             .to_state(&self.tree.forest, &self.tree.inner.cache)
@@ -317,11 +383,7 @@ impl<'a, B: DbBackend> CursorMut<'a, B> {
     /// Sets the metadata.
     pub fn set_metadata(&mut self, metadata: &[u8]) {
         self.internal.metadata = metadata.to_vec();
-        self.tree.inner.internal_insert(
-            self.header().hash(),
-            self.header().height,
-            self.internal.clone(),
-        );
+        self.tree.inner.update_block(&self.internal);
     }
 
     /// Consumes and returns the parent of this block.
@@ -343,6 +405,8 @@ impl<'a, B: DbBackend> CursorMut<'a, B> {
 pub enum ApplyBlockErr {
     #[error("parent `{0}` not found")]
     ParentNotFound(HashVal),
+    #[error("state of parent `{0}` was pruned")]
+    ParentPruned(HashVal),
     #[error("validation error: `{0}`")]
     CannotValidate(themelio_stf::StateError),
     #[error("header mismatch")]
@@ -354,164 +418,332 @@ struct Inner<B: DbBackend> {
     backend: B,
     canonical: bool,
     // cached SealedStates. this is also required so that inserted blocks in non-canonical mode are persistent.
-    cache: DashMap<HashVal, SealedState>,
+    cache: StateCache,
+    subscribers: Mutex<Vec<Sender<BlockTreeEvent>>>,
 }
 
 impl<B: DbBackend> Inner<B> {
+    /// Starts a read-only view of the database.
+    fn view(&self) -> Txn<'_, B> {
+        Txn {
+            backend: &self.backend,
+            pending: BTreeMap::new(),
+            events: Vec::new(),
+            pruned: Vec::new(),
+        }
+    }
+
+    /// Runs the closure in a transaction, then atomically commits everything it wrote and notifies subscribers.
+    fn transact<T>(&mut self, f: impl FnOnce(&mut Txn<'_, B>) -> T) -> T {
+        let track_tips = self.has_subscribers();
+        let mut txn = Txn {
+            backend: &self.backend,
+            pending: BTreeMap::new(),
+            events: Vec::new(),
+            pruned: Vec::new(),
+        };
+        let old_tips = if track_tips { txn.all_tips() } else { vec![] };
+        let res = f(&mut txn);
+        let mut events = std::mem::take(&mut txn.events);
+        if !txn.pruned.is_empty() {
+            events.push(BlockTreeEvent::BranchPruned(std::mem::take(
+                &mut txn.pruned,
+            )));
+        }
+        if track_tips {
+            let new_tips = txn.all_tips();
+            if new_tips != old_tips {
+                events.push(BlockTreeEvent::TipChanged(
+                    new_tips
+                        .into_iter()
+                        .filter_map(|tip| Some(txn.get_block(tip, None)?.header))
+                        .collect(),
+                ));
+            }
+        }
+        let batch = txn.into_batch();
+        if !batch.is_empty() {
+            self.backend.write_batch(batch);
+        }
+        for event in events {
+            self.emit(event);
+        }
+        res
+    }
+
     /// Gets a block from the database.
     fn get_block(&self, blkhash: HashVal, height: Option<u64>) -> Option<InternalValue> {
-        self.maybe_gc_cache();
-        let height = match height {
-            Some(height) => height,
-            None => self.index_get(blkhash)?,
-        };
-        self.internal_get(blkhash, height)
+        self.view().get_block(blkhash, height)
     }
 
-    /// Removes a block with no parent.
-    fn remove_orphan(&mut self, blkhash: HashVal, height: Option<u64>) {
-        let current = self
-            .get_block(blkhash, height)
-            .expect("trying to remove nonexistent orphan");
-        debug_assert!(self.get_block(current.header.previous, None).is_none());
-        // remove from tips, index, then main
-        self.tip_remove(blkhash);
-        self.index_remove(blkhash);
-        self.internal_remove(blkhash, current.header.height);
+    /// Removes a set of blocks with no children.
+    fn remove_childless(&mut self, blocks: &[HashVal]) {
+        self.transact(|txn| {
+            for blkhash in blocks.iter().copied() {
+                txn.remove_childless(blkhash, None);
+            }
+        });
         // finally delete from cache
-        self.cache.remove(&blkhash);
+        for blkhash in blocks {
+            self.cache.remove(blkhash);
+        }
     }
 
-    /// Removes a block with no children.
-    fn remove_childless(&mut self, blkhash: HashVal, height: Option<u64>) {
-        let current = self
-            .get_block(blkhash, height)
-            .expect("trying to remove nonexistent childless");
-        // remove from tips, index, then main
-        self.tip_remove(blkhash);
-        self.tip_insert(current.header.previous, current.header.height - 1);
-        self.index_remove(blkhash);
-
-        let mut parent = self
-            .get_block(current.header.previous, Some(current.header.height - 1))
-            .unwrap();
-        parent.next.remove(&blkhash);
-        self.internal_insert(current.header.previous, parent.header.height, parent);
+    /// Removes a set of blocks from the tips list, without touching the blocks themselves.
+    fn remove_tips(&mut self, blocks: &[HashVal]) {
+        self.transact(|txn| {
+            for blkhash in blocks.iter().copied() {
+                txn.tip_remove(blkhash);
+            }
+        });
+    }
 
-        self.internal_remove(blkhash, current.header.height);
-        // finally delete from cache
-        self.cache.remove(&blkhash);
+    /// Overwrites an existing block.
+    fn update_block(&mut self, value: &InternalValue) {
+        self.transact(|txn| {
+            txn.internal_insert(value.header.hash(), value.header.height, value);
+            txn.events
+                .push(BlockTreeEvent::MetadataUpdated(value.header));
+        });
     }
 
     /// Inserts a block into the database
-    fn insert_block(
-        &mut self,
+    fn insert_block(&mut self, state: SealedState, init_metadata: &[u8]) {
+        let (state, value) = self.stabilize(state, init_metadata);
+        // the block, the parent pointer, the blkhash index and the tips list are all updated in one atomic batch
+        self.transact(|txn| txn.insert_block(&value));
+        self.cache_inserted(state);
+    }
+
+    /// Prepares a state for insertion. In canonical mode, this stabilizes its SMTs onto disk, which must happen before the block becomes visible, so that we never point to missing SMTs.
+    fn stabilize(
+        &self,
         mut state: SealedState,
         init_metadata: &[u8],
-    ) -> Option<InternalValue> {
-        // if let Some(val) = self.get_block(state.header().hash(), Some(state.inner_ref().height)) {
-        //     return Some(val);
-        // }
+    ) -> (SealedState, InternalValue) {
         let action = state.proposer_action().cloned();
-        // we carefully insert the block to avoid inconsistency:
-        // - first we insert the block itself
-        // - then we point the parent to it
-        // - then we insert into the blkhash index
-        // - then we update the tips list
-        let header = state.header();
-        let blkhash = header.hash();
-        // stabilize the block onto disk
         if self.canonical {
             state.save_smts();
         }
+        let value = InternalValue::from_state(&state, action, init_metadata.to_vec());
+        (state, value)
+    }
+
+    /// Caches the state of a block that was just inserted. This is what keeps the states of non-canonical trees around.
+    fn cache_inserted(&self, state: SealedState) {
+        if !self.canonical {
+            self.cache.insert(state.header().hash(), state);
+        }
+    }
+
+    fn all_tips(&self) -> Vec<HashVal> {
+        self.view().all_tips()
+    }
+
+    fn all_at_height(&self, height: u64) -> Vec<HashVal> {
+        self.view().all_at_height(height)
+    }
+}
+
+/// A set of uncommitted writes on top of a backend. Reads through a Txn observe its own pending writes.
+struct Txn<'a, B: DbBackend> {
+    backend: &'a B,
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // events to emit once the writes are committed
+    events: Vec<BlockTreeEvent>,
+    pruned: Vec<Header>,
+}
+
+impl<'a, B: DbBackend> Txn<'a, B> {
+    fn into_batch(self) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.pending {
+            match value {
+                Some(value) => batch.insert(&key, &value),
+                None => batch.remove(&key),
+            }
+        }
+        batch
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.pending.get(key) {
+            Some(value) => value.clone(),
+            None => self.backend.get(key),
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], value: Vec<u8>) {
+        self.pending.insert(key.to_vec(), Some(value));
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.pending.insert(key.to_vec(), None);
+    }
+
+    fn key_range(&self, start: &[u8], end: &[u8]) -> Vec<Vec<u8>> {
+        let mut keys: BTreeSet<Vec<u8>> = self.backend.key_range(start, end).into_iter().collect();
+        for (key, value) in self.pending.range(start.to_vec()..=end.to_vec()) {
+            if value.is_some() {
+                keys.insert(key.clone());
+            } else {
+                keys.remove(key);
+            }
+        }
+        keys.into_iter().collect()
+    }
+
+    /// Gets a block.
+    fn get_block(&self, blkhash: HashVal, height: Option<u64>) -> Option<InternalValue> {
+        let height = match height {
+            Some(height) => height,
+            None => self.index_get(blkhash)?,
+        };
+        self.internal_get(blkhash, height)
+    }
+
+    /// Inserts a block, hooking it up to its parent if that's in the tree.
+    fn insert_block(&mut self, value: &InternalValue) {
+        let header = value.header;
+        let blkhash = header.hash();
         // insert the block
-        self.internal_insert(
-            blkhash,
-            header.height,
-            InternalValue::from_state(&state, action, init_metadata.to_vec()),
-        );
+        self.internal_insert(blkhash, header.height, value);
         // insert into parent
         if let Some(mut parent) =
             self.get_block(header.previous, Some(header.height.saturating_sub(1)))
         {
             parent.next.insert(blkhash);
-            self.internal_insert(header.previous, parent.header.height, parent);
+            self.internal_insert(header.previous, parent.header.height, &parent);
         }
         // insert into blkhash index
         self.index_insert(blkhash, header.height);
         // update tips list
         self.tip_insert(blkhash, header.height);
         self.tip_remove(header.previous);
-        // update cache
-        if !self.canonical {
-            self.cache.insert(state.header().hash(), state);
-            self.maybe_gc_cache();
+        // add the skip pointer
+        if let Some(skip) = header.height.checked_sub(1).and_then(|parent_height| {
+            self.ancestor_at_height(header.previous, parent_height, skip_height(header.height))
+        }) {
+            self.jump_insert(blkhash, skip);
         }
-        None
+        self.events.push(BlockTreeEvent::BlockInserted(header));
     }
 
-    fn maybe_gc_cache(&self) {
-        if self.canonical && self.cache.len() > 10000 {
-            // in canonical mode we are sure that the stuff is all on disk, so it's safe to do this.
-            log::warn!("GC-ing the blkdb");
-            self.cache.clear()
+    /// Removes a block with no parent.
+    fn remove_orphan(&mut self, blkhash: HashVal, height: Option<u64>) {
+        let current = self
+            .get_block(blkhash, height)
+            .expect("trying to remove nonexistent orphan");
+        debug_assert!(self.get_block(current.header.previous, None).is_none());
+        self.pruned.push(current.header);
+        self.tip_remove(blkhash);
+        self.index_remove(blkhash);
+        self.jump_remove(blkhash);
+        self.internal_remove(blkhash, current.header.height);
+    }
+
+    /// Removes a block with no children.
+    fn remove_childless(&mut self, blkhash: HashVal, height: Option<u64>) {
+        let current = self
+            .get_block(blkhash, height)
+            .expect("trying to remove nonexistent childless");
+        self.pruned.push(current.header);
+        self.tip_remove(blkhash);
+        self.index_remove(blkhash);
+        self.jump_remove(blkhash);
+        if let Some(mut parent) =
+            self.get_block(current.header.previous, Some(current.header.height - 1))
+        {
+            parent.next.remove(&blkhash);
+            // the parent only becomes a tip if it has no other children
+            if parent.next.is_empty() {
+                self.tip_insert(current.header.previous, parent.header.height);
+            }
+            self.internal_insert(current.header.previous, parent.header.height, &parent);
         }
+        self.internal_remove(blkhash, current.header.height);
     }
 
-    fn internal_insert(&mut self, blkhash: HashVal, height: u64, value: InternalValue) {
-        self.backend.insert(
+    fn internal_insert(&mut self, blkhash: HashVal, height: u64, value: &InternalValue) {
+        let was_live = self
+            .internal_get_lenient(blkhash, height)
+            .map(|old| !old.is_pruned())
+            .unwrap_or_default();
+        self.refcount_update(&value.header, was_live, !value.is_pruned());
+        self.insert(
             &main_key(blkhash, height),
-            &stdcode::serialize(&value).unwrap(),
+            stdcode::serialize(value).unwrap(),
         );
     }
 
     fn index_insert(&mut self, blkhash: HashVal, height: u64) {
-        self.backend
-            .insert(&index_key(blkhash), &stdcode::serialize(&height).unwrap());
+        self.insert(&index_key(blkhash), stdcode::serialize(&height).unwrap());
     }
 
     fn tip_insert(&mut self, blkhash: HashVal, height: u64) {
-        self.backend
-            .insert(&tip_key(blkhash), &stdcode::serialize(&height).unwrap());
+        self.insert(&tip_key(blkhash), stdcode::serialize(&height).unwrap());
     }
 
     fn internal_get(&self, blkhash: HashVal, height: u64) -> Option<InternalValue> {
         Some(
-            stdcode::deserialize(&self.backend.get(&main_key(blkhash, height))?)
+            stdcode::deserialize(&self.get(&main_key(blkhash, height))?)
                 .expect("cannot deserialize internal value"),
         )
     }
 
+    /// Gets a block, treating an undecodable one as missing.
+    fn internal_get_lenient(&self, blkhash: HashVal, height: u64) -> Option<InternalValue> {
+        stdcode::deserialize(&self.get(&main_key(blkhash, height))?).ok()
+    }
+
     fn internal_remove(&mut self, blkhash: HashVal, height: u64) {
-        self.backend.remove(&main_key(blkhash, height));
+        if let Some(old) = self.internal_get_lenient(blkhash, height) {
+            self.refcount_update(&old.header, !old.is_pruned(), false);
+        }
+        self.remove(&main_key(blkhash, height));
     }
 
     fn index_get(&self, blkhash: HashVal) -> Option<u64> {
         Some(
-            stdcode::deserialize(&self.backend.get(&index_key(blkhash))?)
+            stdcode::deserialize(&self.get(&index_key(blkhash))?)
                 .expect("cannot deserialize index value"),
         )
     }
 
     fn index_remove(&mut self, blkhash: HashVal) {
-        self.backend.remove(&index_key(blkhash));
+        self.remove(&index_key(blkhash));
     }
 
     fn tip_remove(&mut self, blkhash: HashVal) {
-        self.backend.remove(&tip_key(blkhash));
+        self.remove(&tip_key(blkhash));
+    }
+
+    fn jump_insert(&mut self, blkhash: HashVal, skip: HashVal) {
+        self.insert(&jump_key(blkhash), skip.to_vec());
+    }
+
+    fn jump_get(&self, blkhash: HashVal) -> Option<HashVal> {
+        Some(HashVal(
+            self.get(&jump_key(blkhash))?
+                .as_slice()
+                .try_into()
+                .expect("corrupt jump value"),
+        ))
+    }
+
+    fn jump_remove(&mut self, blkhash: HashVal) {
+        self.remove(&jump_key(blkhash));
     }
 
     fn all_tips(&self) -> Vec<HashVal> {
-        let raw = self
-            .backend
-            .key_range(&tip_key(HashVal([0x00; 32])), &tip_key(HashVal([0xff; 32])));
+        let raw = self.key_range(&tip_key(HashVal([0x00; 32])), &tip_key(HashVal([0xff; 32])));
         raw.into_iter()
             .map(|v| HashVal((&v[8..]).try_into().expect("corrupt tip key")))
             .collect()
     }
 
     fn all_at_height(&self, height: u64) -> Vec<HashVal> {
-        let raw = self.backend.key_range(
+        let raw = self.key_range(
             &main_key(HashVal([0x00; 32]), height),
             &main_key(HashVal([0xff; 32]), height),
         );
@@ -524,6 +756,7 @@ impl<B: DbBackend> Inner<B> {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct InternalValue {
     header: Header,
+    // empty once the state is pruned
     partial_state: Vec<u8>,
     action: Option<ProposerAction>,
     next: BTreeSet<HashVal>,
@@ -541,21 +774,25 @@ impl InternalValue {
         }
     }
 
-    fn to_state(
-        &self,
-        forest: &novasmt::Forest,
-        cache: &DashMap<HashVal, SealedState>,
-    ) -> SealedState {
-        cache
-            .entry(self.header.hash())
-            .or_insert_with(|| {
-                SealedState::from_partial_encoding_infallible(&self.partial_state, forest)
-            })
-            .value()
-            .clone()
+    fn is_pruned(&self) -> bool {
+        self.partial_state.is_empty()
+    }
+
+    fn to_state(&self, forest: &novasmt::Forest, cache: &StateCache) -> SealedState {
+        assert!(
+            !self.is_pruned(),
+            "state of block {} was pruned",
+            self.header.height
+        );
+        cache.get_or_insert_with(self.header.hash(), || {
+            SealedState::from_partial_encoding_infallible(&self.partial_state, forest)
+        })
     }
 }
 
+/// The highest height a block can be stored at. The heights above it are taken by the other keyspaces.
+const MAX_BLOCK_HEIGHT: u64 = u64::MAX - 5;
+
 fn main_key(blkhash: HashVal, height: u64) -> [u8; 40] {
     let mut toret = [0u8; 40];
     toret[..8].copy_from_slice(&height.to_be_bytes());
@@ -570,3 +807,15 @@ fn tip_key(blkhash: HashVal) -> [u8; 40] {
 fn index_key(blkhash: HashVal) -> [u8; 40] {
     main_key(blkhash, u64::MAX)
 }
+
+fn jump_key(blkhash: HashVal) -> [u8; 40] {
+    main_key(blkhash, u64::MAX - 2)
+}
+
+fn refcount_key(root: HashVal) -> [u8; 40] {
+    main_key(root, u64::MAX - 3)
+}
+
+fn refcount_marker_key() -> [u8; 40] {
+    main_key(HashVal::default(), u64::MAX - 4)
+}
@@ -0,0 +1,138 @@
+use tmelcrypt::HashVal;
+
+use super::{BlockTree, Cursor, Txn};
+use crate::traits::DbBackend;
+
+/// The height that a block at the given height keeps a skip pointer to. This is the same deterministic skip list used by Bitcoin Core, which guarantees that any ancestor can be reached in O(log n) hops.
+pub(super) fn skip_height(height: u64) -> u64 {
+    fn invert_lowest_one(n: u64) -> u64 {
+        n & n.wrapping_sub(1)
+    }
+    if height < 2 {
+        0
+    } else if height & 1 == 1 {
+        invert_lowest_one(invert_lowest_one(height - 1)) + 1
+    } else {
+        invert_lowest_one(height)
+    }
+}
+
+impl<'a, B: DbBackend> Txn<'a, B> {
+    /// Finds the ancestor at the given height of the block with the given hash and height, following skip pointers where they help.
+    ///
+    /// Blocks inserted before skip pointers existed simply have none, in which case we fall back to walking parent pointers.
+    pub(super) fn ancestor_at_height(
+        &self,
+        mut blkhash: HashVal,
+        mut height: u64,
+        target: u64,
+    ) -> Option<HashVal> {
+        if target > height {
+            return None;
+        }
+        while height > target {
+            let skip = skip_height(height);
+            let skip_prev = skip_height(height - 1);
+            // take the skip pointer unless the parent's skip pointer gets us closer
+            let jump = if skip == target
+                || (skip > target && !(skip_prev + 2 < skip && skip_prev >= target))
+            {
+                self.jump_get(blkhash)
+            } else {
+                None
+            };
+            match jump {
+                Some(jump) => {
+                    blkhash = jump;
+                    height = skip;
+                }
+                None => {
+                    blkhash = self.internal_get(blkhash, height)?.header.previous;
+                    height -= 1;
+                }
+            }
+        }
+        self.internal_get(blkhash, height)?;
+        Some(blkhash)
+    }
+}
+
+impl<B: DbBackend> BlockTree<B> {
+    /// Gets the ancestor of the given block at the given height. A block is considered its own ancestor.
+    pub fn ancestor_at_height(&self, blkhash: HashVal, height: u64) -> Option<Cursor<'_, B>> {
+        let view = self.inner.view();
+        let ancestor = view.ancestor_at_height(blkhash, view.index_get(blkhash)?, height)?;
+        self.get_cursor(ancestor)
+    }
+
+    /// Checks whether `ancestor` is an ancestor of `descendant`. A block is considered its own ancestor.
+    pub fn is_ancestor_of(&self, ancestor: HashVal, descendant: HashVal) -> bool {
+        let view = self.inner.view();
+        let inner = || {
+            let ancestor_height = view.index_get(ancestor)?;
+            view.ancestor_at_height(descendant, view.index_get(descendant)?, ancestor_height)
+        };
+        inner() == Some(ancestor)
+    }
+
+    /// Finds the lowest common ancestor of two blocks, if they have one.
+    pub fn lowest_common_ancestor(&self, left: HashVal, right: HashVal) -> Option<Cursor<'_, B>> {
+        let view = self.inner.view();
+        let left_height = view.index_get(left)?;
+        let right_height = view.index_get(right)?;
+        let common_ancestor = |height: u64| {
+            let left = view.ancestor_at_height(left, left_height, height)?;
+            let right = view.ancestor_at_height(right, right_height, height)?;
+            if left == right {
+                Some(left)
+            } else {
+                None
+            }
+        };
+        // ancestors exist for a contiguous range of heights that ends at the lower of the two blocks, so we binary search for where it starts
+        let mut high = left_height.min(right_height);
+        let mut low = {
+            let (mut low, mut high) = (0, high);
+            while low < high {
+                let mid = low + (high - low) / 2;
+                if view.ancestor_at_height(left, left_height, mid).is_some() {
+                    high = mid;
+                } else {
+                    low = mid + 1;
+                }
+            }
+            low
+        };
+        common_ancestor(low)?;
+        // common ancestors exist for heights in [low, LCA height], so we binary search for the top of that range
+        while low < high {
+            let mid = high - (high - low) / 2;
+            if common_ancestor(mid).is_some() {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        self.get_cursor(common_ancestor(low)?)
+    }
+
+    /// Gets the path between two blocks, going up from `from` to their lowest common ancestor, then down to `to`. Both ends are included.
+    pub fn path_between(&self, from: HashVal, to: HashVal) -> Option<Vec<HashVal>> {
+        let lca = self.lowest_common_ancestor(from, to)?.header();
+        let walk_up = |blkhash: HashVal| {
+            let mut path = vec![];
+            let mut cursor = self.get_cursor(blkhash)?;
+            while cursor.header().height > lca.height {
+                path.push(cursor.header().hash());
+                cursor = cursor.parent()?;
+            }
+            Some(path)
+        };
+        let mut path = walk_up(from)?;
+        path.push(lca.hash());
+        let mut down = walk_up(to)?;
+        down.reverse();
+        path.extend(down);
+        Some(path)
+    }
+}
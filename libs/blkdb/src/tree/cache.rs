@@ -0,0 +1,125 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+use lru::LruCache;
+use parking_lot::Mutex;
+use themelio_stf::SealedState;
+use tmelcrypt::HashVal;
+
+/// The default number of SealedStates kept in the cache of a canonical BlockTree.
+pub const DEFAULT_CACHE_CAPACITY: usize = 10000;
+
+/// Statistics about a BlockTree's state cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// How many lookups were served from the cache.
+    pub hits: u64,
+    /// How many lookups had to decode a state from its partial encoding.
+    pub misses: u64,
+    /// How many states were evicted to stay within capacity.
+    pub evictions: u64,
+    /// How many states are currently cached.
+    pub len: usize,
+    /// The maximum number of evictable states. Pinned states don't count towards this.
+    pub capacity: usize,
+}
+
+/// A cache of decoded SealedStates.
+///
+/// In canonical mode every state is on disk, so the cache is a bounded LRU. In non-canonical mode the SMTs of inserted blocks are never saved, so the cached state is the *only* full copy of the block: such states are pinned, and leave the cache only when their block leaves the tree.
+pub(super) struct StateCache {
+    canonical: bool,
+    lru: Mutex<LruCache<HashVal, SealedState>>,
+    pinned: DashMap<HashVal, SealedState>,
+
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl StateCache {
+    pub fn new(canonical: bool, capacity: usize) -> Self {
+        Self {
+            canonical,
+            lru: Mutex::new(LruCache::new(capacity)),
+            pinned: Default::default(),
+            hits: Default::default(),
+            misses: Default::default(),
+            evictions: Default::default(),
+        }
+    }
+
+    /// Inserts a state.
+    pub fn insert(&self, blkhash: HashVal, state: SealedState) {
+        if self.canonical {
+            let mut lru = self.lru.lock();
+            if lru.len() == lru.cap() && !lru.contains(&blkhash) {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+            lru.put(blkhash, state);
+        } else {
+            self.pinned.insert(blkhash, state);
+        }
+    }
+
+    /// Gets a state, creating and caching it if it's not in the cache.
+    pub fn get_or_insert_with(
+        &self,
+        blkhash: HashVal,
+        create: impl FnOnce() -> SealedState,
+    ) -> SealedState {
+        let cached = if self.canonical {
+            self.lru.lock().get(&blkhash).cloned()
+        } else {
+            self.pinned.get(&blkhash).map(|v| v.value().clone())
+        };
+        if let Some(state) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return state;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // we decode without holding any locks, since decoding can be slow
+        let state = create();
+        self.insert(blkhash, state.clone());
+        state
+    }
+
+    /// Removes a state.
+    pub fn remove(&self, blkhash: &HashVal) {
+        self.lru.lock().pop(blkhash);
+        self.pinned.remove(blkhash);
+    }
+
+    /// Removes every state that doesn't satisfy the predicate.
+    pub fn retain(&self, mut keep: impl FnMut(&HashVal) -> bool) {
+        let mut lru = self.lru.lock();
+        let to_remove = lru
+            .iter()
+            .map(|(k, _)| *k)
+            .filter(|k| !keep(k))
+            .collect::<Vec<_>>();
+        for blkhash in to_remove {
+            lru.pop(&blkhash);
+        }
+        self.pinned.retain(|k, _| keep(k));
+    }
+
+    /// Changes the capacity of the LRU part of the cache, evicting if needed.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut lru = self.lru.lock();
+        let overflow = lru.len().saturating_sub(capacity);
+        self.evictions.fetch_add(overflow as u64, Ordering::Relaxed);
+        lru.resize(capacity);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            len: lru.len() + self.pinned.len(),
+            capacity: lru.cap(),
+        }
+    }
+}
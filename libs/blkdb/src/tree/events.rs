@@ -0,0 +1,41 @@
+use smol::channel::Receiver;
+use themelio_stf::Header;
+
+use super::{BlockTree, Inner};
+use crate::traits::DbBackend;
+
+/// An event describing a change to a [BlockTree]. Events are emitted only after the change has been committed to the backend.
+#[derive(Clone, Debug)]
+pub enum BlockTreeEvent {
+    /// A new block was inserted.
+    BlockInserted(Header),
+    /// The set of tips changed. Contains all the new tips.
+    TipChanged(Vec<Header>),
+    /// Blocks were removed from the tree, e.g. by [BlockTree::set_genesis] or [BlockTree::delete_tips].
+    BranchPruned(Vec<Header>),
+    /// The metadata of a block was updated.
+    MetadataUpdated(Header),
+}
+
+impl<B: DbBackend> BlockTree<B> {
+    /// Subscribes to changes to the tree. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<BlockTreeEvent> {
+        let (send, recv) = smol::channel::unbounded();
+        self.inner.subscribers.lock().push(send);
+        recv
+    }
+}
+
+impl<B: DbBackend> Inner<B> {
+    /// Whether or not anybody is listening to events. If not, we avoid the work of generating them.
+    pub(super) fn has_subscribers(&self) -> bool {
+        !self.subscribers.lock().is_empty()
+    }
+
+    /// Sends an event to every live subscriber, forgetting the dead ones.
+    pub(super) fn emit(&self, event: BlockTreeEvent) {
+        self.subscribers
+            .lock()
+            .retain(|send| send.try_send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::{HashMap, HashSet};
+
+use themelio_stf::Header;
+use tmelcrypt::HashVal;
+
+use super::{
+    main_key, refcount_key, refcount_marker_key, BlockTree, InternalValue, Txn, MAX_BLOCK_HEIGHT,
+};
+use crate::traits::DbBackend;
+
+impl<B: DbBackend> BlockTree<B> {
+    /// Prunes the states of all the blocks below the given height, returning how many were pruned. Pruned blocks keep their headers and metadata, and stay in the tree, but their states are gone for good: [Cursor::to_state](super::Cursor::to_state) no longer works on them, and new blocks can't be applied on top of them. In canonical mode, their SMTs are deleted too, unless a block that isn't pruned still uses them.
+    ///
+    /// Only the blocks between the given height and the highest already-pruned height are visited, and whether their SMTs are still used is looked up in per-SMT reference counts rather than by scanning the retained blocks. Calling this after every block thus costs time proportional to the number of newly pruned blocks.
+    pub fn prune_states(&mut self, below_height: u64) -> usize {
+        let mut to_prune = Vec::new();
+        let mut height = below_height;
+        while let Some(lower) = height.checked_sub(1) {
+            height = lower;
+            let unpruned = self
+                .blocks_at_height(height)
+                .into_iter()
+                .filter(|block| !block.is_pruned())
+                .collect::<Vec<_>>();
+            if unpruned.is_empty() {
+                break;
+            }
+            to_prune.extend(unpruned);
+        }
+        if to_prune.is_empty() {
+            return 0;
+        }
+
+        // the blocks are marked first, so that a crash at worst leaks some SMTs rather than leaving blocks that point to deleted ones
+        let dead = self.inner.transact(|txn| {
+            for block in to_prune.iter_mut() {
+                block.partial_state.clear();
+                txn.internal_insert(block.header.hash(), block.header.height, block);
+            }
+            // consecutive states often share SMTs (e.g. when nothing was staked), and those must stay around for the retained states
+            to_prune
+                .iter()
+                .flat_map(|block| smt_roots(&block.header))
+                .filter(|root| txn.refcount_get(*root) == 0)
+                .collect::<HashSet<_>>()
+        });
+        for block in to_prune.iter() {
+            self.inner.cache.remove(&block.header.hash());
+        }
+        if self.canonical {
+            for root in dead {
+                self.forest.delete_tree(root.0);
+            }
+        }
+        log::debug!(
+            "pruned the states of {} blocks below height {}",
+            to_prune.len(),
+            below_height
+        );
+        to_prune.len()
+    }
+
+    fn blocks_at_height(&self, height: u64) -> Vec<InternalValue> {
+        self.inner
+            .all_at_height(height)
+            .into_iter()
+            .filter_map(|blkhash| self.inner.get_block(blkhash, Some(height)))
+            .collect()
+    }
+}
+
+impl<'a, B: DbBackend> Txn<'a, B> {
+    /// Whether or not the SMT reference counts have been built. Databases written before they existed don't have them.
+    pub(super) fn has_refcounts(&self) -> bool {
+        self.get(&refcount_marker_key()).is_some()
+    }
+
+    /// How many blocks with unpruned states use the SMT with the given root.
+    pub(super) fn refcount_get(&self, root: HashVal) -> u64 {
+        self.get(&refcount_key(root))
+            .map(|raw| stdcode::deserialize(&raw).expect("cannot deserialize refcount"))
+            .unwrap_or_default()
+    }
+
+    pub(super) fn refcount_set(&mut self, root: HashVal, count: u64) {
+        if count == 0 {
+            self.remove(&refcount_key(root));
+        } else {
+            self.insert(&refcount_key(root), stdcode::serialize(&count).unwrap());
+        }
+    }
+
+    /// Updates the reference counts of the SMTs of a block that gains or loses its state.
+    pub(super) fn refcount_update(&mut self, header: &Header, was_live: bool, is_live: bool) {
+        if was_live == is_live {
+            return;
+        }
+        for root in smt_roots(header) {
+            let count = self.refcount_get(root);
+            let count = if is_live {
+                count + 1
+            } else {
+                count.saturating_sub(1)
+            };
+            self.refcount_set(root, count);
+        }
+    }
+
+    /// Counts the SMT references of every block from scratch.
+    pub(super) fn rebuild_refcounts(&mut self) {
+        for key in self.key_range(
+            &refcount_key(HashVal([0x00; 32])),
+            &refcount_key(HashVal([0xff; 32])),
+        ) {
+            self.remove(&key);
+        }
+        let mut counts: HashMap<HashVal, u64> = HashMap::new();
+        for key in self.key_range(
+            &main_key(HashVal([0x00; 32]), 0),
+            &main_key(HashVal([0xff; 32]), MAX_BLOCK_HEIGHT),
+        ) {
+            let block: Option<InternalValue> = self
+                .get(&key)
+                .and_then(|raw| stdcode::deserialize(&raw).ok());
+            if let Some(block) = block.filter(|block| !block.is_pruned()) {
+                for root in smt_roots(&block.header) {
+                    *counts.entry(root).or_default() += 1;
+                }
+            }
+        }
+        for (root, count) in counts {
+            self.refcount_set(root, count);
+        }
+        self.insert(&refcount_marker_key(), stdcode::serialize(&true).unwrap());
+    }
+}
+
+/// The roots of all the SMTs that a state with the given header consists of.
+pub(super) fn smt_roots(header: &Header) -> [HashVal; 5] {
+    [
+        header.coins_hash,
+        header.transactions_hash,
+        header.pools_hash,
+        header.stakes_hash,
+        header.history_hash,
+    ]
+}
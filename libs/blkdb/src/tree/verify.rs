@@ -0,0 +1,332 @@
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
+
+use thiserror::Error;
+use tmelcrypt::HashVal;
+
+use super::{
+    index_key, jump_key, main_key, refcount_key, smt_roots, tip_key, BlockTree, InternalValue, Txn,
+    MAX_BLOCK_HEIGHT,
+};
+use crate::traits::DbBackend;
+
+/// How many rounds of fixes `repair` attempts. Fixing one inconsistency can uncover others (e.g. deleting a corrupt block leaves dangling pointers to it), but never indefinitely.
+const MAX_REPAIR_ROUNDS: usize = 16;
+
+/// An inconsistency found in the database underlying a [BlockTree].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    #[error("malformed key {0:?}")]
+    MalformedKey(Vec<u8>),
+    #[error("block `{blkhash}` at height {height} cannot be decoded")]
+    UndecodableBlock { blkhash: HashVal, height: u64 },
+    #[error("block `{blkhash}` at height {height} is stored under the wrong key")]
+    MisplacedBlock { blkhash: HashVal, height: u64 },
+    #[error("block `{parent}` points to nonexistent child `{child}`")]
+    DanglingChild { parent: HashVal, child: HashVal },
+    #[error("block `{child}` is missing from the children of its parent `{parent}`")]
+    UnlinkedChild { parent: HashVal, child: HashVal },
+    #[error("block `{0}` has no parent, but is not the root of the tree")]
+    ExtraRoot(HashVal),
+    #[error("block `{blkhash}` at height {height} is missing from the index")]
+    MissingIndex { blkhash: HashVal, height: u64 },
+    #[error("index entry for `{blkhash}` points to missing height {height:?}")]
+    DanglingIndex {
+        blkhash: HashVal,
+        height: Option<u64>,
+    },
+    #[error("tip `{0}` has children")]
+    TipHasChildren(HashVal),
+    #[error("tip `{0}` does not exist")]
+    DanglingTip(HashVal),
+    #[error("skip pointer of nonexistent block `{0}`")]
+    DanglingJump(HashVal),
+    #[error("block `{0}` has no children but is not a tip")]
+    MissingTip(HashVal),
+    #[error("SMT root `{root}` is used by {expected} unpruned blocks, but its reference count is {found:?}")]
+    WrongRefcount {
+        root: HashVal,
+        expected: u64,
+        found: Option<u64>,
+    },
+    #[error("block `{blkhash}` references missing SMT root `{root}`")]
+    MissingSmtRoot { blkhash: HashVal, root: HashVal },
+}
+
+impl Inconsistency {
+    /// Whether or not [BlockTree::repair] can fix this inconsistency.
+    pub fn is_repairable(&self) -> bool {
+        !matches!(
+            self,
+            Inconsistency::ExtraRoot(_) | Inconsistency::MissingSmtRoot { .. }
+        )
+    }
+
+    fn is_index_issue(&self) -> bool {
+        matches!(
+            self,
+            Inconsistency::MissingIndex { .. } | Inconsistency::DanglingIndex { .. }
+        )
+    }
+}
+
+/// The outcome of [BlockTree::repair].
+#[derive(Debug, Clone, Default)]
+pub struct RepairReport {
+    /// Inconsistencies that were fixed.
+    pub repaired: Vec<Inconsistency>,
+    /// Inconsistencies that are still present.
+    pub remaining: Vec<Inconsistency>,
+}
+
+impl<B: DbBackend> BlockTree<B> {
+    /// Walks the whole database, returning every inconsistency found. This never panics, even on a corrupt database.
+    pub fn verify(&self) -> Vec<Inconsistency> {
+        let mut issues = Vec::new();
+        let view = self.inner.view();
+
+        // the main keyspace
+        let mut blocks: HashMap<HashVal, InternalValue> = HashMap::new();
+        for key in view.key_range(
+            &main_key(HashVal([0x00; 32]), 0),
+            &main_key(HashVal([0xff; 32]), MAX_BLOCK_HEIGHT),
+        ) {
+            let (blkhash, height) = match split_key(&key) {
+                Some(v) => v,
+                None => {
+                    issues.push(Inconsistency::MalformedKey(key));
+                    continue;
+                }
+            };
+            let value: Option<InternalValue> = view
+                .get(&key)
+                .and_then(|raw| stdcode::deserialize(&raw).ok());
+            match value {
+                None => issues.push(Inconsistency::UndecodableBlock { blkhash, height }),
+                Some(value) if value.header.hash() != blkhash || value.header.height != height => {
+                    issues.push(Inconsistency::MisplacedBlock { blkhash, height })
+                }
+                Some(value) => {
+                    blocks.insert(blkhash, value);
+                }
+            }
+        }
+
+        // the blkhash index
+        let mut indexed = BTreeSet::new();
+        for key in view.key_range(
+            &index_key(HashVal([0x00; 32])),
+            &index_key(HashVal([0xff; 32])),
+        ) {
+            let (blkhash, _) = match split_key(&key) {
+                Some(v) => v,
+                None => {
+                    issues.push(Inconsistency::MalformedKey(key));
+                    continue;
+                }
+            };
+            let height: Option<u64> = view
+                .get(&key)
+                .and_then(|raw| stdcode::deserialize(&raw).ok());
+            match (height, blocks.get(&blkhash)) {
+                (Some(height), Some(block)) if block.header.height == height => {
+                    indexed.insert(blkhash);
+                }
+                _ => issues.push(Inconsistency::DanglingIndex { blkhash, height }),
+            }
+        }
+
+        // the tips list
+        let mut tips = BTreeSet::new();
+        for key in view.key_range(&tip_key(HashVal([0x00; 32])), &tip_key(HashVal([0xff; 32]))) {
+            let (blkhash, _) = match split_key(&key) {
+                Some(v) => v,
+                None => {
+                    issues.push(Inconsistency::MalformedKey(key));
+                    continue;
+                }
+            };
+            match blocks.get(&blkhash) {
+                None => issues.push(Inconsistency::DanglingTip(blkhash)),
+                Some(block) if !block.next.is_empty() => {
+                    issues.push(Inconsistency::TipHasChildren(blkhash))
+                }
+                Some(_) => {
+                    tips.insert(blkhash);
+                }
+            }
+        }
+
+        // the skip pointers
+        for key in view.key_range(
+            &jump_key(HashVal([0x00; 32])),
+            &jump_key(HashVal([0xff; 32])),
+        ) {
+            match split_key(&key) {
+                None => issues.push(Inconsistency::MalformedKey(key)),
+                Some((blkhash, _)) if !blocks.contains_key(&blkhash) => {
+                    issues.push(Inconsistency::DanglingJump(blkhash))
+                }
+                Some(_) => {}
+            }
+        }
+
+        // the SMT reference counts, unless they have yet to be built
+        let mut refcounts = HashMap::new();
+        if view.has_refcounts() {
+            for key in view.key_range(
+                &refcount_key(HashVal([0x00; 32])),
+                &refcount_key(HashVal([0xff; 32])),
+            ) {
+                match split_key(&key) {
+                    None => issues.push(Inconsistency::MalformedKey(key)),
+                    Some((root, _)) => {
+                        let count: Option<u64> = view
+                            .get(&key)
+                            .and_then(|raw| stdcode::deserialize(&raw).ok());
+                        refcounts.insert(root, (0, count));
+                    }
+                }
+            }
+            for block in blocks.values().filter(|block| !block.is_pruned()) {
+                for root in smt_roots(&block.header) {
+                    // a missing count is a count of zero
+                    refcounts.entry(root).or_insert((0, Some(0))).0 += 1;
+                }
+            }
+        }
+        for (root, (expected, found)) in refcounts {
+            if found != Some(expected) {
+                issues.push(Inconsistency::WrongRefcount {
+                    root,
+                    expected,
+                    found,
+                });
+            }
+        }
+
+        // relationships between blocks
+        let mut roots = Vec::new();
+        for (blkhash, block) in blocks.iter() {
+            let blkhash = *blkhash;
+            if !indexed.contains(&blkhash) {
+                issues.push(Inconsistency::MissingIndex {
+                    blkhash,
+                    height: block.header.height,
+                });
+            }
+            for child in block.next.iter().copied() {
+                if !blocks.contains_key(&child) {
+                    issues.push(Inconsistency::DanglingChild {
+                        parent: blkhash,
+                        child,
+                    });
+                }
+            }
+            match blocks.get(&block.header.previous) {
+                Some(parent) if !parent.next.contains(&blkhash) => {
+                    issues.push(Inconsistency::UnlinkedChild {
+                        parent: block.header.previous,
+                        child: blkhash,
+                    })
+                }
+                Some(_) => {}
+                None => roots.push((block.header.height, blkhash)),
+            }
+            if block.next.is_empty() && !tips.contains(&blkhash) {
+                issues.push(Inconsistency::MissingTip(blkhash));
+            }
+            if self.canonical && !block.is_pruned() {
+                for root in smt_roots(&block.header) {
+                    if self.forest.open_tree(root.0).is_none() {
+                        issues.push(Inconsistency::MissingSmtRoot { blkhash, root });
+                    }
+                }
+            }
+        }
+        // the lowest root is the genesis; everything else is debris
+        roots.sort_unstable();
+        for (_, root) in roots.into_iter().skip(1) {
+            issues.push(Inconsistency::ExtraRoot(root));
+        }
+        issues
+    }
+
+    /// Fixes every repairable inconsistency found by [BlockTree::verify]. Unrepairable inconsistencies are left alone and reported.
+    pub fn repair(&mut self) -> RepairReport {
+        let mut report = RepairReport::default();
+        for _ in 0..MAX_REPAIR_ROUNDS {
+            let (mut fixable, unfixable): (Vec<_>, Vec<_>) = self
+                .verify()
+                .into_iter()
+                .partition(|issue| issue.is_repairable());
+            report.remaining = unfixable;
+            if fixable.is_empty() {
+                break;
+            }
+            // index fixes go first, since the other fixes look blocks up through the index
+            fixable.sort_by_key(|issue| !issue.is_index_issue());
+            for issue in fixable.iter() {
+                log::warn!("repairing: {}", issue);
+            }
+            self.inner.transact(|txn| {
+                for issue in fixable.iter() {
+                    fix_one(txn, issue);
+                }
+            });
+            report.repaired.extend(fixable);
+        }
+        // forget the cached states of blocks we just deleted
+        let view = self.inner.view();
+        self.inner
+            .cache
+            .retain(|blkhash| view.index_get(*blkhash).is_some());
+        report
+    }
+}
+
+/// Fixes a single repairable inconsistency.
+fn fix_one<B: DbBackend>(txn: &mut Txn<'_, B>, issue: &Inconsistency) {
+    match issue {
+        Inconsistency::MalformedKey(key) => txn.remove(key),
+        Inconsistency::UndecodableBlock { blkhash, height }
+        | Inconsistency::MisplacedBlock { blkhash, height } => {
+            txn.internal_remove(*blkhash, *height)
+        }
+        Inconsistency::DanglingChild { parent, child } => {
+            if let Some(mut value) = txn.get_block(*parent, None) {
+                value.next.remove(child);
+                txn.internal_insert(*parent, value.header.height, &value);
+            }
+        }
+        Inconsistency::UnlinkedChild { parent, child } => {
+            if let Some(mut value) = txn.get_block(*parent, None) {
+                value.next.insert(*child);
+                txn.internal_insert(*parent, value.header.height, &value);
+            }
+        }
+        Inconsistency::MissingIndex { blkhash, height } => txn.index_insert(*blkhash, *height),
+        Inconsistency::DanglingIndex { blkhash, .. } => txn.index_remove(*blkhash),
+        Inconsistency::TipHasChildren(blkhash) | Inconsistency::DanglingTip(blkhash) => {
+            txn.tip_remove(*blkhash)
+        }
+        Inconsistency::DanglingJump(blkhash) => txn.jump_remove(*blkhash),
+        Inconsistency::WrongRefcount { root, expected, .. } => txn.refcount_set(*root, *expected),
+        Inconsistency::MissingTip(blkhash) => {
+            if let Some(height) = txn.index_get(*blkhash) {
+                txn.tip_insert(*blkhash, height)
+            }
+        }
+        Inconsistency::ExtraRoot(_) | Inconsistency::MissingSmtRoot { .. } => {}
+    }
+}
+
+/// Splits a key into its block hash and height, if it's well-formed.
+fn split_key(key: &[u8]) -> Option<(HashVal, u64)> {
+    if key.len() != 40 {
+        return None;
+    }
+    let height = u64::from_be_bytes(key[..8].try_into().ok()?);
+    let blkhash = HashVal(key[8..].try_into().ok()?);
+    Some((blkhash, height))
+}
@@ -76,6 +76,8 @@ async fn run_staker(idx: usize, genesis: SealedState, forest: novasmt::Forest) {
             pk: TEST_SKK[idx].to_public(),
         },
         get_confirmed: Box::new(|_| None),
+        peers: Default::default(),
+        store: None,
     });
     loop {
         let blk = protocol.next_confirmed().await;
@@ -1,15 +1,26 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
 mod helpers;
 use blkdb::{backends::InMemoryDb, BlockTree, Cursor};
 use helpers::*;
-use themelio_stf::{Block, SealedState, StakeMapping, STAKE_EPOCH};
+use themelio_stf::{AbbrBlock, Block, Header, SealedState, StakeMapping, STAKE_EPOCH};
 
 pub mod gossip;
 use gossip::*;
 
 use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal};
 
-use crate::msg::{ProposalSig, VoteSig};
+use crate::{
+    evidence::Evidence,
+    metrics,
+    msg::{ProposalSig, VoteSig},
+    store::{ChainStore, StoredProposal, StoredVote},
+};
+
+/// How far past the highest LNC tip a proposal may be. Honest proposers never get more than 500 heights ahead of their LNC (see `next_height_time`), so this leaves plenty of slack while keeping untrusted heights away from the proposer schedule.
+const MAX_PROPOSAL_LOOKAHEAD: u64 = 1000;
 
 /// A representation of the chain state internal to Symphonia.
 pub struct ChainState {
@@ -17,8 +28,17 @@ pub struct ChainState {
     stakes: StakeMapping,
     inner: BlockTree<InMemoryDb>,
     forest: novasmt::Forest,
+    get_proposer: Arc<dyn Fn(u64) -> Option<Ed25519PK> + Send + Sync>,
+
+    // the first proposal and vote seen from every staker at every height, to catch equivocation
+    proposals_seen: BTreeMap<(u64, Ed25519PK), (AbbrBlock, ProposalSig)>,
+    votes_seen: BTreeMap<(u64, Ed25519PK), (Header, VoteSig)>,
+    evidence: BTreeMap<(Ed25519PK, u64), Evidence>,
 
+    // every notarized block in the tree by height, counting the genesis, so that LNCs can be found without walking the chain
+    notarized: BTreeSet<(u64, HashVal)>,
     drained_height: u64,
+    store: Option<Arc<ChainStore>>,
 }
 
 impl ChainState {
@@ -26,6 +46,9 @@ impl ChainState {
     pub fn new(genesis: SealedState, forest: novasmt::Forest) -> Self {
         let epoch = genesis.inner_ref().height / STAKE_EPOCH;
         let stakes = genesis.inner_ref().stakes.clone();
+        let get_proposer = Arc::new(gen_get_proposer(genesis.clone()));
+        let notarized =
+            std::iter::once((genesis.inner_ref().height, genesis.header().hash())).collect();
         let mut inner = BlockTree::new(InMemoryDb::default(), forest.clone(), false);
         inner.set_genesis(genesis, &[]);
         Self {
@@ -33,9 +56,62 @@ impl ChainState {
             stakes,
             inner,
             forest,
+            get_proposer,
+
+            proposals_seen: BTreeMap::new(),
+            votes_seen: BTreeMap::new(),
+            evidence: BTreeMap::new(),
 
+            notarized,
             drained_height: 0,
+            store: None,
+        }
+    }
+
+    /// Backs this chain state with a durable store, first reloading whatever proposals and votes the store already has. From then on, every accepted proposal and vote is persisted before it takes effect.
+    pub fn attach_store(&mut self, store: Arc<ChainStore>) {
+        self.store = None;
+        let proposals = store.proposals();
+        let votes = store.votes();
+        log::debug!(
+            "reloading {} proposals and {} votes",
+            proposals.len(),
+            votes.len()
+        );
+        // proposals come in order of height, so parents come before their children
+        for proposal in proposals {
+            if !self.has_block(proposal.block.header.hash()) {
+                let _ = self.inject_proposal_inner(
+                    &proposal.block,
+                    proposal.proposer,
+                    proposal.proposal_sig,
+                    proposal.last_nonempty,
+                    false,
+                );
+            }
         }
+        for vote in votes {
+            let hash = vote.header.hash();
+            if self
+                .inject_vote(hash, vote.voter, vote.sig.clone())
+                .is_err()
+                && vote.sig.verify(vote.voter, hash)
+            {
+                // even if the block is gone, we must remember the vote so that we never cast a conflicting one
+                self.record_vote(vote.voter, vote.header, vote.sig);
+            }
+        }
+        self.store = Some(store);
+    }
+
+    /// Returns the scheduled proposer for the given height, or `None` if nothing is staked at that height.
+    pub fn get_proposer(&self, height: u64) -> Option<Ed25519PK> {
+        (self.get_proposer)(height)
+    }
+
+    /// Has the given staker already proposed something at the given height? This includes proposals reloaded from the store, so a restarted staker knows not to propose again.
+    pub fn has_proposed(&self, height: u64, proposer: Ed25519PK) -> bool {
+        self.proposals_seen.contains_key(&(height, proposer))
     }
 
     /// Does this block exist?
@@ -50,6 +126,39 @@ impl ChainState {
         proposer: Ed25519PK,
         proposal_sig: ProposalSig,
         last_nonempty: HashVal,
+    ) -> Result<(), ProposalError> {
+        self.inject_proposal_counted(proposed_block, proposer, proposal_sig, last_nonempty, true)
+    }
+
+    fn inject_proposal_counted(
+        &mut self,
+        proposed_block: &Block,
+        proposer: Ed25519PK,
+        proposal_sig: ProposalSig,
+        last_nonempty: HashVal,
+        require_lnc: bool,
+    ) -> Result<(), ProposalError> {
+        let res = self.inject_proposal_inner(
+            proposed_block,
+            proposer,
+            proposal_sig,
+            last_nonempty,
+            require_lnc,
+        );
+        match res {
+            Ok(()) => metrics::PROPOSALS.inc(),
+            Err(_) => metrics::REJECTED_PROPOSALS.inc(),
+        }
+        res
+    }
+
+    fn inject_proposal_inner(
+        &mut self,
+        proposed_block: &Block,
+        proposer: Ed25519PK,
+        proposal_sig: ProposalSig,
+        last_nonempty: HashVal,
+        require_lnc: bool,
     ) -> Result<(), ProposalError> {
         log::debug!(
             "received proposal ({}, {:?}) extending from {:?}",
@@ -57,13 +166,27 @@ impl ChainState {
             proposed_block.header.hash(),
             last_nonempty
         );
-        if !proposal_sig.verify(proposer, &proposed_block.abbreviate()) {
+        // anybody can send us a proposal at any height, so we bound the height before doing anything expensive with it
+        let max_height = self.get_lnc_height().saturating_add(MAX_PROPOSAL_LOOKAHEAD);
+        if proposed_block.header.height > max_height {
+            return Err(ProposalError::IncorrectHeight);
+        }
+        let abbr_block = proposed_block.abbreviate();
+        if !proposal_sig.verify(proposer, &abbr_block) {
             return Err(ProposalError::InvalidBlock);
         }
+        if self.get_proposer(proposed_block.header.height) != Some(proposer) {
+            return Err(ProposalError::WrongProposer);
+        }
+        self.record_proposal(proposer, abbr_block, proposal_sig.clone());
 
-        let lnc_tips = self.get_lnc_tips();
-        if !lnc_tips.contains(&last_nonempty) {
-            log::warn!("tips: {:?}", lnc_tips);
+        if require_lnc {
+            let lnc_tips = self.get_lnc_tips();
+            if !lnc_tips.contains(&last_nonempty) {
+                log::warn!("tips: {:?}", lnc_tips);
+                return Err(ProposalError::NotExtendingLnc);
+            }
+        } else if !self.has_block(last_nonempty) {
             return Err(ProposalError::NotExtendingLnc);
         }
 
@@ -94,7 +217,6 @@ impl ChainState {
                 }
             }
         }
-        // TODO: check whether this is the *right* guy to propose this round. Not checking this potentially impacts fairness, but not correctness
         for block in to_apply {
             self.inner
                 .apply_block(&block, &[])
@@ -105,7 +227,7 @@ impl ChainState {
                 &proposed_block,
                 &stdcode::serialize(&StreamletMetadata {
                     proposer,
-                    proposal_sig,
+                    proposal_sig: proposal_sig.clone(),
                     votes: BTreeMap::new(),
                 })
                 .unwrap(),
@@ -114,6 +236,14 @@ impl ChainState {
                 log::warn!("error applying block: {:?}", e);
                 ProposalError::InvalidBlock
             })?;
+        if let Some(store) = self.store.as_ref() {
+            store.insert_proposal(&StoredProposal {
+                block: proposed_block.clone(),
+                proposer,
+                proposal_sig,
+                last_nonempty,
+            });
+        }
         Ok(())
     }
 
@@ -127,13 +257,33 @@ impl ChainState {
         if !signature.verify(voter, voting_for) {
             return Err(VoteError::InvalidSignature);
         }
-        let mut existing_metadata = self
+        let cursor = self
             .inner
             .get_cursor(voting_for)
-            .ok_or(VoteError::NoSuchBlock)?
-            .get_streamlet()
-            .ok_or(VoteError::EmptyBlock)?;
-        existing_metadata.votes.insert(voter, signature);
+            .ok_or(VoteError::NoSuchBlock)?;
+        let header = cursor.header();
+        let mut existing_metadata = cursor.get_streamlet().ok_or(VoteError::EmptyBlock)?;
+        self.record_vote(voter, header, signature.clone());
+        let was_notarized = existing_metadata.is_notarized(self.epoch, &self.stakes);
+        if existing_metadata
+            .votes
+            .insert(voter, signature.clone())
+            .is_none()
+        {
+            // persist before the vote becomes visible to anybody else
+            if let Some(store) = self.store.as_ref() {
+                store.insert_vote(&StoredVote {
+                    header,
+                    voter,
+                    sig: signature,
+                });
+            }
+            metrics::VOTES.inc();
+            if !was_notarized && existing_metadata.is_notarized(self.epoch, &self.stakes) {
+                self.notarized.insert((header.height, voting_for));
+                metrics::NOTARIZATIONS.inc();
+            }
+        }
         self.inner
             .get_cursor_mut(voting_for)
             .expect("failed to put metadata back in")
@@ -141,8 +291,38 @@ impl ChainState {
         Ok(())
     }
 
-    /// Votes for all "appropriate" proposals.
+    /// Adds a piece of equivocation evidence, returning whether it was new. Evidence that doesn't verify, or that implicates a staker without stake at that height, is ignored. One piece of evidence is enough to prove an offender equivocated at a height, so we keep at most one per offender and height.
+    pub fn inject_evidence(&mut self, evidence: Evidence) -> bool {
+        if !evidence.verify()
+            || self
+                .stakes
+                .vote_power(evidence.height() / STAKE_EPOCH, evidence.offender())
+                <= 0.0
+        {
+            return false;
+        }
+        let key = (evidence.offender(), evidence.height());
+        if self.evidence.contains_key(&key) {
+            return false;
+        }
+        log::warn!(
+            "staker {:?} equivocated at height {}",
+            evidence.offender(),
+            evidence.height()
+        );
+        metrics::EQUIVOCATIONS.inc();
+        self.evidence.insert(key, evidence);
+        true
+    }
+
+    /// Returns all the equivocation evidence known so far.
+    pub fn evidence(&self) -> Vec<Evidence> {
+        self.evidence.values().cloned().collect()
+    }
+
+    /// Votes for all "appropriate" proposals. Never votes for two different blocks at the same height.
     pub fn vote_all(&mut self, voter_sk: Ed25519SK) {
+        let me = voter_sk.to_public();
         let lnc_cursor = self
             .get_lnc_tips()
             .into_iter()
@@ -150,10 +330,15 @@ impl ChainState {
             .map(|v| self.inner.get_cursor(v).unwrap())
             .unwrap();
         let mut vote_for = Vec::new();
+        let mut vote_heights = BTreeSet::new();
         let mut stack = lnc_cursor.children();
         while let Some(child) = stack.pop() {
             if let Some(metadata) = child.get_streamlet() {
-                if metadata.votes.get(&voter_sk.to_public()).is_none() {
+                let height = child.header().height;
+                if !metadata.votes.contains_key(&me)
+                    && !self.votes_seen.contains_key(&(height, me))
+                    && vote_heights.insert(height)
+                {
                     vote_for.push(child.header().hash());
                 }
             } else {
@@ -162,12 +347,8 @@ impl ChainState {
         }
         for hash in vote_for {
             log::debug!("self-voting for {}", hash);
-            self.inject_vote(
-                hash,
-                voter_sk.to_public(),
-                VoteSig::generate(voter_sk, hash),
-            )
-            .expect("vote_all should never produce an error");
+            self.inject_vote(hash, me, VoteSig::generate(voter_sk, hash))
+                .expect("vote_all should never produce an error");
         }
     }
 
@@ -186,7 +367,28 @@ impl ChainState {
             .into_iter()
             .filter_map(|v| self.inner.get_cursor(v))
             .collect::<Vec<_>>();
-        let to_send = self.get_nonempty_descendants(their_lnc_tips);
+        let mut to_send = self.get_nonempty_descendants(their_lnc_tips);
+        // We also send over our own notarized chains, which they may not have if they fork off below their lnc tips. Otherwise two equally long notarized chains would never learn about each other.
+        let mut walked: Vec<HashVal> = vec![];
+        for tip in self.get_lnc_tips() {
+            // the chains mostly overlap, so we only walk down to where this one meets a chain we've already walked
+            let stop_height = walked
+                .iter()
+                .filter_map(|&other| self.inner.lowest_common_ancestor(tip, other))
+                .map(|lca| lca.header().height)
+                .fold(self.drained_height, u64::max);
+            walked.push(tip);
+            let mut cursor = self.inner.get_cursor(tip);
+            while let Some(current) = cursor {
+                if current.header().height <= stop_height {
+                    break;
+                }
+                if current.get_streamlet().is_some() {
+                    to_send.insert(current.header().hash());
+                }
+                cursor = current.parent();
+            }
+        }
         to_send
             .into_iter()
             .map(|hash| {
@@ -210,6 +412,22 @@ impl ChainState {
             .collect()
     }
 
+    /// Generates an evidence request.
+    pub fn new_evidence_request(&self) -> EvidenceRequest {
+        EvidenceRequest {
+            known: self.evidence.keys().copied().collect(),
+        }
+    }
+
+    /// Generates a response to the given evidence request, containing evidence for every offender and height the requester has none for.
+    pub fn new_evidence_response(&self, request: EvidenceRequest) -> Vec<Evidence> {
+        self.evidence
+            .iter()
+            .filter(|(key, _)| !request.known.contains(key))
+            .map(|(_, evidence)| evidence.clone())
+            .collect()
+    }
+
     /// Generates a response to the given transaction request.
     pub fn new_transaction_response(&self, request: TransactionRequest) -> TransactionResponse {
         if let Some(cursor) = self.inner.get_cursor(request.block_hash) {
@@ -235,6 +453,15 @@ impl ChainState {
         let mut new_inner = BlockTree::new(InMemoryDb::default(), self.forest.clone(), false);
         // DFS into the new thing.
         let cursor = self.inner.get_cursor(genesis.header().hash());
+        let genesis_height = genesis.inner_ref().height;
+        let genesis_hash = genesis.header().hash();
+        self.proposals_seen
+            .retain(|(height, _), _| *height > genesis_height);
+        self.votes_seen
+            .retain(|(height, _), _| *height > genesis_height);
+        if let Some(store) = self.store.as_ref() {
+            store.prune(genesis_height);
+        }
         new_inner.set_genesis(
             genesis,
             if let Some(cursor) = cursor.as_ref() {
@@ -253,6 +480,10 @@ impl ChainState {
             }
         }
         self.inner = new_inner;
+        let inner = &self.inner;
+        self.notarized
+            .retain(|(_, hash)| inner.get_cursor(*hash).is_some());
+        self.notarized.insert((genesis_height, genesis_hash));
     }
 
     /// Attempts to apply a full-block response from a gossip peer.
@@ -262,11 +493,18 @@ impl ChainState {
             .get_cursor(response.block.header.hash())
             .is_none()
         {
-            self.inject_proposal(
+            // A block that is already notarized must be accepted even if it forks off below our LNC, since it may be part of an equally long notarized chain that we just haven't heard the votes for.
+            let mut valid_votes = response.metadata.clone();
+            valid_votes
+                .votes
+                .retain(|voter, vote| vote.verify(*voter, response.block.header.hash()));
+            let notarized = valid_votes.is_notarized(self.epoch, &self.stakes);
+            self.inject_proposal_counted(
                 &response.block,
                 response.metadata.proposer,
                 response.metadata.proposal_sig,
                 response.last_nonempty,
+                !notarized,
             )?;
         }
         let voting_for = response.block.header.hash();
@@ -321,34 +559,57 @@ impl ChainState {
             }
             ancestors.reverse();
             self.drained_height = new_drained_height;
+            metrics::FINALIZATIONS.inc_by(ancestors.len() as u64);
             ancestors.into_iter().map(|v| v.to_state()).collect()
         } else {
             vec![]
         }
     }
 
+    fn record_proposal(&mut self, proposer: Ed25519PK, abbr_block: AbbrBlock, sig: ProposalSig) {
+        let key = (abbr_block.header.height, proposer);
+        if let Some((seen_block, seen_sig)) = self.proposals_seen.get(&key) {
+            if seen_block.header.hash() != abbr_block.header.hash() {
+                let evidence = Evidence::double_proposal(
+                    proposer,
+                    (seen_block.clone(), seen_sig.clone()),
+                    (abbr_block, sig),
+                );
+                self.inject_evidence(evidence);
+            }
+        } else {
+            self.proposals_seen.insert(key, (abbr_block, sig));
+        }
+    }
+
+    fn record_vote(&mut self, voter: Ed25519PK, header: Header, sig: VoteSig) {
+        let key = (header.height, voter);
+        if let Some((seen_header, seen_sig)) = self.votes_seen.get(&key) {
+            if seen_header.hash() != header.hash() {
+                let evidence =
+                    Evidence::double_vote(voter, (*seen_header, seen_sig.clone()), (header, sig));
+                self.inject_evidence(evidence);
+            }
+        } else {
+            self.votes_seen.insert(key, (header, sig));
+        }
+    }
+
     /// Get LNCs
     fn get_lnc_tips(&self) -> BTreeSet<HashVal> {
         let tip_notarized_ancestors = self
             .inner
             .get_tips()
             .into_iter()
-            .map(|mut tip| {
-                // "move back" the tip until something notarized is found
-                while !tip
-                    .get_streamlet()
-                    .map(|v| v.is_notarized(self.epoch, &self.stakes))
-                    .unwrap_or_default()
-                // empty blocks cannot be notarized in any way
-                {
-                    if let Some(parent) = tip.parent() {
-                        tip = parent;
-                    } else {
-                        // Genesis is ALWAYS considered notarized
-                        return tip;
-                    }
-                }
-                tip
+            .map(|tip| {
+                // the highest notarized block that the tip descends from. Genesis is ALWAYS considered notarized, so there always is one.
+                let tip = tip.header();
+                self.notarized
+                    .range(..(tip.height + 1, HashVal::default()))
+                    .rev()
+                    .find(|(_, notarized)| self.inner.is_ancestor_of(*notarized, tip.hash()))
+                    .and_then(|(_, notarized)| self.inner.get_cursor(*notarized))
+                    .expect("tip doesn't descend from genesis")
             })
             .collect::<Vec<_>>();
         // we filter out things that are not at the highest height
@@ -364,6 +625,16 @@ impl ChainState {
             .collect()
     }
 
+    /// Get the height of the highest LNC tip
+    fn get_lnc_height(&self) -> u64 {
+        self.get_lnc_tips()
+            .into_iter()
+            .filter_map(|tip| self.inner.get_cursor(tip))
+            .map(|cursor| cursor.header().height)
+            .max()
+            .unwrap_or_default()
+    }
+
     /// Get finalized tip
     fn get_final_tip(&self) -> Option<HashVal> {
         // for each LNC tip, try to find the final tip
@@ -375,17 +646,30 @@ impl ChainState {
         None
     }
 
+    /// Finds the middle of the highest three consecutive nonempty blocks ending in a notarized ancestor of the given tip. Only blocks above the drained height can be newly final.
     fn find_final(&self, tip: HashVal) -> Option<HashVal> {
-        let mut cursor = self.inner.get_cursor(tip)?;
-        loop {
-            if cursor.get_streamlet().is_some()
-                && cursor.parent()?.get_streamlet().is_some()
-                && cursor.parent()?.parent()?.get_streamlet().is_some()
-            {
-                return Some(cursor.parent()?.header().hash());
-            }
-            cursor = cursor.parent()?;
+        let tip_height = self.inner.get_cursor(tip)?.header().height;
+        if tip_height < self.drained_height + 2 {
+            return None;
         }
+        let is_nonempty = |height: u64| {
+            self.inner
+                .ancestor_at_height(tip, height)
+                .map(|cursor| cursor.get_streamlet().is_some())
+                .unwrap_or_default()
+        };
+        let (top, _) = self
+            .notarized
+            .range(
+                (self.drained_height + 2, HashVal::default())..(tip_height + 1, HashVal::default()),
+            )
+            .rev()
+            .find(|(height, notarized)| {
+                self.inner.is_ancestor_of(*notarized, tip)
+                    && is_nonempty(height - 1)
+                    && is_nonempty(height - 2)
+            })?;
+        Some(self.inner.ancestor_at_height(tip, top - 1)?.header().hash())
     }
 
     fn get_nonempty_descendants(
@@ -408,9 +692,47 @@ impl ChainState {
 
 #[cfg(test)]
 mod tests {
-    use themelio_stf::{GenesisConfig, State};
+    use themelio_stf::{GenesisConfig, ProposerAction, State};
 
     use super::*;
+    use crate::sim::staked_genesis;
+
+    /// Builds a block at height 1 on top of genesis. Different deltas give different blocks.
+    fn first_block(genesis: &SealedState, fee_multiplier_delta: i8) -> Block {
+        genesis
+            .next_state()
+            .seal(Some(ProposerAction {
+                fee_multiplier_delta,
+                reward_dest: HashVal::default().into(),
+            }))
+            .to_block()
+    }
+
+    /// Proposes the given block on top of genesis, signed by the given key.
+    fn propose(
+        cstate: &mut ChainState,
+        genesis: &SealedState,
+        sk: Ed25519SK,
+        block: &Block,
+    ) -> Result<(), ProposalError> {
+        let sig = ProposalSig::generate(sk, &block.abbreviate());
+        cstate.inject_proposal(block, sk.to_public(), sig, genesis.header().hash())
+    }
+
+    /// Sets up a chain state with four stakers, returning it along with the genesis, the scheduled proposer for height 1, and the other stakers.
+    fn setup() -> (ChainState, SealedState, Ed25519SK, Vec<Ed25519SK>) {
+        let skk = (0..4)
+            .map(|_| tmelcrypt::ed25519_keygen().1)
+            .collect::<Vec<_>>();
+        let forest = novasmt::Forest::new(novasmt::InMemoryBackend::default());
+        let genesis = staked_genesis(&forest, &skk);
+        let cstate = ChainState::new(genesis.clone(), forest);
+        let scheduled = cstate.get_proposer(1).unwrap();
+        let (right, wrong): (Vec<_>, Vec<_>) =
+            skk.into_iter().partition(|sk| sk.to_public() == scheduled);
+        assert_eq!(right.len(), 1);
+        (cstate, genesis, right[0], wrong)
+    }
 
     #[test]
     fn simple_sequence() {
@@ -419,4 +741,206 @@ mod tests {
         let cstate = ChainState::new(genesis, forest);
         dbg!(cstate.get_lnc_tips());
     }
+
+    #[test]
+    fn scheduled_proposer() {
+        let (mut cstate, genesis, right, wrong) = setup();
+        let block = first_block(&genesis, 0);
+        // correctly signed, but not this staker's turn
+        for sk in wrong {
+            assert!(matches!(
+                propose(&mut cstate, &genesis, sk, &block),
+                Err(ProposalError::WrongProposer)
+            ));
+        }
+        assert_eq!(cstate.get_lnc_state().inner_ref().height, 0);
+        propose(&mut cstate, &genesis, right, &block).unwrap();
+    }
+
+    #[test]
+    fn proposal_at_absurd_height() {
+        let (mut cstate, genesis, _, others) = setup();
+        let mut block = first_block(&genesis, 0);
+        block.header.height = u64::MAX;
+        // nobody is staked this far out, but we must reject the proposal rather than choke on the schedule
+        let attacker = tmelcrypt::ed25519_keygen().1;
+        for sk in others.into_iter().chain(std::iter::once(attacker)) {
+            assert!(matches!(
+                propose(&mut cstate, &genesis, sk, &block),
+                Err(ProposalError::IncorrectHeight)
+            ));
+        }
+        assert!(cstate.evidence().is_empty());
+        assert_eq!(cstate.get_proposer(u64::MAX), None);
+    }
+
+    #[test]
+    fn double_proposal() {
+        let (mut cstate, genesis, proposer, _) = setup();
+        let first = first_block(&genesis, 0);
+        let second = first_block(&genesis, 1);
+        propose(&mut cstate, &genesis, proposer, &first).unwrap();
+        // proposing the same thing again is not equivocation
+        let _ = propose(&mut cstate, &genesis, proposer, &first);
+        assert!(cstate.evidence().is_empty());
+        let _ = propose(&mut cstate, &genesis, proposer, &second);
+        // a third proposal makes new pairs of conflicting proposals, but we already have all the evidence we need
+        let third = first_block(&genesis, 2);
+        let _ = propose(&mut cstate, &genesis, proposer, &third);
+        let evidence = cstate.evidence();
+        assert_eq!(evidence.len(), 1);
+        assert!(matches!(evidence[0], Evidence::DoubleProposal { .. }));
+        assert!(evidence[0].verify());
+        assert_eq!(evidence[0].offender(), proposer.to_public());
+        assert_eq!(evidence[0].height(), 1);
+    }
+
+    #[test]
+    fn double_vote() {
+        let (mut cstate, genesis, proposer, others) = setup();
+        let first = first_block(&genesis, 0);
+        let second = first_block(&genesis, 1);
+        propose(&mut cstate, &genesis, proposer, &first).unwrap();
+        propose(&mut cstate, &genesis, proposer, &second).unwrap();
+        // honest voting never votes for both
+        cstate.vote_all(others[0]);
+        assert!(cstate
+            .evidence()
+            .iter()
+            .all(|evidence| evidence.offender() != others[0].to_public()));
+        // but a byzantine voter gets caught
+        let voter = others[1];
+        for block in [&first, &second] {
+            let hash = block.header.hash();
+            cstate
+                .inject_vote(hash, voter.to_public(), VoteSig::generate(voter, hash))
+                .unwrap();
+        }
+        let evidence = cstate
+            .evidence()
+            .into_iter()
+            .find(|evidence| evidence.offender() == voter.to_public())
+            .expect("double vote not detected");
+        assert!(evidence.verify());
+        // evidence with the messages swapped around isn't valid
+        if let Evidence::DoubleVote {
+            voter,
+            first,
+            second,
+        } = evidence
+        {
+            let swapped = Evidence::DoubleVote {
+                voter,
+                first: second,
+                second: first,
+            };
+            assert!(!swapped.verify());
+            assert!(!cstate.inject_evidence(swapped));
+        } else {
+            panic!("wrong kind of evidence")
+        }
+    }
+
+    /// The votes on a block, by voter.
+    fn votes_on(cstate: &ChainState, block: &Block) -> BTreeMap<Ed25519PK, VoteSig> {
+        cstate
+            .inner
+            .get_cursor(block.header.hash())
+            .and_then(|cursor| cursor.get_streamlet())
+            .map(|metadata| metadata.votes)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn restart_remembers_votes() {
+        let (mut cstate, genesis, proposer, others) = setup();
+        let store = Arc::new(ChainStore::new(InMemoryDb::default()));
+        cstate.attach_store(store.clone());
+        let first = first_block(&genesis, 0);
+        let second = first_block(&genesis, 1);
+        let voter = others[0];
+        propose(&mut cstate, &genesis, proposer, &first).unwrap();
+        cstate.vote_all(voter);
+        assert!(votes_on(&cstate, &first).contains_key(&voter.to_public()));
+
+        // "restart" with the same store
+        let mut restarted = ChainState::new(genesis.clone(), cstate.forest.clone());
+        restarted.attach_store(store.clone());
+        assert!(restarted.has_block(first.header.hash()));
+        assert!(votes_on(&restarted, &first).contains_key(&voter.to_public()));
+        // a conflicting proposal must not get our vote
+        propose(&mut restarted, &genesis, proposer, &second).unwrap();
+        restarted.vote_all(voter);
+        assert!(!votes_on(&restarted, &second).contains_key(&voter.to_public()));
+
+        // even if we lost the block itself, we still remember the vote
+        let mut amnesiac = ChainState::new(genesis.clone(), cstate.forest.clone());
+        let store = Arc::new(ChainStore::new(InMemoryDb::default()));
+        for vote in restarted.store.as_ref().unwrap().votes() {
+            store.insert_vote(&vote);
+        }
+        amnesiac.attach_store(store.clone());
+        assert!(!amnesiac.has_block(first.header.hash()));
+        propose(&mut amnesiac, &genesis, proposer, &second).unwrap();
+        amnesiac.vote_all(voter);
+        assert!(!votes_on(&amnesiac, &second).contains_key(&voter.to_public()));
+
+        amnesiac.reset_genesis(genesis.next_state().seal(None));
+        assert!(store.votes().is_empty());
+    }
+
+    #[test]
+    fn restart_remembers_own_proposal() {
+        let (mut cstate, genesis, proposer, _) = setup();
+        let store = Arc::new(ChainStore::new(InMemoryDb::default()));
+        cstate.attach_store(store.clone());
+        assert!(!cstate.has_proposed(1, proposer.to_public()));
+        propose(&mut cstate, &genesis, proposer, &first_block(&genesis, 0)).unwrap();
+        assert!(cstate.has_proposed(1, proposer.to_public()));
+
+        // restarting within the same round must not let us propose again
+        let mut restarted = ChainState::new(genesis.clone(), cstate.forest.clone());
+        restarted.attach_store(store);
+        assert!(restarted.has_proposed(1, proposer.to_public()));
+        assert!(!restarted.has_proposed(2, proposer.to_public()));
+    }
+
+    #[test]
+    fn buffered_store_flushes_own_messages() {
+        let (_, genesis, me, others) = setup();
+        let flushes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let store = ChainStore::buffered(InMemoryDb::default(), me.to_public(), {
+            let flushes = flushes.clone();
+            move || {
+                flushes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        });
+        let flushed = || flushes.load(std::sync::atomic::Ordering::SeqCst);
+        let header = first_block(&genesis, 0).header;
+        let vote = |voter: Ed25519SK| StoredVote {
+            header,
+            voter: voter.to_public(),
+            sig: VoteSig::generate(voter, header.hash()),
+        };
+        // other stakers' votes can be heard again, so they aren't worth a flush
+        store.insert_vote(&vote(others[0]));
+        assert_eq!(flushed(), 0);
+        store.insert_vote(&vote(me));
+        assert_eq!(flushed(), 1);
+        store.sign_confirmation(others[0], header).unwrap();
+        assert_eq!(flushed(), 2);
+        assert_eq!(store.votes().len(), 2);
+    }
+
+    #[test]
+    fn conflicting_confirmation() {
+        let (_, genesis, signer, _) = setup();
+        let store = ChainStore::new(InMemoryDb::default());
+        let first = first_block(&genesis, 0).header;
+        let second = first_block(&genesis, 1).header;
+        let signature = store.sign_confirmation(signer, first).unwrap();
+        // asking again gives the same signature
+        assert_eq!(store.sign_confirmation(signer, first), Some(signature));
+        assert_eq!(store.sign_confirmation(signer, second), None);
+    }
 }
@@ -2,7 +2,7 @@ use std::collections::BTreeSet;
 
 use serde::{Deserialize, Serialize};
 use themelio_stf::{AbbrBlock, Block, Transaction, TxHash};
-use tmelcrypt::HashVal;
+use tmelcrypt::{Ed25519PK, HashVal};
 
 use super::helpers::StreamletMetadata;
 
@@ -44,3 +44,10 @@ pub struct TransactionResponse {
     /// Just the transactions. Hash these transactions to check the txhash validity.
     pub transactions: Vec<Transaction>,
 }
+
+/// A gossip request that solicits equivocation evidence.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EvidenceRequest {
+    /// The offenders and heights we already have evidence for. We keep only one piece of evidence per offender and height, so these don't need any more.
+    pub known: BTreeSet<(Ed25519PK, u64)>,
+}
@@ -1,10 +1,10 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, convert::TryInto};
 
 use blkdb::{backends::InMemoryDb, Cursor};
 use once_cell::sync::Lazy;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use themelio_stf::{AbbrBlock, StakeMapping};
+use themelio_stf::{AbbrBlock, SealedState, StakeMapping, STAKE_EPOCH};
 use thiserror::Error;
 use tmelcrypt::{Ed25519PK, HashVal};
 
@@ -18,6 +18,8 @@ pub enum ProposalError {
     IncorrectHeight,
     #[error("proposal doesn't extend the longest notarized chain")]
     NotExtendingLnc,
+    #[error("proposal not from the scheduled proposer")]
+    WrongProposer,
 }
 
 #[derive(Error, Debug)]
@@ -110,3 +112,74 @@ impl StreamletMetadata {
         true
     }
 }
+
+/// Returns a proposer-calculator for a given epoch, given the SealedState before the epoch. The calculator returns `None` at heights where nothing is staked.
+pub fn gen_get_proposer(pre_epoch: SealedState) -> impl Fn(u64) -> Option<Ed25519PK> {
+    let end_height = if pre_epoch.inner_ref().height < STAKE_EPOCH {
+        0
+    } else if pre_epoch.inner_ref().height / STAKE_EPOCH
+        != (pre_epoch.inner_ref().height + 1) / STAKE_EPOCH
+    {
+        pre_epoch.inner_ref().height
+    } else {
+        (pre_epoch.inner_ref().height / STAKE_EPOCH * STAKE_EPOCH) - 1
+    };
+    if end_height > 0 {
+        assert!(end_height % STAKE_EPOCH == STAKE_EPOCH - 1)
+    }
+    // majority beacon of all the blocks in the previous epoch
+    let beacon_components = if end_height >= STAKE_EPOCH {
+        (end_height - STAKE_EPOCH..=end_height)
+            .map(|height| pre_epoch.inner_ref().history.get(&height).0.unwrap().hash())
+            .collect::<Vec<_>>()
+    } else {
+        vec![HashVal::default()]
+    };
+    let seed = tmelcrypt::majority_beacon(&beacon_components);
+    let stakes = pre_epoch.inner_ref().stakes.clone();
+    move |height: u64| {
+        // we sum the number of µsyms staked
+        // TODO: overflow?
+        let total_staked = stakes
+            .val_iter()
+            .filter_map(|v| {
+                if v.e_post_end > height / STAKE_EPOCH && v.e_start <= height / STAKE_EPOCH {
+                    Some(v.syms_staked)
+                } else {
+                    None
+                }
+            })
+            .sum::<u128>();
+        if total_staked == 0 {
+            return None;
+        }
+        // "clamp" the subseed
+        // we hash the seed with the height
+        let mut seed = tmelcrypt::hash_keyed(height.to_be_bytes(), seed);
+        let seed = loop {
+            let numseed = u128::from_be_bytes(
+                (&tmelcrypt::hash_keyed(height.to_be_bytes(), seed).0[0..16])
+                    .try_into()
+                    .unwrap(),
+            );
+            let numseed = numseed >> total_staked.leading_zeros();
+            if numseed < total_staked {
+                break numseed;
+            }
+            seed = tmelcrypt::hash_single(seed);
+        };
+        // now we go through the stakedocs
+        let mut stake_docs = stakes.val_iter().collect::<Vec<_>>();
+        stake_docs.sort_by_key(|v| v.pubkey);
+        let mut sum = 0;
+        for stake in stake_docs {
+            if stake.e_post_end > height / STAKE_EPOCH && stake.e_start <= height / STAKE_EPOCH {
+                sum += stake.syms_staked;
+                if seed <= sum {
+                    return Some(stake.pubkey);
+                }
+            }
+        }
+        None
+    }
+}
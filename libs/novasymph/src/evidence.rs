@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use themelio_stf::{AbbrBlock, Header};
+use tmelcrypt::{Ed25519PK, HashVal};
+
+use crate::msg::{ProposalSig, VoteSig};
+
+/// Self-contained proof that a staker equivocated: it signed two conflicting messages at the same height. Evidence can be checked with [Evidence::verify] without any other context, so that it can later be wrapped in a slashing transaction.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Evidence {
+    /// The staker proposed two different blocks at the same height.
+    DoubleProposal {
+        proposer: Ed25519PK,
+        first: (AbbrBlock, ProposalSig),
+        second: (AbbrBlock, ProposalSig),
+    },
+    /// The staker voted for two different blocks at the same height.
+    DoubleVote {
+        voter: Ed25519PK,
+        first: (Header, VoteSig),
+        second: (Header, VoteSig),
+    },
+}
+
+impl Evidence {
+    /// Creates evidence of a double proposal, putting the two proposals in canonical order.
+    pub fn double_proposal(
+        proposer: Ed25519PK,
+        a: (AbbrBlock, ProposalSig),
+        b: (AbbrBlock, ProposalSig),
+    ) -> Self {
+        let (first, second) = if a.0.header.hash() < b.0.header.hash() {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        Evidence::DoubleProposal {
+            proposer,
+            first,
+            second,
+        }
+    }
+
+    /// Creates evidence of a double vote, putting the two votes in canonical order.
+    pub fn double_vote(voter: Ed25519PK, a: (Header, VoteSig), b: (Header, VoteSig)) -> Self {
+        let (first, second) = if a.0.hash() < b.0.hash() {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        Evidence::DoubleVote {
+            voter,
+            first,
+            second,
+        }
+    }
+
+    /// The staker that equivocated.
+    pub fn offender(&self) -> Ed25519PK {
+        match self {
+            Evidence::DoubleProposal { proposer, .. } => *proposer,
+            Evidence::DoubleVote { voter, .. } => *voter,
+        }
+    }
+
+    /// The height at which the staker equivocated.
+    pub fn height(&self) -> u64 {
+        match self {
+            Evidence::DoubleProposal { first, .. } => first.0.header.height,
+            Evidence::DoubleVote { first, .. } => first.0.height,
+        }
+    }
+
+    /// A hash that uniquely identifies this evidence.
+    pub fn hash(&self) -> HashVal {
+        tmelcrypt::hash_single(stdcode::serialize(self).unwrap())
+    }
+
+    /// Checks that both messages are correctly signed by the offender, are at the same height, and conflict. The messages must be in canonical order, so that the same pair of messages always makes the same evidence.
+    pub fn verify(&self) -> bool {
+        match self {
+            Evidence::DoubleProposal {
+                proposer,
+                first,
+                second,
+            } => {
+                first.0.header.height == second.0.header.height
+                    && first.0.header.hash() < second.0.header.hash()
+                    && first.1.verify(*proposer, &first.0)
+                    && second.1.verify(*proposer, &second.0)
+            }
+            Evidence::DoubleVote {
+                voter,
+                first,
+                second,
+            } => {
+                first.0.height == second.0.height
+                    && first.0.hash() < second.0.hash()
+                    && first.1.verify(*voter, first.0.hash())
+                    && second.1.verify(*voter, second.0.hash())
+            }
+        }
+    }
+}
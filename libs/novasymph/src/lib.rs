@@ -1,18 +1,33 @@
 mod cstate;
+mod evidence;
+mod metrics;
 mod msg;
 mod protocol;
+mod runtime;
+pub mod sim;
+mod store;
+mod transport;
+pub use evidence::*;
+pub use msg::{ProposalSig, VoteSig};
+use std::sync::Arc;
+
 use once_cell::sync::Lazy;
 pub use protocol::*;
+pub use runtime::*;
+pub use store::ChainStore;
+pub use transport::*;
 
 /// Crate-local executor to prevent CPU spikes (e.g. while spamming massive numbers of empty blocks) from causing latency spikes elsewhere in the executor
-static NS_EXECUTOR: Lazy<&'static smol::Executor<'static>> = Lazy::new(|| {
-    let exec = Box::leak(Box::new(smol::Executor::new()));
-    let exec: &'static smol::Executor<'static> = exec;
+static NS_EXECUTOR: Lazy<Arc<smol::Executor<'static>>> = Lazy::new(|| {
+    let exec = Arc::new(smol::Executor::new());
     log::warn!("starting novasymph executor");
     // spin off one thread
     std::thread::Builder::new()
         .name("novasymph".into())
-        .spawn(move || smol::future::block_on(exec.run(smol::future::pending::<()>())))
+        .spawn({
+            let exec = exec.clone();
+            move || smol::future::block_on(exec.run(smol::future::pending::<()>()))
+        })
         .unwrap();
     exec
 });
@@ -0,0 +1,82 @@
+use once_cell::sync::Lazy;
+use prometheus::{register_histogram, register_int_counter, register_int_gauge};
+use prometheus::{Histogram, IntCounter, IntGauge};
+
+// Metrics are registered in the default prometheus registry, so that the embedding program can export them together with its own.
+
+pub(crate) static ROUND: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("novasymph_round", "Height of the current consensus round").unwrap()
+});
+
+pub(crate) static PROPOSALS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("novasymph_proposals_total", "Accepted block proposals").unwrap()
+});
+
+pub(crate) static REJECTED_PROPOSALS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "novasymph_rejected_proposals_total",
+        "Rejected block proposals"
+    )
+    .unwrap()
+});
+
+pub(crate) static VOTES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "novasymph_votes_total",
+        "Distinct valid votes received or cast"
+    )
+    .unwrap()
+});
+
+pub(crate) static NOTARIZATIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "novasymph_notarizations_total",
+        "Blocks that became notarized"
+    )
+    .unwrap()
+});
+
+pub(crate) static FINALIZATIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("novasymph_finalizations_total", "Blocks that became final").unwrap()
+});
+
+pub(crate) static CONFIRMATIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "novasymph_confirmations_total",
+        "Finalized blocks that gathered a confirmation proof"
+    )
+    .unwrap()
+});
+
+pub(crate) static CONFIRMATION_LATENCY: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "novasymph_confirmation_latency_seconds",
+        "Time from a block being finalized to it being confirmed",
+        vec![0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0]
+    )
+    .unwrap()
+});
+
+pub(crate) static GOSSIP_TIMEOUTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "novasymph_gossip_timeouts_total",
+        "Gossip requests that timed out"
+    )
+    .unwrap()
+});
+
+pub(crate) static GOSSIP_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "novasymph_gossip_failures_total",
+        "Gossip requests that failed"
+    )
+    .unwrap()
+});
+
+pub(crate) static EQUIVOCATIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "novasymph_equivocations_total",
+        "Distinct pieces of equivocation evidence learned"
+    )
+    .unwrap()
+});
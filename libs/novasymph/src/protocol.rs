@@ -1,15 +1,13 @@
 use futures_util::stream::FuturesOrdered;
-use melnet::Request;
 use parking_lot::RwLock;
+use peermgr::PeerManager;
 use smol::{channel::Receiver, future::Boxed};
 use smol::{channel::Sender, prelude::*};
-use smol_timeout::TimeoutExt;
 use std::{
     collections::BTreeMap,
-    convert::TryInto,
     net::SocketAddr,
     sync::Arc,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 use themelio_stf::{
     Block, ConfirmedState, ConsensusProof, ProposerAction, SealedState, StakeMapping, Transaction,
@@ -20,15 +18,25 @@ use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal};
 use crate::{
     cstate::{
         gossip::{
-            AbbrBlockResponse, BlockRequest, FullBlockResponse, TransactionRequest,
-            TransactionResponse,
+            AbbrBlockResponse, BlockRequest, EvidenceRequest, FullBlockResponse,
+            TransactionRequest, TransactionResponse,
         },
         ChainState,
     },
+    evidence::Evidence,
+    metrics,
     msg::ProposalSig,
-    NS_EXECUTOR,
+    runtime::Runtime,
+    store::ChainStore,
+    transport::{MelnetTransport, Responder, Transport},
 };
 
+/// How long a gossip peer that serves provably bogus data is ignored for.
+const BAN_TIME: Duration = Duration::from_secs(600);
+
+/// How often we ask a random peer for equivocation evidence. Evidence is rare and not urgent, so this is much slower than block gossip.
+const EVIDENCE_INTERVAL: Duration = Duration::from_secs(10);
+
 /// A trait that represents a "mempool".
 pub trait BlockBuilder: 'static + Send + Sync {
     /// Given a previous state, build a block that extends it
@@ -56,6 +64,10 @@ pub struct EpochConfig<B: BlockBuilder> {
     pub signing_sk: Ed25519SK,
     pub builder: B,
     pub get_confirmed: Box<dyn Fn(u64) -> Option<ConfirmedState> + Sync + Send + 'static>,
+    /// Keeps track of the gossip peers, including the ones discovered through the transport.
+    pub peers: Arc<PeerManager>,
+    /// Durable storage for the consensus state, so that a restarted staker remembers its votes. If `None`, everything is kept in memory.
+    pub store: Option<Arc<ChainStore>>,
 }
 
 /// Represents a running instance of the Symphonia protocol for a particular epoch.
@@ -66,18 +78,37 @@ pub struct EpochProtocol {
 }
 
 impl EpochProtocol {
-    /// Create a new instance of the protocol over melnet.
+    /// Create a new instance of the protocol over melnet, listening on `cfg.listen` and bootstrapping off `cfg.bootstrap`.
     pub fn new<B: BlockBuilder>(cfg: EpochConfig<B>) -> Self {
+        let transport = MelnetTransport::new(cfg.listen, &cfg.bootstrap);
+        Self::new_with_transport(cfg, Arc::new(transport))
+    }
+
+    /// Create a new instance of the protocol over the given transport. `cfg.listen` and `cfg.bootstrap` are ignored.
+    pub fn new_with_transport<B: BlockBuilder>(
+        cfg: EpochConfig<B>,
+        transport: Arc<dyn Transport>,
+    ) -> Self {
+        Self::new_with_runtime(cfg, transport, Runtime::default())
+    }
+
+    /// Create a new instance of the protocol over the given transport, spawning its tasks and telling time with the given runtime.
+    pub fn new_with_runtime<B: BlockBuilder>(
+        cfg: EpochConfig<B>,
+        transport: Arc<dyn Transport>,
+        runtime: Runtime,
+    ) -> Self {
         let (send_confirmed, recv_confirmed) = smol::channel::unbounded();
-        let cstate = Arc::new(RwLock::new(ChainState::new(
-            cfg.genesis.clone(),
-            cfg.forest.clone(),
-        )));
+        let mut cstate = ChainState::new(cfg.genesis.clone(), cfg.forest.clone());
+        if let Some(store) = cfg.store.clone() {
+            cstate.attach_store(store);
+        }
+        let cstate = Arc::new(RwLock::new(cstate));
         Self {
             _task: {
                 let cstate = cstate.clone();
-                NS_EXECUTOR.spawn(async move {
-                    protocol_loop(cfg, cstate, send_confirmed).await;
+                runtime.clone().spawn(async move {
+                    protocol_loop(cfg, transport, runtime, cstate, send_confirmed).await;
                 })
             },
             cstate,
@@ -90,75 +121,105 @@ impl EpochProtocol {
         self.recv_confirmed.recv().await.unwrap()
     }
 
+    /// Returns all the equivocation evidence known so far, including evidence learned from peers.
+    pub fn evidence(&self) -> Vec<Evidence> {
+        self.cstate.read().evidence()
+    }
+
+    /// Adds equivocation evidence from elsewhere (e.g. evidence persisted from before a restart), so that it gets gossiped. Returns whether the evidence was valid and new.
+    pub fn inject_evidence(&self, evidence: Evidence) -> bool {
+        self.cstate.write().inject_evidence(evidence)
+    }
+
     /// Forces the given state to be genesis.
     pub fn reset_genesis(&self, genesis: SealedState) {
         self.cstate.write().reset_genesis(genesis)
     }
 }
 
+/// Answers gossip requests from the chain state and the confirmation signatures collected so far.
+struct ProtocolResponder {
+    cstate: Arc<RwLock<ChainState>>,
+    known_votes: Arc<RwLock<BTreeMap<u64, UnconfirmedBlock>>>,
+}
+
+impl Responder for ProtocolResponder {
+    fn get_blocks(&self, request: BlockRequest) -> Vec<AbbrBlockResponse> {
+        self.cstate.read().new_block_responses(request)
+    }
+
+    fn get_txx(&self, request: TransactionRequest) -> TransactionResponse {
+        self.cstate.read().new_transaction_response(request)
+    }
+
+    fn get_evidence(&self, request: EvidenceRequest) -> Vec<Evidence> {
+        self.cstate.read().new_evidence_response(request)
+    }
+
+    fn confirm_block(&self, height: u64) -> BTreeMap<Ed25519PK, Vec<u8>> {
+        let res = self
+            .known_votes
+            .read()
+            .get(&height)
+            .map(|v| v.signatures.clone())
+            .unwrap_or_default();
+        log::debug!(
+            "responding to confirm request for {} with {} sigs",
+            height,
+            res.len()
+        );
+        res
+    }
+}
+
 async fn protocol_loop<B: BlockBuilder>(
     cfg: EpochConfig<B>,
+    transport: Arc<dyn Transport>,
+    runtime: Runtime,
     cstate: Arc<RwLock<ChainState>>,
     send_confirmed: Sender<ConfirmedState>,
 ) -> ! {
     let (send_finalized, recv_finalized) = smol::channel::unbounded();
 
     let cfg = Arc::new(cfg);
-    let height_to_proposer = gen_get_proposer(cfg.genesis.clone());
-    let network = melnet::NetState::new_with_name("symphgossip");
-    for addr in &cfg.bootstrap {
-        network.add_route(*addr);
-    }
-
     let my_epoch = (cfg.genesis.inner_ref().height + 1) / STAKE_EPOCH;
+    let known_votes = Arc::new(RwLock::new(BTreeMap::new()));
 
-    // melnet server
-    {
-        let cstate_inner = cstate.clone();
-        network.listen(
-            "get_blocks",
-            move |breq: Request<BlockRequest, Vec<AbbrBlockResponse>>| {
-                let cstate_inner = cstate_inner.clone();
-                NS_EXECUTOR
-                    .spawn(async move {
-                        let response = cstate_inner.read().new_block_responses(breq.body);
-                        breq.response.send(Ok(response))
-                    })
-                    .detach();
-            },
-        );
-        let cstate_inner = cstate.clone();
-        network.listen(
-            "get_txx",
-            move |breq: Request<TransactionRequest, TransactionResponse>| {
-                let cstate_inner = cstate_inner.clone();
-                NS_EXECUTOR
-                    .spawn(async move {
-                        let resp = cstate_inner.read().new_transaction_response(breq.body);
-                        breq.response.send(Ok(resp))
-                    })
-                    .detach();
-            },
-        )
-    }
-    // melnet client
-    let _gossiper = NS_EXECUTOR.spawn(gossiper_loop(network.clone(), cstate.clone(), cfg.clone()));
-    let _confirmer = NS_EXECUTOR.spawn(confirmer_loop(
+    // gossip client
+    let _gossiper = runtime.spawn(gossiper_loop(
+        transport.clone(),
+        runtime.clone(),
+        cstate.clone(),
+        cfg.clone(),
+    ));
+    let _evidence = runtime.spawn(evidence_loop(
+        transport.clone(),
+        runtime.clone(),
+        cstate.clone(),
+        cfg.peers.clone(),
+    ));
+    let _confirmer = runtime.spawn(confirmer_loop(
         my_epoch,
         cfg.signing_sk,
-        network.clone(),
+        transport.clone(),
+        runtime.clone(),
         cstate.clone(),
+        known_votes.clone(),
+        cfg.peers.clone(),
+        cfg.store.clone(),
         recv_finalized,
         send_confirmed,
     ));
 
     // actually run off into the background
-    network.add_route(cfg.listen);
-    let listener = smol::net::TcpListener::bind(cfg.listen)
-        .await
-        .expect("could not start to listen");
-    let net_inner = network.clone();
-    let _server = NS_EXECUTOR.spawn(async move { net_inner.run_server(listener).await });
+    let responder = Arc::new(ProtocolResponder {
+        cstate: cstate.clone(),
+        known_votes,
+    });
+    let _server = runtime.spawn({
+        let transport = transport.clone();
+        async move { transport.serve(responder).await }
+    });
     loop {
         let vote_loop = async {
             loop {
@@ -168,20 +229,27 @@ async fn protocol_loop<B: BlockBuilder>(
                 }
                 let hint_tip = cstate.read().get_lnc_state();
                 cfg.builder.hint_next_build(hint_tip);
-                smol::Timer::after(Duration::from_secs(1)).await;
+                runtime.sleep(Duration::from_secs(1)).await;
             }
         };
         let (height, height_time) = next_height_time(
             cstate.read().get_lnc_state().inner_ref().height,
             cfg.start_time,
             cfg.interval,
+            runtime.now(),
         );
-        wait_until_sys(height_time).or(vote_loop).await;
+        runtime.sleep_until(height_time).or(vote_loop).await;
 
         log::debug!("entering height {}", height);
+        metrics::ROUND.set(height as i64);
 
         let mut cstate = cstate.write();
-        if height_to_proposer(height) == cfg.signing_sk.to_public() {
+        if cstate.get_proposer(height) == Some(cfg.signing_sk.to_public()) {
+            if cstate.has_proposed(height, cfg.signing_sk.to_public()) {
+                // we proposed before restarting, and proposing anything else would be equivocation
+                log::warn!("already proposed at height {}, skipping this round", height);
+                continue;
+            }
             let mut build_upon = cstate.get_lnc_state();
             if build_upon.inner_ref().height >= height {
                 log::warn!(
@@ -269,28 +337,41 @@ async fn protocol_loop<B: BlockBuilder>(
 
 // "gossiper" thread
 async fn gossiper_loop<B: BlockBuilder>(
-    network: melnet::NetState,
+    transport: Arc<dyn Transport>,
+    runtime: Runtime,
     cstate: Arc<RwLock<ChainState>>,
     cfg: Arc<EpochConfig<B>>,
 ) -> ! {
     'mainloop: loop {
-        smol::Timer::after(Duration::from_millis(300)).await;
-        if let Some(random_peer) = network.routes().get(0) {
+        runtime.sleep(Duration::from_millis(300)).await;
+        cfg.peers.add_peers(transport.peers());
+        if let Some(random_peer) = cfg.peers.pick() {
             // log::debug!("gossipping with {}", random_peer);
             // create a new block request
             let block_req = cstate.read().new_block_request();
-            let response = melnet::request::<_, Vec<AbbrBlockResponse>>(
-                *random_peer,
-                "symphgossip",
-                "get_blocks",
-                block_req,
-            )
-            .timeout(Duration::from_secs(10))
-            .await;
+            let start = runtime.now();
+            let response = runtime
+                .timeout(
+                    transport.get_blocks(random_peer, block_req),
+                    Duration::from_secs(10),
+                )
+                .await;
             match response {
-                None => log::warn!("gossip timed out with {}", random_peer),
-                Some(Err(err)) => log::warn!("gossip failed with {}: {:?}", random_peer, err),
+                None => {
+                    metrics::GOSSIP_TIMEOUTS.inc();
+                    log::warn!("gossip timed out with {}", random_peer);
+                    cfg.peers.record_failure(random_peer);
+                }
+                Some(Err(err)) => {
+                    metrics::GOSSIP_FAILURES.inc();
+                    log::warn!("gossip failed with {}: {:?}", random_peer, err);
+                    cfg.peers.record_failure(random_peer);
+                }
                 Some(Ok(mut res)) => {
+                    cfg.peers.record_success(
+                        random_peer,
+                        runtime.now().duration_since(start).unwrap_or_default(),
+                    );
                     // log::debug!("({}) {} responses gotten", random_peer, res.len());
                     res.sort_unstable_by_key(|v| v.abbr_block.header.height);
                     // we now "fill in" everything
@@ -298,8 +379,18 @@ async fn gossiper_loop<B: BlockBuilder>(
                     for abbr_response in res {
                         let mut known = im::HashSet::new();
                         let mut unknown = Vec::new();
+                        // only the votes matter for blocks we already have, so we don't bother getting their transactions
+                        let have_block = cstate
+                            .read()
+                            .has_block(abbr_response.abbr_block.header.hash());
                         // we assemble all the things we don't know
-                        for txhash in abbr_response.abbr_block.txhashes.iter().copied() {
+                        for txhash in abbr_response
+                            .abbr_block
+                            .txhashes
+                            .iter()
+                            .copied()
+                            .filter(|_| !have_block)
+                        {
                             if let Some(tx) = cfg.builder.get_cached_transaction(txhash) {
                                 known.insert(tx);
                             } else {
@@ -324,16 +415,11 @@ async fn gossiper_loop<B: BlockBuilder>(
                                 block_hash: abbr_response.abbr_block.header.hash(),
                                 hashes: unknown.clone(),
                             };
-                            let response = melnet::request::<_, TransactionResponse>(
-                                *random_peer,
-                                "symphgossip",
-                                "get_txx",
-                                query,
-                            )
-                            .await;
+                            let response = transport.get_txx(random_peer, query).await;
                             match response {
                                 Err(err) => {
                                     log::warn!("({}) get_txx failed: {:?}", random_peer, err);
+                                    cfg.peers.record_failure(random_peer);
                                     continue 'mainloop;
                                 }
                                 Ok(response) => {
@@ -342,6 +428,7 @@ async fn gossiper_loop<B: BlockBuilder>(
                                             "({}) get_txx didn't give us enough",
                                             random_peer
                                         );
+                                        cfg.peers.record_failure(random_peer);
                                         continue 'mainloop;
                                     }
                                     for (txhash, transaction) in
@@ -349,6 +436,8 @@ async fn gossiper_loop<B: BlockBuilder>(
                                     {
                                         if transaction.hash_nosigs() != txhash {
                                             log::warn!("({}) get_txx didn't give us something of the right hash", random_peer);
+                                            // no honest peer does this
+                                            cfg.peers.ban(random_peer, BAN_TIME);
                                             continue 'mainloop;
                                         }
                                         known.insert(transaction);
@@ -384,46 +473,75 @@ async fn gossiper_loop<B: BlockBuilder>(
     }
 }
 
+// "evidence" thread
+async fn evidence_loop(
+    transport: Arc<dyn Transport>,
+    runtime: Runtime,
+    cstate: Arc<RwLock<ChainState>>,
+    peers: Arc<PeerManager>,
+) -> ! {
+    loop {
+        runtime.sleep(EVIDENCE_INTERVAL).await;
+        if let Some(random_peer) = peers.pick() {
+            gossip_evidence(transport.as_ref(), &runtime, random_peer, &cstate).await;
+        }
+    }
+}
+
+/// Fetches the equivocation evidence we don't have yet from a peer.
+async fn gossip_evidence(
+    transport: &dyn Transport,
+    runtime: &Runtime,
+    peer: SocketAddr,
+    cstate: &RwLock<ChainState>,
+) {
+    let evidence_req = cstate.read().new_evidence_request();
+    let response = runtime
+        .timeout(
+            transport.get_evidence(peer, evidence_req),
+            Duration::from_secs(10),
+        )
+        .await;
+    match response {
+        None => {
+            metrics::GOSSIP_TIMEOUTS.inc();
+            log::warn!("evidence gossip timed out with {}", peer)
+        }
+        Some(Err(err)) => {
+            metrics::GOSSIP_FAILURES.inc();
+            log::warn!("evidence gossip failed with {}: {:?}", peer, err)
+        }
+        Some(Ok(evidence)) => {
+            let mut cstate = cstate.write();
+            for evidence in evidence {
+                if !cstate.inject_evidence(evidence) {
+                    log::warn!("({}) sent us invalid or known evidence", peer);
+                }
+            }
+        }
+    }
+}
+
 // "gossiper" thread
+#[allow(clippy::too_many_arguments)]
 async fn confirmer_loop(
     my_epoch: u64,
     signing_sk: Ed25519SK,
-    network: melnet::NetState,
+    transport: Arc<dyn Transport>,
+    runtime: Runtime,
     cstate: Arc<RwLock<ChainState>>,
+    known_votes: Arc<RwLock<BTreeMap<u64, UnconfirmedBlock>>>,
+    peers: Arc<PeerManager>,
+    store: Option<Arc<ChainStore>>,
     recv_finalized: Receiver<SealedState>,
     send_confirmed: Sender<ConfirmedState>,
 ) -> Option<()> {
-    let known_votes = Arc::new(RwLock::new(BTreeMap::new()));
-    network.listen("confirm_block", {
-        let known_votes = known_votes.clone();
-        move |req: Request<u64, BTreeMap<Ed25519PK, Vec<u8>>>| {
-            let known_votes = known_votes.clone();
-            NS_EXECUTOR
-                .spawn(async move {
-                    let height = req.body;
-                    let res = known_votes
-                        .read()
-                        .get(&height)
-                        .cloned()
-                        .map(|v: UnconfirmedBlock| v.signatures)
-                        .unwrap_or_default();
-                    log::debug!(
-                        "responding to confirm request for {} with {} sigs",
-                        height,
-                        res.len()
-                    );
-                    req.response.send(Ok(res))
-                })
-                .detach();
-        }
-    });
-
     let (send_fut, recv_fut) = smol::channel::bounded(128);
     let mut confirmed_generator = FuturesOrdered::<Boxed<Option<ConfirmedState>>>::new();
     let _piper = {
         // let cstate = cstate.clone();
         // let known_votes = known_votes.clone();
-        NS_EXECUTOR.spawn(async move {
+        runtime.spawn(async move {
             loop {
                 let start_evt = async {
                     let fut = recv_fut.recv().await.unwrap();
@@ -455,7 +573,20 @@ async fn confirmer_loop(
         }
         log::info!("[[[ {} FINALIZED ]]]", finalized.inner_ref().height);
         let my_header = finalized.header();
-        let own_signature = signing_sk.sign(&finalized.header().hash());
+        let own_signature = match store.as_ref() {
+            Some(store) => match store.sign_confirmation(signing_sk, my_header) {
+                Some(signature) => signature,
+                None => {
+                    log::error!(
+                        "refusing to confirm {:?}, since a different block at height {} was already confirmed",
+                        my_header.hash(),
+                        my_header.height
+                    );
+                    continue;
+                }
+            },
+            None => signing_sk.sign(&my_header.hash()),
+        };
         let sigs = UnconfirmedBlock {
             state: finalized,
             signatures: [(signing_sk.to_public(), own_signature)]
@@ -467,10 +598,13 @@ async fn confirmer_loop(
         known_votes.write().insert(my_height, sigs);
         let known_votes = known_votes.clone();
         let cstate = cstate.clone();
-        let network = network.clone();
+        let transport = transport.clone();
+        let peers = peers.clone();
+        let runtime = runtime.clone();
 
         // This future resolves to either a confirmed block, or nothing. Nothing is when the cstate no longer has this block due to external intervention.
         let confirm_fut = async move {
+            let finalized_at = Instant::now();
             while !known_votes
                 .read()
                 .get(&my_height)
@@ -481,7 +615,8 @@ async fn confirmer_loop(
                     log::warn!("breaking out of confirmation loop due to external intervention");
                     break;
                 }
-                if let Some(random_peer) = network.routes().into_iter().next() {
+                peers.add_peers(transport.peers());
+                if let Some(random_peer) = peers.pick() {
                     // log::debug!(
                     //     "confirming block {} with {}; known votes {:?}",
                     //     my_height,
@@ -494,13 +629,15 @@ async fn confirmer_loop(
                     //         .keys()
                     //         .collect::<Vec<_>>()
                     // );
-                    let their_sigs = melnet::request::<_, BTreeMap<Ed25519PK, Vec<u8>>>(
-                        random_peer,
-                        "symphgossip",
-                        "confirm_block",
-                        my_height,
-                    )
-                    .await;
+                    let start = runtime.now();
+                    let their_sigs = transport.confirm_block(random_peer, my_height).await;
+                    match their_sigs.as_ref() {
+                        Ok(_) => peers.record_success(
+                            random_peer,
+                            runtime.now().duration_since(start).unwrap_or_default(),
+                        ),
+                        Err(_) => peers.record_failure(random_peer),
+                    }
                     let mut known_votes = known_votes.write();
                     let sigs = known_votes.get_mut(&my_height).unwrap();
                     match their_sigs {
@@ -530,11 +667,13 @@ async fn confirmer_loop(
                         ),
                     }
                 }
-                smol::Timer::after(Duration::from_millis(1000)).await;
+                runtime.sleep(Duration::from_millis(1000)).await;
             }
 
             let sigs = known_votes.read().get(&my_height).cloned().unwrap();
             log::info!("[[[ {} CONFIRMED !!! ]]]", &my_height);
+            metrics::CONFIRMATIONS.inc();
+            metrics::CONFIRMATION_LATENCY.observe(finalized_at.elapsed().as_secs_f64());
             Some(sigs.state.confirm(sigs.signatures, None).unwrap())
         };
         send_fut.send(confirm_fut.boxed()).await.unwrap();
@@ -558,22 +697,13 @@ impl UnconfirmedBlock {
     }
 }
 
-async fn wait_until_sys(sys: SystemTime) {
-    let now = SystemTime::now();
-    if sys < now {
-    } else {
-        let interv = sys.duration_since(now).unwrap();
-        smol::Timer::after(interv).await;
-    }
-}
-
 /// waits until the next block height, then returns that height
 fn next_height_time(
     current_height: u64,
     start_time: SystemTime,
     interval: Duration,
+    now: SystemTime,
 ) -> (u64, SystemTime) {
-    let now = SystemTime::now();
     let elapsed_time = now
         .duration_since(start_time)
         .expect("clock randomly jumped, that breaks streamlet");
@@ -588,71 +718,3 @@ fn next_height_time(
         (current_height + 1, next_time)
     }
 }
-
-// a helper function that returns a proposer-calculator for a given epoch, given the SealedState before the epoch.
-fn gen_get_proposer(pre_epoch: SealedState) -> impl Fn(u64) -> Ed25519PK {
-    let end_height = if pre_epoch.inner_ref().height < STAKE_EPOCH {
-        0
-    } else if pre_epoch.inner_ref().height / STAKE_EPOCH
-        != (pre_epoch.inner_ref().height + 1) / STAKE_EPOCH
-    {
-        pre_epoch.inner_ref().height
-    } else {
-        (pre_epoch.inner_ref().height / STAKE_EPOCH * STAKE_EPOCH) - 1
-    };
-    if end_height > 0 {
-        assert!(end_height % STAKE_EPOCH == STAKE_EPOCH - 1)
-    }
-    // majority beacon of all the blocks in the previous epoch
-    let beacon_components = if end_height >= STAKE_EPOCH {
-        (end_height - STAKE_EPOCH..=end_height)
-            .map(|height| pre_epoch.inner_ref().history.get(&height).0.unwrap().hash())
-            .collect::<Vec<_>>()
-    } else {
-        vec![HashVal::default()]
-    };
-    let seed = tmelcrypt::majority_beacon(&beacon_components);
-    let stakes = pre_epoch.inner_ref().stakes.clone();
-    move |height: u64| {
-        // we sum the number of µsyms staked
-        // TODO: overflow?
-        let total_staked = stakes
-            .val_iter()
-            .filter_map(|v| {
-                if v.e_post_end > height / STAKE_EPOCH && v.e_start <= height / STAKE_EPOCH {
-                    Some(v.syms_staked)
-                } else {
-                    None
-                }
-            })
-            .sum::<u128>();
-        // "clamp" the subseed
-        // we hash the seed with the height
-        let mut seed = tmelcrypt::hash_keyed(&height.to_be_bytes(), &seed);
-        let seed = loop {
-            let numseed = u128::from_be_bytes(
-                (&tmelcrypt::hash_keyed(&height.to_be_bytes(), &seed).0[0..16])
-                    .try_into()
-                    .unwrap(),
-            );
-            let numseed = numseed >> total_staked.leading_zeros();
-            if numseed < total_staked {
-                break numseed;
-            }
-            seed = tmelcrypt::hash_single(&seed);
-        };
-        // now we go through the stakedocs
-        let mut stake_docs = stakes.val_iter().collect::<Vec<_>>();
-        stake_docs.sort_by_key(|v| v.pubkey);
-        let mut sum = 0;
-        for stake in stake_docs {
-            if stake.e_post_end > height / STAKE_EPOCH && stake.e_start <= height / STAKE_EPOCH {
-                sum += stake.syms_staked;
-                if seed <= sum {
-                    return stake.pubkey;
-                }
-            }
-        }
-        unreachable!()
-    }
-}
@@ -0,0 +1,88 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use smol::{future::Boxed, prelude::*};
+
+use crate::NS_EXECUTOR;
+
+/// A source of time for the protocol. Abstracted so that simulations can run on virtual time.
+pub trait Clock: Send + Sync + 'static {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+
+    /// Returns a future that resolves once the given duration has passed.
+    fn sleep(&self, duration: Duration) -> Boxed<()>;
+}
+
+/// The real wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Boxed<()> {
+        async move {
+            smol::Timer::after(duration).await;
+        }
+        .boxed()
+    }
+}
+
+/// Where a running protocol spawns its background tasks, and how it tells time. The default runtime uses the novasymph executor and the wall clock.
+#[derive(Clone)]
+pub struct Runtime {
+    executor: Arc<smol::Executor<'static>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new(NS_EXECUTOR.clone(), Arc::new(SystemClock))
+    }
+}
+
+impl Runtime {
+    /// Creates a runtime from an executor and a clock. The executor must be run by somebody else.
+    pub fn new(executor: Arc<smol::Executor<'static>>, clock: Arc<dyn Clock>) -> Self {
+        Self { executor, clock }
+    }
+
+    pub(crate) fn spawn<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> smol::Task<T> {
+        self.executor.spawn(future)
+    }
+
+    pub(crate) fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    pub(crate) async fn sleep(&self, duration: Duration) {
+        self.clock.sleep(duration).await
+    }
+
+    pub(crate) async fn sleep_until(&self, time: SystemTime) {
+        if let Ok(duration) = time.duration_since(self.now()) {
+            self.sleep(duration).await
+        }
+    }
+
+    /// Runs a future, giving up if it doesn't finish within the given duration.
+    pub(crate) async fn timeout<T>(
+        &self,
+        future: impl Future<Output = T>,
+        duration: Duration,
+    ) -> Option<T> {
+        async { Some(future.await) }
+            .or(async {
+                self.sleep(duration).await;
+                None
+            })
+            .await
+    }
+}
@@ -0,0 +1,540 @@
+//! A deterministic, discrete-event simulator for the Symphonia protocol.
+//!
+//! Every staker runs the real protocol loops over a simulated network, on a single-threaded executor and a virtual clock that jumps straight to the next timer whenever every task is idle. With all randomness drawn from the seed, a run is exactly reproducible, so a seed that breaks an invariant can be replayed until the bug is found.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use parking_lot::Mutex;
+use peermgr::PeerManager;
+use themelio_stf::{
+    Block, CoinData, Denom, GenesisConfig, NetID, ProposerAction, SealedState, StakeDoc, State,
+    Transaction, TxKind,
+};
+use thiserror::Error;
+use tmelcrypt::{Ed25519SK, HashVal};
+
+use crate::{
+    evidence::Evidence,
+    protocol::{BlockBuilder, EpochConfig, EpochProtocol},
+    runtime::{Clock, Runtime},
+    transport::Transport,
+};
+
+mod byzantine;
+mod clock;
+mod network;
+pub use byzantine::Byzantine;
+use byzantine::{BuiltBlocks, ByzantineTransport};
+use clock::SimClock;
+use network::SimNetwork;
+
+/// Configuration for a simulation run.
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// Seed for all the randomness in the run, including the stakers' keys.
+    pub seed: u64,
+    /// Number of stakers, all with equal stake.
+    pub stakers: usize,
+    /// Length of a round.
+    pub interval: Duration,
+    /// How much virtual time to simulate.
+    pub duration: Duration,
+    /// Range from which the one-way latency of every message is uniformly drawn.
+    pub latency: (Duration, Duration),
+    /// Probability that any request is lost.
+    pub drop_rate: f64,
+    /// Events to inject, with the virtual time at which they happen.
+    pub schedule: Vec<(Duration, SimEvent)>,
+    /// Stakers that misbehave, and how. Everybody else is honest.
+    pub byzantine: BTreeMap<usize, Byzantine>,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            stakers: 4,
+            interval: Duration::from_secs(10),
+            duration: Duration::from_secs(300),
+            latency: (Duration::from_millis(10), Duration::from_millis(200)),
+            drop_rate: 0.0,
+            schedule: vec![],
+            byzantine: BTreeMap::new(),
+        }
+    }
+}
+
+/// Something that happens to the simulated network or its nodes. Nodes are identified by their index.
+#[derive(Clone, Debug)]
+pub enum SimEvent {
+    /// The node crashes, losing all its state.
+    Crash(usize),
+    /// A crashed node restarts from genesis.
+    Restart(usize),
+    /// The network splits, so that only nodes on the same side can talk. Nodes not on any side are cut off from everybody.
+    Partition(Vec<Vec<usize>>),
+    /// All partitions are removed.
+    Heal,
+    /// The latency range changes.
+    SetLatency(Duration, Duration),
+    /// The drop rate changes.
+    SetDropRate(f64),
+}
+
+/// What happened in a simulation run.
+#[derive(Clone, Debug)]
+pub struct SimReport {
+    /// For every node, the height and hash of every block it confirmed, in order. A node that restarted confirms blocks from genesis again.
+    pub confirmed: Vec<Vec<(u64, HashVal)>>,
+    /// Which nodes were running at the end.
+    pub running: Vec<bool>,
+    /// Which nodes were honest.
+    pub honest: Vec<bool>,
+    /// The equivocation evidence known to the running nodes at the end.
+    pub evidence: Vec<Evidence>,
+}
+
+/// A broken invariant in a simulation run.
+#[derive(Error, Debug)]
+pub enum SimViolation {
+    #[error("nodes {first} and {second} confirmed conflicting blocks at height {height}")]
+    Conflict {
+        height: u64,
+        first: usize,
+        second: usize,
+    },
+    #[error("node {node} only confirmed up to height {height}, less than {expected}")]
+    Stalled {
+        node: usize,
+        height: u64,
+        expected: u64,
+    },
+}
+
+impl SimReport {
+    /// Checks that no two honest nodes, nor one honest node across restarts, confirmed different blocks at the same height.
+    pub fn check_safety(&self) -> Result<(), SimViolation> {
+        let mut seen: BTreeMap<u64, (HashVal, usize)> = BTreeMap::new();
+        for node in (0..self.confirmed.len()).filter(|node| self.honest[*node]) {
+            for (height, hash) in self.confirmed[node].iter() {
+                let (seen_hash, seen_node) = *seen.entry(*height).or_insert((*hash, node));
+                if seen_hash != *hash {
+                    return Err(SimViolation::Conflict {
+                        height: *height,
+                        first: seen_node,
+                        second: node,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every honest node running at the end confirmed blocks up to at least the given height.
+    pub fn check_liveness(&self, min_height: u64) -> Result<(), SimViolation> {
+        for node in
+            (0..self.confirmed.len()).filter(|node| self.running[*node] && self.honest[*node])
+        {
+            let height = self.highest_confirmed(node);
+            if height < min_height {
+                return Err(SimViolation::Stalled {
+                    node,
+                    height,
+                    expected: min_height,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The highest height the given node confirmed.
+    pub fn highest_confirmed(&self, node: usize) -> u64 {
+        self.confirmed[node]
+            .iter()
+            .map(|(height, _)| *height)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// Runs a simulation to completion.
+pub fn simulate(cfg: SimConfig) -> SimReport {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let end = start + cfg.duration;
+    let clock = SimClock::new(start);
+    let executor = Arc::new(smol::Executor::new());
+    let runtime = Runtime::new(executor.clone(), Arc::new(clock.clone()));
+
+    let skk = (0..cfg.stakers)
+        .map(|idx| sim_key(cfg.seed, idx))
+        .collect::<Vec<_>>();
+    let addrs = (0..cfg.stakers)
+        .map(|idx| SocketAddr::from(([10, 0, 0, 1], 10000 + idx as u16)))
+        .collect::<Vec<_>>();
+    let network = SimNetwork::new(cfg.seed, clock.clone(), addrs.clone());
+    network.set_latency(cfg.latency.0, cfg.latency.1);
+    network.set_drop_rate(cfg.drop_rate);
+    let forest = novasmt::Forest::new(novasmt::InMemoryBackend::default());
+    let genesis = staked_genesis(&forest, &skk);
+    let confirmed = Arc::new(Mutex::new(vec![vec![]; cfg.stakers]));
+
+    let start_node = |idx: usize| {
+        let built = BuiltBlocks::default();
+        let config = EpochConfig {
+            listen: addrs[idx],
+            bootstrap: vec![],
+            genesis: genesis.clone(),
+            forest: forest.clone(),
+            start_time: start,
+            interval: cfg.interval,
+            signing_sk: skk[idx],
+            builder: SimBlockBuilder {
+                built: built.clone(),
+            },
+            get_confirmed: Box::new(|_| None),
+            peers: Arc::new(PeerManager::with_clock(cfg.seed ^ idx as u64, {
+                let clock = clock.clone();
+                move || clock.now()
+            })),
+            store: None,
+        };
+        let transport: Arc<dyn Transport> = match cfg.byzantine.get(&idx) {
+            Some(behavior) => Arc::new(ByzantineTransport::new(
+                network.transport(addrs[idx]),
+                *behavior,
+                skk[idx],
+                genesis.header().hash(),
+                built,
+                cfg.seed ^ idx as u64,
+            )),
+            None => Arc::new(network.transport(addrs[idx])),
+        };
+        let protocol = Arc::new(EpochProtocol::new_with_runtime(
+            config,
+            transport,
+            runtime.clone(),
+        ));
+        let collector = executor.spawn({
+            let protocol = protocol.clone();
+            let confirmed = confirmed.clone();
+            async move {
+                loop {
+                    let state = protocol.next_confirmed().await;
+                    let header = state.inner().header();
+                    confirmed.lock()[idx].push((header.height, header.hash()));
+                }
+            }
+        });
+        SimNode {
+            protocol,
+            _collector: collector,
+        }
+    };
+    let mut nodes = (0..cfg.stakers)
+        .map(|idx| Some(start_node(idx)))
+        .collect::<Vec<_>>();
+
+    let mut schedule = cfg.schedule.clone();
+    schedule.sort_by_key(|(time, _)| *time);
+    let mut schedule = schedule
+        .into_iter()
+        .map(|(time, event)| (start + time, event))
+        .collect::<VecDeque<_>>();
+    loop {
+        while executor.try_tick() {}
+        while let Some((_, event)) = schedule
+            .front()
+            .filter(|(time, _)| *time <= clock.now())
+            .cloned()
+        {
+            schedule.pop_front();
+            log::debug!("simulation event at {:?}: {:?}", clock.now(), event);
+            match event {
+                SimEvent::Crash(idx) => nodes[idx] = None,
+                SimEvent::Restart(idx) => {
+                    if nodes[idx].is_none() {
+                        nodes[idx] = Some(start_node(idx))
+                    }
+                }
+                SimEvent::Partition(sides) => network.partition(
+                    &sides
+                        .iter()
+                        .map(|side| side.iter().map(|idx| addrs[*idx]).collect())
+                        .collect::<Vec<_>>(),
+                ),
+                SimEvent::Heal => network.heal(),
+                SimEvent::SetLatency(min, max) => network.set_latency(min, max),
+                SimEvent::SetDropRate(drop_rate) => network.set_drop_rate(drop_rate),
+            }
+        }
+        if clock.now() >= end {
+            break;
+        }
+        let limit = schedule
+            .front()
+            .map(|(time, _)| *time)
+            .unwrap_or(end)
+            .min(end);
+        clock.advance(limit);
+    }
+
+    let mut evidence = BTreeMap::new();
+    for node in nodes.iter().flatten() {
+        for ev in node.protocol.evidence() {
+            evidence.insert(ev.hash(), ev);
+        }
+    }
+    let running = nodes.iter().map(|node| node.is_some()).collect();
+    let honest = (0..cfg.stakers)
+        .map(|idx| !cfg.byzantine.contains_key(&idx))
+        .collect();
+    drop(nodes);
+    // let the cancelled tasks clean up
+    while executor.try_tick() {}
+    let confirmed = confirmed.lock().clone();
+    SimReport {
+        confirmed,
+        running,
+        honest,
+        evidence: evidence.into_values().collect(),
+    }
+}
+
+struct SimNode {
+    protocol: Arc<EpochProtocol>,
+    _collector: smol::Task<()>,
+}
+
+/// Proposes blocks with a single faucet transaction, so that gossip has to fetch transactions too. Remembers what it built, for byzantine stakers to tamper with.
+struct SimBlockBuilder {
+    built: BuiltBlocks,
+}
+
+impl BlockBuilder for SimBlockBuilder {
+    fn build_block(&self, tip: SealedState) -> Block {
+        let mut state = tip.next_state();
+        let height = state.height;
+        let fee = faucet_tx(height, u64::MAX.into()).base_fee(state.fee_multiplier, 0) * 2;
+        state
+            .apply_tx(&faucet_tx(height, fee))
+            .expect("faucet transaction doesn't apply");
+        let block = state
+            .seal(Some(ProposerAction {
+                fee_multiplier_delta: 0,
+                reward_dest: HashVal::default().into(),
+            }))
+            .to_block();
+        let mut built = self.built.lock();
+        built.insert(height, (tip, block.clone()));
+        // nobody asks about blocks this old
+        while built.len() > 64 {
+            built.pop_first();
+        }
+        block
+    }
+}
+
+/// Creates a faucet transaction, unique to the given nonce, that destroys its output.
+fn faucet_tx(nonce: u64, fee: u128) -> Transaction {
+    Transaction {
+        kind: TxKind::Faucet,
+        inputs: vec![],
+        outputs: vec![CoinData {
+            denom: Denom::Mel,
+            value: 1,
+            additional_data: vec![],
+            covhash: HashVal::default().into(),
+        }],
+        fee,
+        scripts: vec![],
+        data: nonce.to_be_bytes().to_vec(),
+        sigs: vec![],
+    }
+}
+
+/// Deterministically derives the key of a staker from the seed.
+fn sim_key(seed: u64, idx: usize) -> Ed25519SK {
+    let secret = tmelcrypt::hash_keyed(
+        b"novasymph-sim",
+        [seed.to_be_bytes(), (idx as u64).to_be_bytes()].concat(),
+    );
+    let secret = ed25519_dalek::SecretKey::from_bytes(&secret.0).unwrap();
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    Ed25519SK::from_bytes(&[secret.to_bytes(), public.to_bytes()].concat()).unwrap()
+}
+
+/// Creates a genesis state staked equally between the given keys.
+pub(crate) fn staked_genesis(forest: &novasmt::Forest, skk: &[Ed25519SK]) -> SealedState {
+    State::genesis(
+        forest,
+        GenesisConfig {
+            network: NetID::Testnet,
+            init_coindata: CoinData {
+                denom: Denom::Mel,
+                value: 1 << 64,
+                additional_data: vec![],
+                covhash: HashVal::default().into(),
+            },
+            init_fee_pool: 1 << 64,
+            stakes: skk
+                .iter()
+                .map(|sk| {
+                    (
+                        tmelcrypt::hash_single(sk.to_public().0).into(),
+                        StakeDoc {
+                            pubkey: sk.to_public(),
+                            e_start: 0,
+                            e_post_end: 100000,
+                            syms_staked: 1,
+                        },
+                    )
+                })
+                .collect(),
+        },
+    )
+    .seal(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn healthy_network() {
+        let report = simulate(SimConfig::default());
+        report.check_safety().unwrap();
+        report.check_liveness(20).unwrap();
+        assert!(report.evidence.is_empty());
+    }
+
+    #[test]
+    fn deterministic() {
+        let cfg = SimConfig {
+            seed: 7,
+            duration: Duration::from_secs(150),
+            drop_rate: 0.1,
+            ..Default::default()
+        };
+        let first = simulate(cfg.clone());
+        let second = simulate(cfg);
+        assert_eq!(first.confirmed, second.confirmed);
+        first.check_safety().unwrap();
+    }
+
+    #[test]
+    fn partition_heals() {
+        let secs = Duration::from_secs;
+        let report = simulate(SimConfig {
+            seed: 1,
+            schedule: vec![
+                (secs(50), SimEvent::Partition(vec![vec![0, 1], vec![2, 3]])),
+                (secs(150), SimEvent::Heal),
+            ],
+            ..Default::default()
+        });
+        report.check_safety().unwrap();
+        // neither side has enough stake to make progress on its own, and each may notarize a different fork, but everybody converges after healing
+        report.check_liveness(20).unwrap();
+    }
+
+    #[test]
+    fn crash_restart() {
+        let secs = Duration::from_secs;
+        let report = simulate(SimConfig {
+            seed: 2,
+            schedule: vec![
+                (secs(60), SimEvent::Crash(3)),
+                (secs(150), SimEvent::Restart(3)),
+            ],
+            ..Default::default()
+        });
+        report.check_safety().unwrap();
+        report.check_liveness(20).unwrap();
+    }
+
+    /// Returns the staker scheduled to propose the first block, so that misbehaving proposers actually get to propose.
+    fn first_proposer(seed: u64) -> usize {
+        let skk = (0..SimConfig::default().stakers)
+            .map(|idx| sim_key(seed, idx))
+            .collect::<Vec<_>>();
+        let forest = novasmt::Forest::new(novasmt::InMemoryBackend::default());
+        let proposer = crate::cstate::ChainState::new(staked_genesis(&forest, &skk), forest)
+            .get_proposer(1)
+            .unwrap();
+        skk.iter()
+            .position(|sk| sk.to_public() == proposer)
+            .unwrap()
+    }
+
+    /// Runs the default scenario with one staker misbehaving, which is less than a third of the stake. Returns the report and the misbehaving staker.
+    fn one_byzantine(seed: u64, behavior: Byzantine) -> (SimReport, usize) {
+        let idx = first_proposer(seed);
+        let report = simulate(SimConfig {
+            seed,
+            byzantine: std::iter::once((idx, behavior)).collect(),
+            ..Default::default()
+        });
+        report.check_safety().unwrap();
+        report.check_liveness(15).unwrap();
+        (report, idx)
+    }
+
+    #[test]
+    fn byzantine_equivocate() {
+        let (report, idx) = one_byzantine(3, Byzantine::Equivocate);
+        let offender = sim_key(3, idx).to_public();
+        assert!(report.evidence.iter().any(|ev| ev.offender() == offender));
+        assert!(report.evidence.iter().all(|ev| ev.offender() == offender));
+    }
+
+    #[test]
+    fn byzantine_withhold_votes() {
+        one_byzantine(4, Byzantine::WithholdVotes);
+    }
+
+    #[test]
+    fn byzantine_vote_for_everything() {
+        one_byzantine(5, Byzantine::VoteForEverything);
+    }
+
+    #[test]
+    fn byzantine_invalid_proposals() {
+        one_byzantine(6, Byzantine::InvalidProposals);
+    }
+
+    #[test]
+    fn byzantine_stale_last_nonempty() {
+        one_byzantine(7, Byzantine::StaleLastNonempty);
+    }
+
+    #[test]
+    fn byzantine_replay_gossip() {
+        one_byzantine(8, Byzantine::ReplayGossip);
+    }
+
+    #[test]
+    fn byzantine_bogus_transactions() {
+        one_byzantine(9, Byzantine::BogusTransactions);
+    }
+
+    #[test]
+    fn byzantine_beyond_bound() {
+        // with half the stake withholding votes, the honest half can never notarize anything, although nothing unsafe happens either
+        let report = simulate(SimConfig {
+            seed: 10,
+            duration: Duration::from_secs(150),
+            byzantine: [(2, Byzantine::WithholdVotes), (3, Byzantine::WithholdVotes)]
+                .iter()
+                .copied()
+                .collect(),
+            ..Default::default()
+        });
+        report.check_safety().unwrap();
+        assert!(report.check_liveness(1).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use themelio_stf::{Block, SealedState};
+use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal};
+
+use super::faucet_tx;
+use crate::{
+    evidence::Evidence,
+    msg::{ProposalSig, VoteSig},
+    transport::{
+        AbbrBlockResponse, BlockRequest, EvidenceRequest, Responder, TransactionRequest,
+        TransactionResponse, Transport,
+    },
+};
+
+/// How many old block responses a [Byzantine::ReplayGossip] staker remembers.
+const REPLAY_HISTORY: usize = 64;
+
+/// An adversarial behaviour that a simulated staker can be given.
+///
+/// Byzantine stakers run the honest protocol internally, but tamper with what they serve to their peers. Every behaviour here must be tolerated, both for safety and for liveness, as long as the byzantine stakers together hold less than a third of the stake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Byzantine {
+    /// Serves a conflicting, but valid, twin of its own proposals to about half of the requests, and votes for both.
+    Equivocate,
+    /// Never shares its votes or its confirmation signatures.
+    WithholdVotes,
+    /// Votes for every block it serves, whether or not it extends the longest notarized chain.
+    VoteForEverything,
+    /// Serves its own proposals with a corrupted header, so that they don't apply.
+    InvalidProposals,
+    /// Serves its own proposals claiming to extend genesis, rather than the block they actually extend.
+    StaleLastNonempty,
+    /// Answers about half of the block requests with an old response instead of a fresh one.
+    ReplayGossip,
+    /// Serves transactions that don't match the hashes they were requested by.
+    BogusTransactions,
+}
+
+/// The blocks a staker built, by height, along with the state they were built upon.
+pub(super) type BuiltBlocks = Arc<Mutex<BTreeMap<u64, (SealedState, Block)>>>;
+
+/// A transport that makes its node misbehave according to a [Byzantine] behaviour.
+pub(super) struct ByzantineTransport<T: Transport> {
+    inner: T,
+    state: Arc<ByzantineState>,
+}
+
+struct ByzantineState {
+    behavior: Byzantine,
+    signing_sk: Ed25519SK,
+    genesis: HashVal,
+    built: BuiltBlocks,
+    rng: Mutex<fastrand::Rng>,
+    // forged blocks by their hash, so that we can answer transaction requests about them
+    forged: Mutex<BTreeMap<HashVal, Block>>,
+    history: Mutex<VecDeque<Vec<AbbrBlockResponse>>>,
+}
+
+impl<T: Transport> ByzantineTransport<T> {
+    /// Wraps a transport. `built` must be shared with the node's block builder, and `genesis` is the hash of the genesis block.
+    pub fn new(
+        inner: T,
+        behavior: Byzantine,
+        signing_sk: Ed25519SK,
+        genesis: HashVal,
+        built: BuiltBlocks,
+        seed: u64,
+    ) -> Self {
+        Self {
+            inner,
+            state: Arc::new(ByzantineState {
+                behavior,
+                signing_sk,
+                genesis,
+                built,
+                rng: Mutex::new(fastrand::Rng::with_seed(seed)),
+                forged: Default::default(),
+                history: Default::default(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Transport> Transport for ByzantineTransport<T> {
+    async fn serve(&self, responder: Arc<dyn Responder>) {
+        self.inner
+            .serve(Arc::new(ByzantineResponder {
+                inner: responder,
+                state: self.state.clone(),
+            }))
+            .await
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.inner.peers()
+    }
+
+    async fn get_blocks(
+        &self,
+        peer: SocketAddr,
+        request: BlockRequest,
+    ) -> anyhow::Result<Vec<AbbrBlockResponse>> {
+        self.inner.get_blocks(peer, request).await
+    }
+
+    async fn get_txx(
+        &self,
+        peer: SocketAddr,
+        request: TransactionRequest,
+    ) -> anyhow::Result<TransactionResponse> {
+        self.inner.get_txx(peer, request).await
+    }
+
+    async fn get_evidence(
+        &self,
+        peer: SocketAddr,
+        request: EvidenceRequest,
+    ) -> anyhow::Result<Vec<Evidence>> {
+        self.inner.get_evidence(peer, request).await
+    }
+
+    async fn confirm_block(
+        &self,
+        peer: SocketAddr,
+        height: u64,
+    ) -> anyhow::Result<BTreeMap<Ed25519PK, Vec<u8>>> {
+        self.inner.confirm_block(peer, height).await
+    }
+}
+
+struct ByzantineResponder {
+    inner: Arc<dyn Responder>,
+    state: Arc<ByzantineState>,
+}
+
+impl ByzantineState {
+    fn me(&self) -> Ed25519PK {
+        self.signing_sk.to_public()
+    }
+
+    /// Returns the block we built for the given response, if the response is about one of our own proposals.
+    fn own_proposal(&self, response: &AbbrBlockResponse) -> Option<(SealedState, Block)> {
+        if response.metadata.proposer != self.me() {
+            return None;
+        }
+        let built = self.built.lock();
+        let (parent, block) = built.get(&response.abbr_block.header.height)?;
+        if block.header.hash() != response.abbr_block.header.hash() {
+            return None;
+        }
+        Some((parent.clone(), block.clone()))
+    }
+
+    /// Replaces the block in a response with a forged one, signed and voted for by us.
+    fn forge(&self, mut response: AbbrBlockResponse, block: Block) -> AbbrBlockResponse {
+        let abbr_block = block.abbreviate();
+        let hash = block.header.hash();
+        response.metadata.proposal_sig = ProposalSig::generate(self.signing_sk, &abbr_block);
+        response.metadata.votes =
+            std::iter::once((self.me(), VoteSig::generate(self.signing_sk, hash))).collect();
+        response.abbr_block = abbr_block;
+        self.forged.lock().insert(hash, block);
+        response
+    }
+
+    fn tamper(&self, response: AbbrBlockResponse) -> AbbrBlockResponse {
+        match self.behavior {
+            Byzantine::Equivocate => match self.own_proposal(&response) {
+                Some((parent, block)) if self.rng.lock().bool() => {
+                    // the twin has the same transactions but a different proposer action, so it's just as valid
+                    let mut twin = parent.next_state();
+                    twin.apply_tx_batch(&block.transactions.iter().cloned().collect::<Vec<_>>())
+                        .expect("twin of our own block doesn't apply");
+                    let mut action = block.proposer_action.expect("proposal without an action");
+                    action.fee_multiplier_delta = action.fee_multiplier_delta.wrapping_add(64);
+                    self.forge(response, twin.seal(Some(action)).to_block())
+                }
+                _ => response,
+            },
+            Byzantine::WithholdVotes => {
+                let mut response = response;
+                response.metadata.votes.remove(&self.me());
+                response
+            }
+            Byzantine::VoteForEverything => {
+                let mut response = response;
+                let hash = response.abbr_block.header.hash();
+                response
+                    .metadata
+                    .votes
+                    .entry(self.me())
+                    .or_insert_with(|| VoteSig::generate(self.signing_sk, hash));
+                response
+            }
+            Byzantine::InvalidProposals => match self.own_proposal(&response) {
+                Some((_, mut block)) => {
+                    block.header.fee_pool += 1;
+                    self.forge(response, block)
+                }
+                None => response,
+            },
+            Byzantine::StaleLastNonempty => {
+                let mut response = response;
+                if self.own_proposal(&response).is_some() {
+                    response.last_nonempty = self.genesis;
+                }
+                response
+            }
+            Byzantine::ReplayGossip | Byzantine::BogusTransactions => response,
+        }
+    }
+}
+
+impl Responder for ByzantineResponder {
+    fn get_blocks(&self, request: BlockRequest) -> Vec<AbbrBlockResponse> {
+        let state = &self.state;
+        let responses = self.inner.get_blocks(request);
+        if state.behavior == Byzantine::ReplayGossip {
+            let mut history = state.history.lock();
+            history.push_back(responses.clone());
+            if history.len() > REPLAY_HISTORY {
+                history.pop_front();
+            }
+            if state.rng.lock().bool() {
+                return history[state.rng.lock().usize(..history.len())].clone();
+            }
+            return responses;
+        }
+        responses.into_iter().map(|r| state.tamper(r)).collect()
+    }
+
+    fn get_txx(&self, request: TransactionRequest) -> TransactionResponse {
+        let state = &self.state;
+        let forged = state.forged.lock().get(&request.block_hash).cloned();
+        let mut response = if let Some(block) = forged {
+            TransactionResponse {
+                transactions: request
+                    .hashes
+                    .iter()
+                    .filter_map(|txhash| {
+                        block
+                            .transactions
+                            .iter()
+                            .find(|tx| tx.hash_nosigs() == *txhash)
+                            .cloned()
+                    })
+                    .collect(),
+            }
+        } else {
+            self.inner.get_txx(request)
+        };
+        if state.behavior == Byzantine::BogusTransactions {
+            for tx in response.transactions.iter_mut() {
+                *tx = faucet_tx(state.rng.lock().u64(..), tx.fee);
+            }
+        }
+        response
+    }
+
+    fn get_evidence(&self, request: EvidenceRequest) -> Vec<Evidence> {
+        self.inner.get_evidence(request)
+    }
+
+    fn confirm_block(&self, height: u64) -> BTreeMap<Ed25519PK, Vec<u8>> {
+        let mut sigs = self.inner.confirm_block(height);
+        if self.state.behavior == Byzantine::WithholdVotes {
+            sigs.remove(&self.state.me());
+        }
+        sigs
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::{Duration, SystemTime},
+};
+
+use parking_lot::Mutex;
+use smol::{future::Boxed, prelude::*};
+
+use crate::runtime::Clock;
+
+/// A virtual clock, which only moves when the simulation advances it.
+#[derive(Clone)]
+pub(super) struct SimClock {
+    state: Arc<Mutex<ClockState>>,
+}
+
+struct ClockState {
+    now: SystemTime,
+    next_id: u64,
+    timers: BTreeMap<(SystemTime, u64), Waker>,
+}
+
+impl SimClock {
+    /// Creates a clock that starts at the given time.
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ClockState {
+                now: start,
+                next_id: 0,
+                timers: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Advances the clock to the earliest pending timer, or to `limit` if there is no timer due before then, waking every timer that is due.
+    pub fn advance(&self, limit: SystemTime) {
+        let state = &mut *self.state.lock();
+        let next = state
+            .timers
+            .keys()
+            .next()
+            .map(|(deadline, _)| *deadline)
+            .filter(|deadline| *deadline <= limit)
+            .unwrap_or(limit);
+        state.now = state.now.max(next);
+        while let Some(entry) = state.timers.first_entry() {
+            if entry.key().0 > state.now {
+                break;
+            }
+            entry.remove().wake();
+        }
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> SystemTime {
+        self.state.lock().now
+    }
+
+    fn sleep(&self, duration: Duration) -> Boxed<()> {
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        Sleep {
+            state: self.state.clone(),
+            key: (state.now + duration, id),
+        }
+        .boxed()
+    }
+}
+
+/// A future that resolves once the virtual clock reaches its deadline.
+struct Sleep {
+    state: Arc<Mutex<ClockState>>,
+    key: (SystemTime, u64),
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock();
+        if state.now >= self.key.0 {
+            Poll::Ready(())
+        } else {
+            state.timers.insert(self.key, cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.state.lock().timers.remove(&self.key);
+    }
+}
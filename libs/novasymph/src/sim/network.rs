@@ -0,0 +1,215 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use tmelcrypt::Ed25519PK;
+
+use super::clock::SimClock;
+use crate::{
+    evidence::Evidence,
+    runtime::Clock,
+    transport::{
+        AbbrBlockResponse, BlockRequest, EvidenceRequest, Responder, TransactionRequest,
+        TransactionResponse, Transport,
+    },
+};
+
+/// A simulated network with latency, message loss and partitions. All randomness comes from one seeded RNG, so that runs are reproducible.
+#[derive(Clone)]
+pub(super) struct SimNetwork {
+    state: Arc<Mutex<NetworkState>>,
+    clock: SimClock,
+    addrs: Arc<Vec<SocketAddr>>,
+}
+
+struct NetworkState {
+    rng: fastrand::Rng,
+    responders: BTreeMap<SocketAddr, Arc<dyn Responder>>,
+    latency: (Duration, Duration),
+    drop_rate: f64,
+    // which side of a partition every node is on; empty when there's no partition
+    sides: HashMap<SocketAddr, usize>,
+}
+
+impl SimNetwork {
+    /// Creates a network between the given addresses.
+    pub fn new(seed: u64, clock: SimClock, addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NetworkState {
+                rng: fastrand::Rng::with_seed(seed),
+                responders: BTreeMap::new(),
+                latency: (Duration::from_millis(0), Duration::from_millis(0)),
+                drop_rate: 0.0,
+                sides: HashMap::new(),
+            })),
+            clock,
+            addrs: Arc::new(addrs),
+        }
+    }
+
+    /// Creates the transport for the node at the given address.
+    pub fn transport(&self, addr: SocketAddr) -> SimTransport {
+        SimTransport {
+            network: self.clone(),
+            addr,
+        }
+    }
+
+    /// Sets the range from which the one-way latency of every message is uniformly drawn.
+    pub fn set_latency(&self, min: Duration, max: Duration) {
+        self.state.lock().latency = (min, max.max(min));
+    }
+
+    /// Sets the probability that a request is lost.
+    pub fn set_drop_rate(&self, drop_rate: f64) {
+        self.state.lock().drop_rate = drop_rate;
+    }
+
+    /// Partitions the network, so that only nodes on the same side can talk. Nodes not on any side are cut off from everybody.
+    pub fn partition(&self, sides: &[Vec<SocketAddr>]) {
+        let mut state = self.state.lock();
+        state.sides = sides
+            .iter()
+            .enumerate()
+            .flat_map(|(side, addrs)| addrs.iter().map(move |addr| (*addr, side)))
+            .collect();
+        // everybody else gets a side of their own
+        for (i, addr) in self.addrs.iter().enumerate() {
+            state.sides.entry(*addr).or_insert(sides.len() + i);
+        }
+    }
+
+    /// Removes all partitions.
+    pub fn heal(&self) {
+        self.state.lock().sides.clear();
+    }
+
+    /// Draws a one-way latency.
+    fn latency(&self) -> Duration {
+        let state = self.state.lock();
+        let (min, max) = state.latency;
+        let millis = state
+            .rng
+            .u64(min.as_millis() as u64..=max.as_millis() as u64);
+        Duration::from_millis(millis)
+    }
+
+    /// Delivers a request from one node to another, and its response back.
+    async fn deliver<T>(
+        &self,
+        from: SocketAddr,
+        to: SocketAddr,
+        handle: impl FnOnce(&dyn Responder) -> T,
+    ) -> anyhow::Result<T> {
+        self.clock.sleep(self.latency()).await;
+        let responder = {
+            let state = self.state.lock();
+            let drop_rate = state.drop_rate;
+            if state.rng.f64() < drop_rate {
+                anyhow::bail!("request from {} to {} lost", from, to)
+            }
+            if state.sides.get(&from) != state.sides.get(&to) {
+                anyhow::bail!("{} and {} are partitioned", from, to)
+            }
+            state
+                .responders
+                .get(&to)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("{} is down", to))?
+        };
+        let response = handle(responder.as_ref());
+        self.clock.sleep(self.latency()).await;
+        Ok(response)
+    }
+}
+
+/// One node's view of a [SimNetwork].
+pub(super) struct SimTransport {
+    network: SimNetwork,
+    addr: SocketAddr,
+}
+
+/// Takes a node off the network when it stops serving, e.g. because it crashed.
+struct Registration {
+    network: SimNetwork,
+    addr: SocketAddr,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.network.state.lock().responders.remove(&self.addr);
+    }
+}
+
+#[async_trait]
+impl Transport for SimTransport {
+    async fn serve(&self, responder: Arc<dyn Responder>) {
+        self.network
+            .state
+            .lock()
+            .responders
+            .insert(self.addr, responder);
+        let _registration = Registration {
+            network: self.network.clone(),
+            addr: self.addr,
+        };
+        smol::future::pending().await
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers = self
+            .network
+            .addrs
+            .iter()
+            .copied()
+            .filter(|addr| *addr != self.addr)
+            .collect::<Vec<_>>();
+        self.network.state.lock().rng.shuffle(&mut peers);
+        peers
+    }
+
+    async fn get_blocks(
+        &self,
+        peer: SocketAddr,
+        request: BlockRequest,
+    ) -> anyhow::Result<Vec<AbbrBlockResponse>> {
+        self.network
+            .deliver(self.addr, peer, |r| r.get_blocks(request))
+            .await
+    }
+
+    async fn get_txx(
+        &self,
+        peer: SocketAddr,
+        request: TransactionRequest,
+    ) -> anyhow::Result<TransactionResponse> {
+        self.network
+            .deliver(self.addr, peer, |r| r.get_txx(request))
+            .await
+    }
+
+    async fn get_evidence(
+        &self,
+        peer: SocketAddr,
+        request: EvidenceRequest,
+    ) -> anyhow::Result<Vec<Evidence>> {
+        self.network
+            .deliver(self.addr, peer, |r| r.get_evidence(request))
+            .await
+    }
+
+    async fn confirm_block(
+        &self,
+        peer: SocketAddr,
+        height: u64,
+    ) -> anyhow::Result<BTreeMap<Ed25519PK, Vec<u8>>> {
+        self.network
+            .deliver(self.addr, peer, |r| r.confirm_block(height))
+            .await
+    }
+}
@@ -0,0 +1,163 @@
+use blkdb::traits::{DbBackend, WriteBatch};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use themelio_stf::{Block, Header};
+use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal};
+
+use crate::msg::{ProposalSig, VoteSig};
+
+const PROPOSAL_PREFIX: u8 = b'b';
+const VOTE_PREFIX: u8 = b'v';
+const CONFIRMATION_PREFIX: u8 = b'c';
+
+/// Durable backing for the consensus state of a staker, so that it survives restarts.
+///
+/// Every proposal and vote that the chain state accepts is written here before anybody else can see it. In particular, this is a write-ahead log of the staker's own proposals, votes and confirmation signatures: after a restart, the staker reloads them and never signs anything conflicting. For this to hold, those writes must be durable by the time they return.
+pub struct ChainStore {
+    backend: Mutex<Box<dyn DbBackend>>,
+    // if the backend buffers writes, the staker whose own messages must be flushed, and how to flush them
+    flush: Option<(Ed25519PK, Box<dyn Fn() + Send + Sync>)>,
+}
+
+/// A proposal, as persisted in a [ChainStore].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct StoredProposal {
+    pub block: Block,
+    pub proposer: Ed25519PK,
+    pub proposal_sig: ProposalSig,
+    pub last_nonempty: HashVal,
+}
+
+/// A vote, as persisted in a [ChainStore].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct StoredVote {
+    pub header: Header,
+    pub voter: Ed25519PK,
+    pub sig: VoteSig,
+}
+
+impl ChainStore {
+    /// Creates a store over the given backend, which may already contain data from a previous run. Every write to the backend must be durable by the time it returns.
+    pub fn new(backend: impl DbBackend) -> Self {
+        Self {
+            backend: Mutex::new(Box::new(backend)),
+            flush: None,
+        }
+    }
+
+    /// Creates a store over a backend that buffers writes, calling `flush` to make them durable. Only the writes that guard against equivocation are flushed: the proposals and votes of `owner`, and confirmations. Losing other stakers' messages in a crash is harmless, since they can be heard again from the network.
+    pub fn buffered(
+        backend: impl DbBackend,
+        owner: Ed25519PK,
+        flush: impl Fn() + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            backend: Mutex::new(Box::new(backend)),
+            flush: Some((owner, Box::new(flush))),
+        }
+    }
+
+    /// Makes the writes so far durable if the given staker's messages need to be.
+    fn flush_for(&self, signer: Option<Ed25519PK>) {
+        if let Some((owner, flush)) = self.flush.as_ref() {
+            if signer.map(|signer| signer == *owner).unwrap_or(true) {
+                flush()
+            }
+        }
+    }
+
+    /// Persists a proposal.
+    pub(crate) fn insert_proposal(&self, proposal: &StoredProposal) {
+        let key = key(
+            PROPOSAL_PREFIX,
+            proposal.block.header.height,
+            &proposal.block.header.hash(),
+        );
+        self.backend
+            .lock()
+            .insert(&key, &stdcode::serialize(proposal).unwrap());
+        self.flush_for(Some(proposal.proposer));
+    }
+
+    /// Persists a vote.
+    pub(crate) fn insert_vote(&self, vote: &StoredVote) {
+        let mut key = key(VOTE_PREFIX, vote.header.height, &vote.header.hash());
+        key.extend_from_slice(&vote.voter.0);
+        self.backend
+            .lock()
+            .insert(&key, &stdcode::serialize(vote).unwrap());
+        self.flush_for(Some(vote.voter));
+    }
+
+    /// Returns all the persisted proposals, in order of height.
+    pub(crate) fn proposals(&self) -> Vec<StoredProposal> {
+        self.load(PROPOSAL_PREFIX)
+    }
+
+    /// Returns all the persisted votes, in order of height.
+    pub(crate) fn votes(&self) -> Vec<StoredVote> {
+        self.load(VOTE_PREFIX)
+    }
+
+    /// Signs a confirmation of the given finalized header. The signature is persisted before it's returned, and if a different header at the same height was already confirmed, this refuses to sign and returns `None`.
+    pub(crate) fn sign_confirmation(
+        &self,
+        signing_sk: Ed25519SK,
+        header: Header,
+    ) -> Option<Vec<u8>> {
+        let mut backend = self.backend.lock();
+        // there's only one confirmation per height, so it's keyed by height alone
+        let key = key(CONFIRMATION_PREFIX, header.height, &HashVal::default());
+        if let Some(existing) = backend.get(&key) {
+            let (hash, signature): (HashVal, Vec<u8>) = stdcode::deserialize(&existing).unwrap();
+            return if hash == header.hash() {
+                Some(signature)
+            } else {
+                None
+            };
+        }
+        let signature = signing_sk.sign(&header.hash());
+        backend.insert(
+            &key,
+            &stdcode::serialize(&(header.hash(), signature.clone())).unwrap(),
+        );
+        self.flush_for(None);
+        Some(signature)
+    }
+
+    /// Deletes everything at or below the given height, which is no longer needed once it's part of the genesis.
+    pub(crate) fn prune(&self, height: u64) {
+        let mut backend = self.backend.lock();
+        let mut batch = WriteBatch::new();
+        for prefix in [PROPOSAL_PREFIX, VOTE_PREFIX, CONFIRMATION_PREFIX] {
+            let start = [prefix];
+            let end = key(prefix, height, &HashVal([0xff; 32]));
+            // votes have the voter after the hash
+            let end = [end.as_slice(), &[0xff; 32]].concat();
+            for key in backend.key_range(&start, &end) {
+                batch.remove(&key);
+            }
+        }
+        if !batch.is_empty() {
+            backend.write_batch(batch);
+        }
+    }
+
+    fn load<T: serde::de::DeserializeOwned>(&self, prefix: u8) -> Vec<T> {
+        let backend = self.backend.lock();
+        backend
+            .key_range(&[prefix], &[prefix + 1])
+            .into_iter()
+            .filter_map(|key| backend.get(&key))
+            .map(|value| stdcode::deserialize(&value).expect("corrupt consensus store"))
+            .collect()
+    }
+}
+
+/// Keys sort by prefix, then by height.
+fn key(prefix: u8, height: u64, hash: &HashVal) -> Vec<u8> {
+    let mut key = vec![prefix];
+    key.extend_from_slice(&height.to_be_bytes());
+    key.extend_from_slice(&hash.0);
+    key
+}
@@ -0,0 +1,184 @@
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use melnet::Request;
+use tmelcrypt::Ed25519PK;
+
+pub use crate::cstate::gossip::{
+    AbbrBlockResponse, BlockRequest, EvidenceRequest, TransactionRequest, TransactionResponse,
+};
+use crate::{evidence::Evidence, NS_EXECUTOR};
+
+mod channel;
+pub use channel::*;
+
+/// The serving side of the Symphonia gossip protocol. A [Transport] dispatches requests from peers to a Responder.
+pub trait Responder: Send + Sync + 'static {
+    /// Answers a request for the blocks descending from the requester's LNC tips.
+    fn get_blocks(&self, request: BlockRequest) -> Vec<AbbrBlockResponse>;
+
+    /// Answers a request for the transactions in a block.
+    fn get_txx(&self, request: TransactionRequest) -> TransactionResponse;
+
+    /// Answers a request for equivocation evidence.
+    fn get_evidence(&self, request: EvidenceRequest) -> Vec<Evidence>;
+
+    /// Answers a request for the confirmation signatures known for the finalized block at a height.
+    fn confirm_block(&self, height: u64) -> BTreeMap<Ed25519PK, Vec<u8>>;
+}
+
+/// A transport over which instances of the Symphonia protocol talk to each other. Peers are identified by their addresses, which need not be real socket addresses.
+#[async_trait]
+pub trait Transport: Send + Sync + 'static {
+    /// Serves requests from peers using the given responder. Never returns.
+    async fn serve(&self, responder: Arc<dyn Responder>);
+
+    /// Returns the known peers, in random order.
+    fn peers(&self) -> Vec<SocketAddr>;
+
+    /// Asks a peer for the blocks descending from our LNC tips.
+    async fn get_blocks(
+        &self,
+        peer: SocketAddr,
+        request: BlockRequest,
+    ) -> anyhow::Result<Vec<AbbrBlockResponse>>;
+
+    /// Asks a peer for the transactions in a block.
+    async fn get_txx(
+        &self,
+        peer: SocketAddr,
+        request: TransactionRequest,
+    ) -> anyhow::Result<TransactionResponse>;
+
+    /// Asks a peer for equivocation evidence.
+    async fn get_evidence(
+        &self,
+        peer: SocketAddr,
+        request: EvidenceRequest,
+    ) -> anyhow::Result<Vec<Evidence>>;
+
+    /// Asks a peer for its confirmation signatures for the finalized block at a height.
+    async fn confirm_block(
+        &self,
+        peer: SocketAddr,
+        height: u64,
+    ) -> anyhow::Result<BTreeMap<Ed25519PK, Vec<u8>>>;
+}
+
+const NETNAME: &str = "symphgossip";
+
+/// The production transport, which runs over melnet.
+pub struct MelnetTransport {
+    network: melnet::NetState,
+    listen: SocketAddr,
+}
+
+impl MelnetTransport {
+    /// Creates a melnet transport that will listen on the given address, bootstrapping off the given peers.
+    pub fn new(listen: SocketAddr, bootstrap: &[SocketAddr]) -> Self {
+        let network = melnet::NetState::new_with_name(NETNAME);
+        for addr in bootstrap {
+            network.add_route(*addr);
+        }
+        Self { network, listen }
+    }
+}
+
+#[async_trait]
+impl Transport for MelnetTransport {
+    async fn serve(&self, responder: Arc<dyn Responder>) {
+        let responder_inner = responder.clone();
+        self.network.listen(
+            "get_blocks",
+            move |breq: Request<BlockRequest, Vec<AbbrBlockResponse>>| {
+                let responder = responder_inner.clone();
+                NS_EXECUTOR
+                    .spawn(async move {
+                        let response = responder.get_blocks(breq.body);
+                        breq.response.send(Ok(response))
+                    })
+                    .detach();
+            },
+        );
+        let responder_inner = responder.clone();
+        self.network.listen(
+            "get_txx",
+            move |breq: Request<TransactionRequest, TransactionResponse>| {
+                let responder = responder_inner.clone();
+                NS_EXECUTOR
+                    .spawn(async move {
+                        let resp = responder.get_txx(breq.body);
+                        breq.response.send(Ok(resp))
+                    })
+                    .detach();
+            },
+        );
+        let responder_inner = responder.clone();
+        self.network.listen(
+            "get_evidence",
+            move |breq: Request<EvidenceRequest, Vec<Evidence>>| {
+                let responder = responder_inner.clone();
+                NS_EXECUTOR
+                    .spawn(async move {
+                        let resp = responder.get_evidence(breq.body);
+                        breq.response.send(Ok(resp))
+                    })
+                    .detach();
+            },
+        );
+        let responder_inner = responder;
+        self.network.listen(
+            "confirm_block",
+            move |req: Request<u64, BTreeMap<Ed25519PK, Vec<u8>>>| {
+                let responder = responder_inner.clone();
+                NS_EXECUTOR
+                    .spawn(async move {
+                        let resp = responder.confirm_block(req.body);
+                        req.response.send(Ok(resp))
+                    })
+                    .detach();
+            },
+        );
+        self.network.add_route(self.listen);
+        let listener = smol::net::TcpListener::bind(self.listen)
+            .await
+            .expect("could not start to listen");
+        self.network.run_server(listener).await
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.network.routes()
+    }
+
+    async fn get_blocks(
+        &self,
+        peer: SocketAddr,
+        request: BlockRequest,
+    ) -> anyhow::Result<Vec<AbbrBlockResponse>> {
+        Ok(melnet::request(peer, NETNAME, "get_blocks", request).await?)
+    }
+
+    async fn get_txx(
+        &self,
+        peer: SocketAddr,
+        request: TransactionRequest,
+    ) -> anyhow::Result<TransactionResponse> {
+        Ok(melnet::request(peer, NETNAME, "get_txx", request).await?)
+    }
+
+    async fn get_evidence(
+        &self,
+        peer: SocketAddr,
+        request: EvidenceRequest,
+    ) -> anyhow::Result<Vec<Evidence>> {
+        Ok(melnet::request(peer, NETNAME, "get_evidence", request).await?)
+    }
+
+    async fn confirm_block(
+        &self,
+        peer: SocketAddr,
+        height: u64,
+    ) -> anyhow::Result<BTreeMap<Ed25519PK, Vec<u8>>> {
+        Ok(melnet::request(peer, NETNAME, "confirm_block", height).await?)
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use parking_lot::RwLock;
+use tmelcrypt::Ed25519PK;
+
+use super::{
+    AbbrBlockResponse, BlockRequest, EvidenceRequest, Responder, TransactionRequest,
+    TransactionResponse, Transport,
+};
+use crate::evidence::Evidence;
+
+/// An in-process network, over which [ChannelTransport]s talk to each other without binding any sockets. Useful for tests and simulations.
+#[derive(Clone, Default)]
+pub struct ChannelNetwork {
+    responders: Arc<RwLock<HashMap<SocketAddr, Arc<dyn Responder>>>>,
+}
+
+impl ChannelNetwork {
+    /// Creates a new, empty network.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a transport for the peer with the given address. The address is just an identifier, and is never bound.
+    pub fn transport(&self, addr: SocketAddr) -> ChannelTransport {
+        ChannelTransport {
+            network: self.clone(),
+            addr,
+        }
+    }
+}
+
+/// A transport that delivers requests directly to the responders of other peers on the same [ChannelNetwork].
+pub struct ChannelTransport {
+    network: ChannelNetwork,
+    addr: SocketAddr,
+}
+
+impl ChannelTransport {
+    fn responder(&self, peer: SocketAddr) -> anyhow::Result<Arc<dyn Responder>> {
+        self.network
+            .responders
+            .read()
+            .get(&peer)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("nobody is serving at {}", peer))
+    }
+}
+
+/// Unregisters a responder when serving stops.
+struct Registration {
+    network: ChannelNetwork,
+    addr: SocketAddr,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.network.responders.write().remove(&self.addr);
+    }
+}
+
+#[async_trait]
+impl Transport for ChannelTransport {
+    async fn serve(&self, responder: Arc<dyn Responder>) {
+        self.network.responders.write().insert(self.addr, responder);
+        let _registration = Registration {
+            network: self.network.clone(),
+            addr: self.addr,
+        };
+        smol::future::pending().await
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers = self
+            .network
+            .responders
+            .read()
+            .keys()
+            .copied()
+            .filter(|addr| *addr != self.addr)
+            .collect::<Vec<_>>();
+        fastrand::shuffle(&mut peers);
+        peers
+    }
+
+    async fn get_blocks(
+        &self,
+        peer: SocketAddr,
+        request: BlockRequest,
+    ) -> anyhow::Result<Vec<AbbrBlockResponse>> {
+        Ok(self.responder(peer)?.get_blocks(request))
+    }
+
+    async fn get_txx(
+        &self,
+        peer: SocketAddr,
+        request: TransactionRequest,
+    ) -> anyhow::Result<TransactionResponse> {
+        Ok(self.responder(peer)?.get_txx(request))
+    }
+
+    async fn get_evidence(
+        &self,
+        peer: SocketAddr,
+        request: EvidenceRequest,
+    ) -> anyhow::Result<Vec<Evidence>> {
+        Ok(self.responder(peer)?.get_evidence(request))
+    }
+
+    async fn confirm_block(
+        &self,
+        peer: SocketAddr,
+        height: u64,
+    ) -> anyhow::Result<BTreeMap<Ed25519PK, Vec<u8>>> {
+        Ok(self.responder(peer)?.confirm_block(height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every request with nothing, except for confirmation requests, which it answers with one signature.
+    struct DummyResponder(Ed25519PK);
+
+    impl Responder for DummyResponder {
+        fn get_blocks(&self, _: BlockRequest) -> Vec<AbbrBlockResponse> {
+            vec![]
+        }
+
+        fn get_txx(&self, _: TransactionRequest) -> TransactionResponse {
+            TransactionResponse {
+                transactions: vec![],
+            }
+        }
+
+        fn get_evidence(&self, _: EvidenceRequest) -> Vec<Evidence> {
+            vec![]
+        }
+
+        fn confirm_block(&self, height: u64) -> BTreeMap<Ed25519PK, Vec<u8>> {
+            std::iter::once((self.0, height.to_be_bytes().to_vec())).collect()
+        }
+    }
+
+    #[test]
+    fn channel_roundtrip() {
+        smol::block_on(async {
+            let network = ChannelNetwork::new();
+            let alice_addr: SocketAddr = "10.0.0.1:1".parse().unwrap();
+            let bob_addr: SocketAddr = "10.0.0.2:1".parse().unwrap();
+            let alice = Arc::new(network.transport(alice_addr));
+            let bob = network.transport(bob_addr);
+            let alice_pk = tmelcrypt::ed25519_keygen().0;
+            let server = smol::spawn({
+                let alice = alice.clone();
+                async move { alice.serve(Arc::new(DummyResponder(alice_pk))).await }
+            });
+            while bob.peers().is_empty() {
+                smol::future::yield_now().await;
+            }
+            assert_eq!(bob.peers(), vec![alice_addr]);
+            assert!(alice.peers().is_empty());
+            let sigs = bob.confirm_block(alice_addr, 42).await.unwrap();
+            assert_eq!(sigs[&alice_pk], 42u64.to_be_bytes().to_vec());
+            // nobody's at bob's address
+            assert!(alice.confirm_block(bob_addr, 42).await.is_err());
+            // once alice stops serving, she's gone
+            server.cancel().await;
+            assert!(bob.peers().is_empty());
+            assert!(bob.confirm_block(alice_addr, 42).await.is_err());
+        })
+    }
+}
@@ -0,0 +1,248 @@
+//! Peer management for Themelio's peer-to-peer networks.
+//!
+//! A [PeerManager] keeps track of how well every known peer has been serving us, picks peers at random weighted by that track record, and temporarily bans peers that misbehave.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use parking_lot::Mutex;
+
+/// How much of a peer's score carries over every time it's updated. The rest comes from the latest request.
+const SCORE_MEMORY: f64 = 0.8;
+
+/// Reliability of a peer we know nothing about yet.
+const INITIAL_RELIABILITY: f64 = 0.5;
+
+/// Latency of a peer we know nothing about yet.
+const INITIAL_LATENCY: Duration = Duration::from_secs(1);
+
+/// When picking, even the least reliable peers count as this reliable, so that they get a chance to redeem themselves.
+const MIN_RELIABILITY: f64 = 0.05;
+
+/// Peers at least this reliable are worth remembering across restarts.
+const GOOD_RELIABILITY: f64 = 0.5;
+
+/// What we know about a peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerStats {
+    /// Recent fraction of requests that succeeded, between 0 and 1.
+    pub reliability: f64,
+    /// Recent latency of successful requests.
+    pub latency: Duration,
+    /// Until when the peer is banned, if it is.
+    pub banned_until: Option<SystemTime>,
+}
+
+impl Default for PeerStats {
+    fn default() -> Self {
+        Self {
+            reliability: INITIAL_RELIABILITY,
+            latency: INITIAL_LATENCY,
+            banned_until: None,
+        }
+    }
+}
+
+impl PeerStats {
+    fn is_banned(&self, now: SystemTime) -> bool {
+        self.banned_until.map(|until| until > now).unwrap_or(false)
+    }
+
+    fn weight(&self) -> f64 {
+        self.reliability.max(MIN_RELIABILITY) / (self.latency.as_secs_f64() + 0.1)
+    }
+}
+
+/// Keeps track of the peers in one network. Meant to be shared, behind an `Arc`, by everything that talks to that network.
+pub struct PeerManager {
+    peers: Mutex<BTreeMap<SocketAddr, PeerStats>>,
+    rng: Mutex<fastrand::Rng>,
+    clock: Box<dyn Fn() -> SystemTime + Send + Sync>,
+}
+
+impl Default for PeerManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeerManager {
+    /// Creates a peer manager that doesn't know any peers yet.
+    pub fn new() -> Self {
+        Self::with_clock(fastrand::u64(..), SystemTime::now)
+    }
+
+    /// Creates a peer manager that draws its random numbers from the given seed and tells time with the given clock. Useful for deterministic simulations.
+    pub fn with_clock(seed: u64, clock: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        Self {
+            peers: Default::default(),
+            rng: Mutex::new(fastrand::Rng::with_seed(seed)),
+            clock: Box::new(clock),
+        }
+    }
+
+    /// Adds peers, e.g. ones discovered by the network or remembered from a previous run. Peers that are already known keep their scores.
+    pub fn add_peers(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        let mut known = self.peers.lock();
+        for peer in peers {
+            known.entry(peer).or_default();
+        }
+    }
+
+    /// Picks a random peer that isn't banned, or `None` if there isn't any. Peers that have been serving us reliably and quickly are more likely to be picked.
+    pub fn pick(&self) -> Option<SocketAddr> {
+        let now = (self.clock)();
+        let candidates = self
+            .peers
+            .lock()
+            .iter()
+            .filter(|(_, stats)| !stats.is_banned(now))
+            .map(|(peer, stats)| (*peer, stats.weight()))
+            .collect::<Vec<_>>();
+        let total: f64 = candidates.iter().map(|(_, weight)| weight).sum();
+        let mut point = self.rng.lock().f64() * total;
+        for (peer, weight) in candidates.iter() {
+            if point < *weight {
+                return Some(*peer);
+            }
+            point -= weight;
+        }
+        // only reachable through rounding errors
+        candidates.last().map(|(peer, _)| *peer)
+    }
+
+    /// Records that a request to a peer succeeded, and how long it took.
+    pub fn record_success(&self, peer: SocketAddr, latency: Duration) {
+        let mut peers = self.peers.lock();
+        let stats = peers.entry(peer).or_default();
+        stats.reliability = stats.reliability * SCORE_MEMORY + (1.0 - SCORE_MEMORY);
+        stats.latency = stats.latency.mul_f64(SCORE_MEMORY) + latency.mul_f64(1.0 - SCORE_MEMORY);
+    }
+
+    /// Records that a request to a peer failed, e.g. with an error or a timeout.
+    pub fn record_failure(&self, peer: SocketAddr) {
+        let mut peers = self.peers.lock();
+        let stats = peers.entry(peer).or_default();
+        stats.reliability *= SCORE_MEMORY;
+    }
+
+    /// Bans a misbehaving peer for the given duration. Banned peers are never picked. A ban also wipes out the peer's reliability, so it starts over once the ban expires.
+    pub fn ban(&self, peer: SocketAddr, duration: Duration) {
+        log::warn!("banning peer {} for {:?}", peer, duration);
+        let now = (self.clock)();
+        let mut peers = self.peers.lock();
+        let stats = peers.entry(peer).or_default();
+        stats.reliability = 0.0;
+        stats.banned_until = Some(now + duration);
+    }
+
+    /// Is this peer currently banned?
+    pub fn is_banned(&self, peer: SocketAddr) -> bool {
+        let now = (self.clock)();
+        self.peers
+            .lock()
+            .get(&peer)
+            .map(|stats| stats.is_banned(now))
+            .unwrap_or(false)
+    }
+
+    /// Returns what we know about a peer.
+    pub fn stats(&self, peer: SocketAddr) -> Option<PeerStats> {
+        self.peers.lock().get(&peer).copied()
+    }
+
+    /// Returns the peers worth remembering across restarts: those that aren't banned and have been serving us reliably.
+    pub fn good_peers(&self) -> Vec<SocketAddr> {
+        let now = (self.clock)();
+        self.peers
+            .lock()
+            .iter()
+            .filter(|(_, stats)| !stats.is_banned(now) && stats.reliability >= GOOD_RELIABILITY)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn peer(idx: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], 10000 + idx))
+    }
+
+    /// A peer manager with a clock that only moves when told to.
+    fn manual_clock(seed: u64) -> (PeerManager, Arc<Mutex<SystemTime>>) {
+        let now = Arc::new(Mutex::new(SystemTime::UNIX_EPOCH));
+        let manager = PeerManager::with_clock(seed, {
+            let now = now.clone();
+            move || *now.lock()
+        });
+        (manager, now)
+    }
+
+    #[test]
+    fn empty() {
+        let manager = PeerManager::new();
+        assert_eq!(manager.pick(), None);
+        assert!(manager.good_peers().is_empty());
+    }
+
+    #[test]
+    fn deterministic() {
+        let picks = || {
+            let (manager, _) = manual_clock(42);
+            manager.add_peers((0..10).map(peer));
+            (0..100)
+                .map(|_| manager.pick().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let first = picks();
+        assert_eq!(first, picks());
+        // and actually random
+        assert!(first.iter().any(|p| *p != first[0]));
+    }
+
+    #[test]
+    fn prefers_good_peers() {
+        let (manager, _) = manual_clock(1);
+        manager.add_peers((0..3).map(peer));
+        for _ in 0..20 {
+            manager.record_success(peer(0), Duration::from_millis(50));
+            manager.record_success(peer(1), Duration::from_secs(2));
+            manager.record_failure(peer(2));
+        }
+        let mut counts = BTreeMap::new();
+        for _ in 0..1000 {
+            *counts.entry(manager.pick().unwrap()).or_insert(0) += 1;
+        }
+        assert!(counts[&peer(0)] > counts[&peer(1)]);
+        assert!(counts[&peer(1)] > counts.get(&peer(2)).copied().unwrap_or(0));
+        // bad peers still get the occasional chance
+        assert!(counts.contains_key(&peer(2)));
+        assert_eq!(manager.good_peers(), vec![peer(0), peer(1)]);
+    }
+
+    #[test]
+    fn bans_expire() {
+        let (manager, now) = manual_clock(2);
+        manager.add_peers((0..2).map(peer));
+        manager.ban(peer(0), Duration::from_secs(60));
+        assert!(manager.is_banned(peer(0)));
+        assert!((0..100).all(|_| manager.pick() == Some(peer(1))));
+        assert_eq!(manager.good_peers(), vec![peer(1)]);
+
+        *now.lock() += Duration::from_secs(61);
+        assert!(!manager.is_banned(peer(0)));
+        assert!((0..1000).any(|_| manager.pick() == Some(peer(0))));
+        // a banned peer has to earn back its reputation
+        assert_eq!(manager.stats(peer(0)).unwrap().reliability, 0.0);
+        manager.add_peers(Some(peer(0)));
+        assert_eq!(manager.stats(peer(0)).unwrap().reliability, 0.0);
+    }
+}